@@ -1,16 +1,32 @@
 use anyhow::Context;
-use clap::Parser;
-use dfu_nusb::DfuNusb;
+use clap::{CommandFactory, FromArgMatches};
+use dfu_nusb::exit;
+use dfu_nusb::file::DfusePrefix;
+use dfu_nusb::{
+    BadSectorPolicy, DeviceFilter, DfuDeviceInfo, DfuNusb, DfuSuffix, DfuseOptions, FinalStatus,
+    OpenOptions, Pacing, Padding, Phase, Probe, Progress, RequestIndex, Timeouts,
+};
 use std::convert::TryFrom;
-use std::io;
+use std::io::{self, BufRead, IsTerminal, Write};
 use std::path::PathBuf;
-use tokio::io::AsyncSeekExt;
+use std::process::ExitCode;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
 use tokio_util::compat::TokioAsyncReadCompatExt;
 
 #[derive(clap::Parser)]
+#[clap(after_help = AFTER_HELP)]
 pub struct Cli {
-    /// Path to the firmware file to write to the device.
-    path: PathBuf,
+    /// Path to the firmware file to write to the device, or `-` to read it from stdin.
+    #[clap(required_unless_present = "completions")]
+    path: Option<PathBuf>,
+
+    /// Read default options from this TOML file rather than dfu-nusb.toml.
+    #[clap(long, value_name = "FILE")]
+    config: Option<PathBuf>,
+
+    /// Print the completions for this shell and exit.
+    #[clap(long, value_name = "SHELL", exclusive = true)]
+    completions: Option<clap_complete::Shell>,
 
     /// Wait for the device to appear.
     #[clap(short, long)]
@@ -26,97 +42,481 @@ pub struct Cli {
         short,
         value_parser = parse_vid_pid, name = "vendor>:<product",
     )]
-    device: (u16, u16),
+    device: Option<(u16, u16)>,
+
+    /// Vendor/Product ID the device re-enumerates with in DFU mode, when it differs from --device.
+    #[clap(long, value_parser = parse_vid_pid, name = "dfu-vendor>:<dfu-product")]
+    dfu_device: Option<(u16, u16)>,
+
+    /// Only use devices whose serial number matches this glob (e.g. "PROTO-*").
+    #[clap(long)]
+    serial: Option<String>,
+
+    /// Only use devices whose product string matches this glob.
+    #[clap(long)]
+    product: Option<String>,
+
+    /// Only use the device with this bus number and device address (e.g. 003:012).
+    #[clap(long, value_parser = parse_bus_device)]
+    bus_device: Option<(u8, u8)>,
+
+    /// Open the device with this platform path (sysfs path, instance or interface path or location ID).
+    #[clap(long, conflicts_with_all = ["vendor>:<product", "serial", "product"])]
+    device_path: Option<String>,
+
+    /// Hold a lock file in this directory while flashing, so other instances keep away.
+    #[clap(long)]
+    lock_dir: Option<PathBuf>,
 
     /// Specify the DFU Interface number.
     #[clap(long, short, default_value = "0")]
     intf: u8,
 
+    /// Timeout of a single control request, in milliseconds.
+    #[clap(long, value_name = "MS")]
+    control_timeout: Option<u64>,
+
+    /// Time the device may stay busy erasing a page, in milliseconds.
+    #[clap(long, value_name = "MS")]
+    erase_timeout: Option<u64>,
+
+    /// Time the device may stay busy writing a block, in milliseconds.
+    #[clap(long, value_name = "MS")]
+    write_timeout: Option<u64>,
+
+    /// Time the device may stay busy manifesting the firmware, in milliseconds.
+    #[clap(long, value_name = "MS")]
+    manifest_timeout: Option<u64>,
+
+    /// Check the interface answers DFU requests before starting.
+    #[clap(long)]
+    probe: bool,
+
+    /// Transfer size to use if the device advertises a wTransferSize of 0.
+    #[clap(long)]
+    fallback_transfer_size: Option<u16>,
+
     /// Specify the Altsetting of the DFU Interface by number.
     #[clap(long, short, default_value = "0")]
     alt: u8,
 
+    /// Select the USB configuration (bConfigurationValue) before claiming the interface.
+    #[clap(long, short)]
+    cfg: Option<u8>,
+
     /// Override start address (e.g. 0x0800C000)
     #[clap(long, short, value_parser=parse_address, name="address")]
     override_address: Option<u32>,
+
+    /// DfuSe address and modifiers in dfu-util syntax (e.g. 0x08000000:leave:will-reset)
+    #[clap(
+        long,
+        short = 's',
+        conflicts_with = "address",
+        name = "address:modifiers"
+    )]
+    dfuse_address: Option<DfuseOptions>,
+
+    /// Don't erase the flash before writing; only use on pre-erased devices.
+    #[clap(long)]
+    skip_erase: bool,
+
+    /// Bypass the DFU suffix, capability and size checks.
+    #[clap(long)]
+    force: bool,
+
+    /// Allow writing to special targets like option bytes or OTP memory.
+    #[clap(long)]
+    allow_dangerous_targets: bool,
+
+    /// Read back the firmware to check it was written correctly.
+    #[clap(long)]
+    verify: bool,
+
+    /// Verify with this DfuSe checksum command (e.g. 0x50) when the device supports it.
+    #[clap(long, value_parser = parse_byte, requires = "verify")]
+    device_crc: Option<u8>,
+
+    /// Append a JSON lines audit log of the session to this file.
+    #[clap(long)]
+    session_log: Option<PathBuf>,
+
+    /// Pause for this many milliseconds after every block written.
+    #[clap(long, default_value = "0")]
+    block_delay: u64,
+
+    /// Limit the download rate to this many bytes per second.
+    #[clap(long)]
+    max_bandwidth: Option<u32>,
+
+    /// Pad the last block to the full transfer size, for bootloaders rejecting short blocks.
+    #[clap(long)]
+    pad_final_block: bool,
+
+    /// Start DfuSe downloads at the start of the page containing the address.
+    #[clap(long)]
+    align_start: bool,
+
+    /// Byte used for padding.
+    #[clap(long, value_parser = parse_byte, default_value = "0xff")]
+    pad_byte: u8,
+
+    /// Skip up to this many DfuSe sectors failing with errWRITE, writing their data to the
+    /// following sectors (NAND bad blocks).
+    #[clap(long, value_name = "LIMIT")]
+    skip_bad_sectors: Option<usize>,
+
+    /// Probe the capacity of plain DFU targets with uploads, to refuse firmware too large for
+    /// them.
+    #[clap(long)]
+    probe_capacity: bool,
+
+    /// wIndex of class requests for non-conforming bootloaders: interface, zero, alt or a
+    /// number.
+    #[clap(long, value_parser = parse_request_index, default_value = "interface")]
+    request_index: RequestIndex,
+
+    /// Count a timeout of the final status request as success, for bootloaders starting the
+    /// firmware right after the last block; `--tolerant-completion=false` turns it off again,
+    /// e.g. when set in the config file.
+    #[clap(long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    tolerant_completion: Option<bool>,
+}
+
+/// Find the device to flash, asking the user to pick one if multiple devices match
+pub fn select_device(filter: &DeviceFilter) -> anyhow::Result<Option<DfuDeviceInfo>> {
+    let mut devices = dfu_nusb::list_devices(filter).context("could not list devices")?;
+    match devices.len() {
+        0 => Ok(None),
+        1 => Ok(devices.pop()),
+        _ => pick_device(devices).map(Some),
+    }
 }
 
-pub fn try_open(vid: u16, pid: u16, int: u8, alt: u8) -> Result<DfuNusb, dfu_nusb::Error> {
-    let info = nusb::list_devices()
-        .unwrap()
-        .find(|dev| dev.vendor_id() == vid && dev.product_id() == pid)
-        .ok_or(dfu_nusb::Error::DeviceNotFound)?;
-    let device = info.open()?;
-    let interface = device.claim_interface(int)?;
+pub fn pick_device(mut devices: Vec<DfuDeviceInfo>) -> anyhow::Result<DfuDeviceInfo> {
+    if !io::stdin().is_terminal() {
+        anyhow::bail!(
+            "{} devices match, use a more specific filter to select one",
+            devices.len()
+        );
+    }
+
+    eprintln!("Multiple DFU devices found:");
+    for (i, device) in devices.iter().enumerate() {
+        let info = device.info();
+        eprintln!(
+            "  {}) [{:04x}:{:04x}] {} serial={} port={}",
+            i + 1,
+            info.vendor_id(),
+            info.product_id(),
+            device.product().unwrap_or("<unknown>"),
+            device.serial().unwrap_or("<none>"),
+            device.port(),
+        );
+    }
 
-    DfuNusb::open(device, interface, alt)
+    let mut stdin = io::stdin().lock();
+    loop {
+        eprint!("Select device [1-{}]: ", devices.len());
+        io::stderr().flush()?;
+        let mut line = String::new();
+        if stdin.read_line(&mut line)? == 0 {
+            anyhow::bail!("no device selected");
+        }
+        match line.trim().parse::<usize>() {
+            Ok(n) if (1..=devices.len()).contains(&n) => return Ok(devices.swap_remove(n - 1)),
+            _ => eprintln!("Invalid selection"),
+        }
+    }
 }
 
 pub async fn run(opts: Cli) -> anyhow::Result<()> {
     let Cli {
         path,
+        config: _,
+        completions: _,
         wait,
         reset,
         device,
+        dfu_device,
+        bus_device,
+        device_path,
+        lock_dir,
+        serial,
+        product,
         intf,
+        control_timeout,
+        erase_timeout,
+        write_timeout,
+        manifest_timeout,
+        probe,
+        fallback_transfer_size,
         alt,
-        override_address,
+        cfg,
+        mut override_address,
+        dfuse_address,
+        skip_erase,
+        force,
+        allow_dangerous_targets,
+        verify,
+        device_crc,
+        session_log,
+        block_delay,
+        max_bandwidth,
+        pad_final_block,
+        align_start,
+        pad_byte,
+        skip_bad_sectors,
+        probe_capacity,
+        request_index,
+        tolerant_completion,
     } = opts;
-    let (vid, pid) = device;
-    let mut file = tokio::fs::File::open(path)
-        .await
-        .context("could not open firmware file")?;
-    let file_size = u32::try_from(file.seek(io::SeekFrom::End(0)).await?)
-        .context("the firmware file is too big")?;
-    file.seek(io::SeekFrom::Start(0)).await?;
-
-    let device = match try_open(vid, pid, intf, alt) {
-        Err(dfu_nusb::Error::DeviceNotFound) if wait => {
-            let bar = indicatif::ProgressBar::new_spinner();
-            bar.set_message("Waiting for device");
-
-            loop {
-                tokio::time::sleep(std::time::Duration::from_millis(250)).await;
-                match try_open(vid, pid, intf, alt) {
-                    Err(dfu_nusb::Error::DeviceNotFound) => bar.tick(),
-                    r => {
-                        bar.finish();
-                        break r;
-                    }
-                }
-            }
-        }
-        r => r,
+    // `leave` needs no handling as downloads always end with the zero-length request that
+    // makes DfuSe devices leave DFU mode
+    let path = path.context("no firmware file given")?;
+    let dfuse = dfuse_address.unwrap_or_default();
+    if dfuse.mass_erase {
+        anyhow::bail!("the mass-erase modifier is not supported");
+    }
+    if dfuse.address.is_some() {
+        override_address = dfuse.address;
     }
-    .context("could not open device")?;
 
-    let mut device = device.into_async_dfu();
+    let mut filter = match device {
+        Some((vid, pid)) => DeviceFilter::new().vid_pid(vid, pid),
+        None => DeviceFilter::new(),
+    };
+    if let Some((vid, pid)) = dfu_device {
+        filter = filter.reenumerates_as(vid, pid);
+    }
+    if let Some(serial) = serial {
+        filter = filter.serial(serial);
+    }
+    if let Some(product) = product {
+        filter = filter.product(product);
+    }
+    if let Some((bus, address)) = bus_device {
+        filter = filter.bus_device(bus, address);
+    }
+    if let Some(path) = device_path {
+        filter = filter.path(path);
+    }
 
-    let bar = indicatif::ProgressBar::new(file_size as u64);
-    bar.set_style(
-        indicatif::ProgressStyle::default_bar()
-            .template(
-                "{spinner:.green} [{elapsed_precise}] [{bar:27.cyan/blue}] \
-                        {bytes}/{total_bytes} ({bytes_per_sec}) ({eta}) {msg:10}",
-            )?
-            .progress_chars("#>-"),
-    );
+    // The size of the firmware isn't known up-front when reading it from stdin
+    let mut file = if path.as_os_str() == "-" {
+        None
+    } else {
+        let mut file = tokio::fs::File::open(path)
+            .await
+            .context("could not open firmware file")?;
+        let file_size = u32::try_from(file.seek(io::SeekFrom::End(0)).await?)
+            .context("the firmware file is too big")?;
+        let mut suffix = [0; DfuSuffix::LENGTH];
+        if file_size as usize >= suffix.len() {
+            file.seek(io::SeekFrom::End(-(suffix.len() as i64))).await?;
+            file.read_exact(&mut suffix).await?;
+        }
+        file.seek(io::SeekFrom::Start(0)).await?;
+        Some((file, file_size, DfuSuffix::parse(&suffix)))
+    };
 
+    // DfuSe images carry their own addresses and may target several alternative settings
+    let mut is_image = false;
+    if let Some((file, _, _)) = file.as_mut() {
+        let mut prefix = [0; DfusePrefix::LENGTH];
+        is_image =
+            file.read_exact(&mut prefix).await.is_ok() && DfusePrefix::parse(&prefix).is_some();
+        file.seek(io::SeekFrom::Start(0)).await?;
+    }
+
+    // Elements of DfuSe images are written one by one, only their byte count is shown
+    let progress = PhaseProgress::new(match &file {
+        Some((_, file_size, _)) if !is_image => Some(*file_size),
+        _ => None,
+    });
+    let info = match select_device(&filter)? {
+        Some(info) => info,
+        None if wait => {
+            progress.waiting();
+            let info = dfu_nusb::wait_for_device(
+                &filter,
+                std::time::Duration::from_millis(250),
+                std::time::Duration::MAX,
+                &dfu_nusb::StdTimer,
+            )
+            .await
+            .context("could not wait for device")?;
+            progress.found();
+            info
+        }
+        None => return Err(dfu_nusb::Error::DeviceNotFound).context("could not open device"),
+    };
+    let mut timeouts = Timeouts::default();
+    for (timeout, ms) in [
+        (&mut timeouts.control, control_timeout),
+        (&mut timeouts.erase_page, erase_timeout),
+        (&mut timeouts.write_block, write_timeout),
+        (&mut timeouts.manifest, manifest_timeout),
+    ] {
+        if let Some(ms) = ms {
+            *timeout = std::time::Duration::from_millis(ms);
+        }
+    }
+    let mut options = OpenOptions::new()
+        .interface(intf)
+        .alt(alt)
+        .timeouts(timeouts);
+    if let Some(cfg) = cfg {
+        options = options.configuration(cfg);
+    }
+    if let Some(dir) = lock_dir {
+        options = options.lock_dir(dir);
+    }
+    if probe {
+        options = options.probe(Probe::GetStatus);
+    }
+    if let Some(size) = fallback_transfer_size {
+        options = options.fallback_transfer_size(size);
+    }
+    let mut device = options
+        .open(&info)
+        .context("could not open device")?
+        .with_progress(progress.clone());
+    for warning in device.warnings() {
+        eprintln!("Warning: {warning}");
+    }
+
+    if let Some(path) = session_log {
+        let log = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .context("could not open session log")?;
+        device = device.with_session_log(dfu_nusb::SessionLog::new(log));
+    }
     if let Some(address) = override_address {
         device.override_address(address);
     }
+    device
+        .force(force || dfuse.force)
+        .skip_erase(skip_erase)
+        .allow_dangerous_targets(allow_dangerous_targets)
+        .verify(verify)
+        .device_crc_command(device_crc)
+        .pacing(Pacing {
+            block_delay: std::time::Duration::from_millis(block_delay),
+            max_bandwidth,
+        })
+        .padding(Padding {
+            final_block: pad_final_block,
+            align_start,
+            fill: pad_byte,
+        })
+        .request_index_quirk(request_index);
+    if let Some(limit) = skip_bad_sectors {
+        device.bad_sector_policy(BadSectorPolicy::Skip { limit });
+    }
+    if let Some(tolerant) = tolerant_completion {
+        device.final_status_quirk(if tolerant {
+            FinalStatus::TolerateTimeout
+        } else {
+            FinalStatus::Required
+        });
+    }
+
+    // Like dfu-util, only remove the protection; the device erases its flash and resets
+    if dfuse.unprotect {
+        device
+            .unprotect()
+            .await
+            .context("could not remove the flash protection")?;
+        println!("Flash protection removed, the device is erasing its flash and resetting");
+        return Ok(());
+    }
+
+    if probe_capacity {
+        let capacity = device
+            .probe_capacity()
+            .await
+            .context("could not probe the capacity of the target")?;
+        println!("Target holds {capacity} bytes");
+    }
 
-    let file = bar.wrap_async_read(file);
-    let file = file.compat();
-    match device.download(file, file_size).await {
+    if let Some((_, _, Some(suffix))) = &file {
+        device
+            .check_suffix(suffix)
+            .context("firmware is not meant for this device, use --force to flash anyway")?;
+    }
+
+    if let (true, Some((file, _, _))) = (is_image, file.as_mut()) {
+        let mut image = Vec::new();
+        file.read_to_end(&mut image).await?;
+        let report = tokio::select! {
+            report = device.download_image(&image) => report,
+            _ = tokio::signal::ctrl_c() => {
+                progress.abandon();
+                return Err(interrupted(&device).await);
+            }
+        }
+        .context("could not parse firmware image")?;
+        progress.finish();
+        println!("{report}");
+        return match report.into_failure() {
+            Some(e) => Err(e).context("the firmware image was only partially written"),
+            None => Ok(()),
+        };
+    }
+
+    if let (Some(address), Some((_, file_size, _))) = (device.address(), &file) {
+        println!(
+            "Flashing {:#010x}-{:#010x}",
+            address,
+            address.saturating_add(file_size.saturating_sub(1))
+        );
+    }
+
+    // Dropping the download future on Ctrl-C cancels the transfer in flight
+    let result = match file {
+        Some((file, file_size, _)) => {
+            let file = file.compat();
+            tokio::select! {
+                result = device.download(file, file_size) => Some(result),
+                _ = tokio::signal::ctrl_c() => None,
+            }
+        }
+        None => {
+            let stdin = tokio::io::stdin().compat();
+            tokio::select! {
+                result = device.download_stream(stdin) => Some(result),
+                _ = tokio::signal::ctrl_c() => None,
+            }
+        }
+    };
+    let Some(result) = result else {
+        progress.abandon();
+        return Err(interrupted(&device).await);
+    };
+    let skipped = device.skipped_sectors();
+    let device = device.into_async_dfu();
+    match result {
         Ok(_) => (),
-        Err(dfu_nusb::Error::Nusb(..)) if bar.is_finished() => {
-            println!("USB error after upload; Device reset itself?");
+        Err(
+            dfu_nusb::Error::Disconnected
+            | dfu_nusb::Error::Nusb(..)
+            | dfu_nusb::Error::Transfer(..),
+        ) if dfuse.will_reset => {
+            println!("USB error after download; Device reset itself");
             return Ok(());
         }
-        e => return e.context("could not write firmware to the device"),
+        e => {
+            progress.abandon();
+            return e.context("could not write firmware to the device");
+        }
+    }
+    progress.finish();
+    for sector in skipped {
+        println!("Skipped bad sector at {sector:#010x}");
     }
-    bar.finish();
 
     if reset {
         // Detach isn't strictly meant to be sent after a download, however u-boot in
@@ -132,6 +532,138 @@ pub async fn run(opts: Cli) -> anyhow::Result<()> {
     Ok(())
 }
 
+const BYTES_TEMPLATE: &str = "{spinner:.green} [{elapsed_precise}] [{bar:27.cyan/blue}] \
+    {bytes}/{total_bytes} ({bytes_per_sec}) ({eta}) {msg}";
+const STREAM_TEMPLATE: &str =
+    "{spinner:.green} [{elapsed_precise}] {bytes} ({bytes_per_sec}) {msg}";
+const SECTORS_TEMPLATE: &str =
+    "{spinner:.green} [{elapsed_precise}] [{bar:27.cyan/blue}] {pos}/{len} sectors {msg}";
+const SECTOR_COUNT_TEMPLATE: &str = "{spinner:.green} [{elapsed_precise}] {pos} sectors {msg}";
+const SPINNER_TEMPLATE: &str = "{spinner:.green} [{elapsed_precise}] {msg}";
+
+/// Progress display switching style with the phase of the operation, so erasing and
+/// manifesting don't look like a stalled download
+#[derive(Clone)]
+pub struct PhaseProgress {
+    bar: indicatif::ProgressBar,
+    /// Size of the firmware, unknown when reading it from stdin
+    total: Option<u64>,
+}
+
+impl PhaseProgress {
+    pub fn new(total: Option<u32>) -> Self {
+        Self {
+            bar: indicatif::ProgressBar::new(0),
+            total: total.map(u64::from),
+        }
+    }
+
+    fn style(&self, template: &str, message: &'static str) {
+        self.bar.set_style(
+            indicatif::ProgressStyle::default_bar()
+                .template(template)
+                .expect("valid progress template")
+                .progress_chars("#>-"),
+        );
+        self.bar.reset();
+        self.bar.set_message(message);
+    }
+
+    /// Show a spinner, for phases without measurable progress
+    fn spin(&self, message: &'static str) {
+        self.style(SPINNER_TEMPLATE, message);
+        self.bar
+            .enable_steady_tick(std::time::Duration::from_millis(100));
+    }
+
+    /// Show a byte count for phases transferring the firmware
+    fn bytes(&self, message: &'static str) {
+        self.bar.disable_steady_tick();
+        match self.total {
+            Some(total) => {
+                self.style(BYTES_TEMPLATE, message);
+                self.bar.set_length(total);
+            }
+            None => self.style(STREAM_TEMPLATE, message),
+        }
+    }
+
+    pub fn waiting(&self) {
+        self.spin("Waiting for device");
+    }
+
+    pub fn found(&self) {
+        self.bar.disable_steady_tick();
+        self.bar.set_message("Device found");
+    }
+
+    pub fn finish(&self) {
+        self.bar.disable_steady_tick();
+        self.bar.finish();
+    }
+
+    pub fn abandon(&self) {
+        self.bar.disable_steady_tick();
+        self.bar.abandon();
+    }
+}
+
+impl dfu_nusb::ProgressHandler for PhaseProgress {
+    fn progress(&mut self, progress: Progress) {
+        match progress {
+            // The style is picked with the first page, which tells whether the count is known
+            Progress::Phase(Phase::Erase) => self.bar.disable_steady_tick(),
+            Progress::Phase(Phase::Download) => self.bytes("Writing"),
+            Progress::Phase(Phase::Manifest) => self.spin("Manifesting"),
+            Progress::Phase(Phase::Upload) => self.bytes("Reading"),
+            Progress::Phase(Phase::Verify) => self.bytes("Verifying"),
+            Progress::Phase(Phase::Reset) => self.finish(),
+            Progress::Erase {
+                address,
+                index,
+                total,
+            } => {
+                if index == 0 {
+                    match total {
+                        Some(total) => {
+                            self.style(SECTORS_TEMPLATE, "Erasing");
+                            self.bar.set_length(total as u64);
+                        }
+                        None => self.style(SECTOR_COUNT_TEMPLATE, "Erasing"),
+                    }
+                }
+                self.bar.set_position(index as u64 + 1);
+                self.bar.set_message(format!("Erasing {address:#010x}"));
+            }
+            Progress::Written(n) | Progress::Read(n) => self.bar.inc(n as u64),
+            Progress::Busy(_) => self.bar.tick(),
+        }
+    }
+}
+
+/// Bring the device back to idle after the user interrupted an operation
+pub async fn interrupted(device: &DfuNusb) -> anyhow::Error {
+    // Include this when reporting a download that hangs
+    eprintln!("Interrupted, aborting\n{}", device.debug_snapshot());
+    match device.abort().await {
+        Ok(state) => eprintln!("Device state: {state}"),
+        Err(e) => eprintln!("Could not abort the operation: {e}"),
+    }
+    Interrupted.into()
+}
+
+/// The user interrupted the operation
+#[derive(Debug)]
+pub struct Interrupted;
+
+impl std::fmt::Display for Interrupted {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "interrupted")
+    }
+}
+
+impl std::error::Error for Interrupted {}
+
 pub fn parse_vid_pid(s: &str) -> anyhow::Result<(u16, u16)> {
     let (vid, pid) = s
         .split_once(':')
@@ -142,6 +674,34 @@ pub fn parse_vid_pid(s: &str) -> anyhow::Result<(u16, u16)> {
     Ok((vid, pid))
 }
 
+pub fn parse_bus_device(s: &str) -> anyhow::Result<(u8, u8)> {
+    let (bus, address) = s
+        .split_once(':')
+        .context("could not parse bus/device (missing `:')")?;
+    let bus = bus.parse().context("could not parse bus number")?;
+    let address = address.parse().context("could not parse device address")?;
+
+    Ok((bus, address))
+}
+
+pub fn parse_byte(s: &str) -> anyhow::Result<u8> {
+    match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => u8::from_str_radix(hex, 16).context("could not parse byte"),
+        None => s.parse().context("could not parse byte"),
+    }
+}
+
+pub fn parse_request_index(s: &str) -> anyhow::Result<RequestIndex> {
+    match s {
+        "interface" => Ok(RequestIndex::Interface),
+        "zero" => Ok(RequestIndex::Zero),
+        "alt" => Ok(RequestIndex::AltSetting),
+        _ => Ok(RequestIndex::Fixed(
+            s.parse().context("could not parse request index")?,
+        )),
+    }
+}
+
 pub fn parse_address(s: &str) -> anyhow::Result<u32> {
     if s.to_ascii_lowercase().starts_with("0x") {
         u32::from_str_radix(&s[2..], 16).context("could not parse override address")
@@ -150,8 +710,149 @@ pub fn parse_address(s: &str) -> anyhow::Result<u32> {
     }
 }
 
+const AFTER_HELP: &str = "Default options are read from the DFU_NUSB_<OPTION> environment \
+    variables (e.g. DFU_NUSB_ALT=1) and from a TOML file given with --config or DFU_NUSB_CONFIG, \
+    or else found as dfu-nusb.toml in the current or the user configuration directory, with the \
+    long option names as keys (e.g. device = \"0483:df11\", verify = true). The command line \
+    wins over the environment, which wins over the file.
+
+Exit codes: 0 success, 1 failure, 2 usage, 3 wrong image, 4 device not found, 5 verify \
+    failed, 6 USB error, 7 device error, 130 cancelled";
+
+/// Options taking their defaults from the environment or the config file
+fn configurable(arg: &clap::Arg) -> Option<&str> {
+    match (arg.get_long()?, arg.get_action()) {
+        ("config" | "completions", _) => None,
+        (long, clap::ArgAction::Set | clap::ArgAction::SetTrue) => Some(long),
+        _ => None,
+    }
+}
+
+/// Arguments deciding which config file to read, found without parsing the command line as
+/// its defaults aren't known yet
+struct PreParsed {
+    /// Path given with --config
+    config: Option<PathBuf>,
+    /// Whether --help or --completions is given, for which no defaults are needed
+    no_defaults: bool,
+}
+
+fn pre_parse() -> anyhow::Result<PreParsed> {
+    let mut parsed = PreParsed {
+        config: None,
+        no_defaults: false,
+    };
+    let mut args = std::env::args_os().skip(1);
+    while let Some(arg) = args.next() {
+        let lossy = arg.to_string_lossy();
+        match &*lossy {
+            "--" => break,
+            "-h" | "--help" | "--completions" => parsed.no_defaults = true,
+            _ if lossy.starts_with("--completions=") => parsed.no_defaults = true,
+            "--config" => parsed.config = args.next().map(PathBuf::from),
+            _ if lossy.starts_with("--config=") => match arg.to_str() {
+                Some(arg) => parsed.config = Some(PathBuf::from(&arg["--config=".len()..])),
+                None => anyhow::bail!("the path given as --config=FILE isn't valid UTF-8"),
+            },
+            _ => (),
+        }
+    }
+    Ok(parsed)
+}
+
+/// Configuration file given with --config or DFU_NUSB_CONFIG, or found in the current or the
+/// user configuration directory
+fn config_path(explicit: Option<PathBuf>) -> Option<PathBuf> {
+    let explicit = explicit.or_else(|| std::env::var_os("DFU_NUSB_CONFIG").map(PathBuf::from));
+    if explicit.is_some() {
+        return explicit;
+    }
+    let config_dir = std::env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))
+        .or_else(|| std::env::var_os("APPDATA").map(PathBuf::from));
+    std::iter::once(PathBuf::from("."))
+        .chain(config_dir)
+        .map(|dir| dir.join("dfu-nusb.toml"))
+        .find(|path| path.is_file())
+}
+
+/// Parse the command line, taking defaults from the environment and the config file
+fn parse_cli() -> anyhow::Result<Cli> {
+    let pre_parsed = pre_parse()?;
+    if pre_parsed.no_defaults {
+        return Ok(Cli::from_arg_matches(&Cli::command().get_matches())?);
+    }
+    let mut config = match config_path(pre_parsed.config) {
+        Some(path) => {
+            let text = std::fs::read_to_string(&path)
+                .with_context(|| format!("could not read {}", path.display()))?;
+            toml::from_str(&text).with_context(|| format!("could not parse {}", path.display()))?
+        }
+        None => toml::Table::new(),
+    };
+
+    let mut command = Cli::command();
+    let options: Vec<_> = command
+        .get_arguments()
+        .filter_map(|arg| Some((arg.get_id().clone(), configurable(arg)?.to_string())))
+        .collect();
+    for (id, long) in options {
+        let variable = format!("DFU_NUSB_{}", long.to_uppercase().replace('-', "_"));
+        let value = match std::env::var(&variable) {
+            Ok(value) => Some(value),
+            Err(std::env::VarError::NotUnicode(_)) => {
+                anyhow::bail!("{variable} isn't valid UTF-8")
+            }
+            Err(std::env::VarError::NotPresent) => match config.remove(&long) {
+                Some(toml::Value::String(value)) => Some(value),
+                Some(toml::Value::Integer(value)) => Some(value.to_string()),
+                Some(toml::Value::Boolean(value)) => Some(value.to_string()),
+                Some(_) => anyhow::bail!("invalid value for {long} in the config file"),
+                None => None,
+            },
+        };
+        if let Some(value) = value {
+            command = command.mut_arg(id, |arg| arg.default_value(value));
+        }
+    }
+    if let Some(key) = config.keys().next() {
+        anyhow::bail!("unknown option {key} in the config file");
+    }
+
+    Ok(Cli::from_arg_matches(&command.get_matches())?)
+}
+
+/// Exit code for `error`, see [`dfu_nusb::exit`]
+fn exit_code(error: &anyhow::Error) -> u8 {
+    if error.chain().any(|e| e.is::<Interrupted>()) {
+        return exit::CANCELLED;
+    }
+    error
+        .chain()
+        .find_map(|e| e.downcast_ref::<dfu_nusb::Error>())
+        .map_or(exit::FAILURE, dfu_nusb::Error::exit_code)
+}
+
 #[tokio::main]
-async fn main() -> anyhow::Result<()> {
-    let opts = Cli::parse();
-    run(opts).await
+async fn main() -> ExitCode {
+    let opts = match parse_cli() {
+        Ok(opts) => opts,
+        Err(e) => {
+            eprintln!("Error: {e:?}");
+            return ExitCode::from(exit::USAGE);
+        }
+    };
+    if let Some(shell) = opts.completions {
+        let mut command = Cli::command();
+        clap_complete::generate(shell, &mut command, "dfu-nusb", &mut io::stdout());
+        return ExitCode::SUCCESS;
+    }
+    match run(opts).await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("Error: {e:?}");
+            ExitCode::from(exit_code(&e))
+        }
+    }
 }
@@ -0,0 +1,234 @@
+//! Provision devices from a manifest, e.g. in a factory flow
+//!
+//! ```toml
+//! verify = true
+//! finalize = "reset"
+//!
+//! [device]
+//! vid_pid = "0483:df11"
+//! serial = "PROTO-*"
+//!
+//! [[step]]
+//! name = "bootloader"
+//! image = "bootloader.bin"
+//! alt = 0
+//! address = 0x08000000
+//!
+//! [[step]]
+//! image = "application.dfu"
+//! alt = 1
+//! ```
+//!
+//! Image paths are relative to the manifest. DfuSe devices leave DFU mode at the end of
+//! every download, so give them a single step with a multi-element DfuSe image.
+
+use anyhow::Context;
+use clap::Parser;
+use dfu_nusb::{exit, DeviceFilter, DownloadReport, Finalize, FlashOptions, OpenOptions};
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::time::Duration;
+
+#[derive(clap::Parser)]
+#[clap(after_help = EXIT_CODES)]
+pub struct Cli {
+    /// Path to the provisioning manifest.
+    #[clap(long)]
+    manifest: PathBuf,
+
+    /// Seconds to wait for the device to show up before each step.
+    #[clap(long, default_value = "10")]
+    wait: u64,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Manifest {
+    device: DeviceSection,
+    /// Read back every image, unless overridden by the step
+    #[serde(default)]
+    verify: bool,
+    /// What to do once the last step was written
+    #[serde(default)]
+    finalize: FinalizePolicy,
+    #[serde(rename = "step")]
+    steps: Vec<Step>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct DeviceSection {
+    vid_pid: Option<String>,
+    serial: Option<String>,
+    product: Option<String>,
+    path: Option<String>,
+    #[serde(default)]
+    interface: u8,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Step {
+    name: Option<String>,
+    image: PathBuf,
+    #[serde(default)]
+    alt: u8,
+    address: Option<u32>,
+    verify: Option<bool>,
+    #[serde(default)]
+    skip_erase: bool,
+}
+
+#[derive(Deserialize, Default, Clone, Copy)]
+#[serde(rename_all = "kebab-case")]
+enum FinalizePolicy {
+    #[default]
+    None,
+    Reset,
+    DetachAndReset,
+}
+
+impl From<FinalizePolicy> for Finalize {
+    fn from(policy: FinalizePolicy) -> Self {
+        match policy {
+            FinalizePolicy::None => Finalize::None,
+            FinalizePolicy::Reset => Finalize::Reset,
+            FinalizePolicy::DetachAndReset => Finalize::DetachAndReset,
+        }
+    }
+}
+
+fn filter(device: &DeviceSection) -> anyhow::Result<DeviceFilter> {
+    let mut filter = DeviceFilter::new();
+    if let Some(vid_pid) = &device.vid_pid {
+        let (vid, pid) = parse_vid_pid(vid_pid)?;
+        filter = filter.vid_pid(vid, pid);
+    }
+    if let Some(serial) = &device.serial {
+        filter = filter.serial(serial);
+    }
+    if let Some(product) = &device.product {
+        filter = filter.product(product);
+    }
+    if let Some(path) = &device.path {
+        filter = filter.path(path);
+    }
+    Ok(filter)
+}
+
+async fn run_step(
+    filter: &DeviceFilter,
+    firmware: &[u8],
+    options: &FlashOptions,
+    wait: Duration,
+) -> anyhow::Result<dfu_nusb::FlashReport> {
+    dfu_nusb::wait_for_device(
+        filter,
+        Duration::from_millis(250),
+        wait,
+        &dfu_nusb::StdTimer,
+    )
+    .await
+    .context("device not found")?;
+
+    let report = dfu_nusb::flash_and_verify(filter, firmware, options).await?;
+    if let Some(image) = &report.image {
+        println!("{image}");
+    }
+    if !report.is_complete() {
+        let failure = report.image.and_then(DownloadReport::into_failure);
+        return match failure {
+            Some(e) => Err(e).context("the firmware image was only partially written"),
+            None => anyhow::bail!("the firmware image was only partially written"),
+        };
+    }
+    Ok(report)
+}
+
+pub async fn run(opts: Cli) -> anyhow::Result<()> {
+    let Cli { manifest, wait } = opts;
+    let text = std::fs::read_to_string(&manifest).context("could not read manifest")?;
+    let parsed: Manifest = toml::from_str(&text).context("could not parse manifest")?;
+    let base = manifest.parent().unwrap_or(Path::new("."));
+    let filter = filter(&parsed.device)?;
+    let total = parsed.steps.len();
+
+    for (i, step) in parsed.steps.iter().enumerate() {
+        let name = step
+            .name
+            .clone()
+            .unwrap_or_else(|| step.image.display().to_string());
+        println!("[{}/{total}] {name} -> alt {}", i + 1, step.alt);
+
+        let path = base.join(&step.image);
+        let firmware = std::fs::read(&path)
+            .with_context(|| format!("could not read image {}", path.display()))?;
+
+        let last = i + 1 == total;
+        let mut options = FlashOptions::new()
+            .open_options(
+                OpenOptions::new()
+                    .interface(parsed.device.interface)
+                    .alt(step.alt),
+            )
+            .verify(step.verify.unwrap_or(parsed.verify))
+            .skip_erase(step.skip_erase)
+            .finalize(if last {
+                parsed.finalize.into()
+            } else {
+                Finalize::None
+            });
+        if let Some(address) = step.address {
+            options = options.address(address);
+        }
+
+        let report = run_step(&filter, &firmware, &options, Duration::from_secs(wait))
+            .await
+            .with_context(|| format!("step {} ({name}) failed", i + 1))?;
+        println!(
+            "[{}/{total}] {name}: {} bytes written to \"{}\"{} in {:.1?}",
+            i + 1,
+            report.size,
+            report.alt_name,
+            if report.verified { ", verified" } else { "" },
+            report.elapsed,
+        );
+    }
+
+    println!("Provisioning complete");
+    Ok(())
+}
+
+pub fn parse_vid_pid(s: &str) -> anyhow::Result<(u16, u16)> {
+    let (vid, pid) = s
+        .split_once(':')
+        .context("could not parse VID/PID (missing `:')")?;
+    let vid = u16::from_str_radix(vid, 16).context("could not parse VID")?;
+    let pid = u16::from_str_radix(pid, 16).context("could not parse PID")?;
+
+    Ok((vid, pid))
+}
+
+const EXIT_CODES: &str = "Exit codes: 0 success, 1 failure, 2 usage, 3 wrong image, \
+    4 device not found, 5 verify failed, 6 USB error, 7 device error, 130 cancelled";
+
+/// Exit code for `error`, see [`dfu_nusb::exit`]
+fn exit_code(error: &anyhow::Error) -> u8 {
+    error
+        .chain()
+        .find_map(|e| e.downcast_ref::<dfu_nusb::Error>())
+        .map_or(exit::FAILURE, dfu_nusb::Error::exit_code)
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    let opts = Cli::parse();
+    match run(opts).await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("Error: {e:?}");
+            ExitCode::from(exit_code(&e))
+        }
+    }
+}
@@ -0,0 +1,19 @@
+use anyhow::Context;
+use clap::Parser;
+use dfu_nusb::FirmwareInfo;
+use std::path::PathBuf;
+
+#[derive(clap::Parser)]
+pub struct Cli {
+    /// Path to the firmware file to inspect.
+    path: PathBuf,
+}
+
+fn main() -> anyhow::Result<()> {
+    let Cli { path } = Cli::parse();
+    let data = std::fs::read(&path).context("could not read firmware file")?;
+    let info = FirmwareInfo::parse(&data).context("could not parse firmware file")?;
+    println!("{info}");
+
+    Ok(())
+}
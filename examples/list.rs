@@ -0,0 +1,122 @@
+use anyhow::Context;
+use clap::Parser;
+use dfu_nusb::{DeviceFilter, DfuDeviceInfo, DfuMode, OpenOptions, Timeouts};
+
+#[derive(clap::Parser)]
+pub struct Cli {
+    /// Only list devices with the given Vendor/Product ID.
+    #[clap(
+        long,
+        short,
+        value_parser = parse_vid_pid, name = "vendor>:<product",
+    )]
+    device: Option<(u16, u16)>,
+
+    /// Only list devices in runtime mode, which need a detach before flashing.
+    #[clap(long, conflicts_with = "dfu_mode")]
+    runtime: bool,
+
+    /// Only list devices in DFU mode, ready to be flashed.
+    #[clap(long)]
+    dfu_mode: bool,
+
+    /// Print the same `Found DFU: ...` lines as `dfu-util --list`.
+    #[clap(long)]
+    dfu_util: bool,
+
+    /// Hexdump the raw descriptors of each DFU interface.
+    #[clap(long)]
+    descriptors: bool,
+}
+
+pub fn parse_vid_pid(s: &str) -> anyhow::Result<(u16, u16)> {
+    let (vid, pid) = s
+        .split_once(':')
+        .context("could not parse VID/PID (missing `:')")?;
+    let vid = u16::from_str_radix(vid, 16).context("could not parse VID")?;
+    let pid = u16::from_str_radix(pid, 16).context("could not parse PID")?;
+
+    Ok((vid, pid))
+}
+
+fn main() -> anyhow::Result<()> {
+    let Cli {
+        device,
+        runtime,
+        dfu_mode,
+        dfu_util,
+        descriptors,
+    } = Cli::parse();
+    let mut filter = match device {
+        Some((vid, pid)) => DeviceFilter::new().vid_pid(vid, pid),
+        None => DeviceFilter::new(),
+    };
+    if runtime {
+        filter = filter.mode(DfuMode::Runtime);
+    }
+    if dfu_mode {
+        filter = filter.mode(DfuMode::Dfu);
+    }
+
+    for device in dfu_nusb::list_devices(&filter).context("could not list devices")? {
+        if dfu_util {
+            match device.dfu_util_list(&Timeouts::default()) {
+                Ok(lines) => lines.iter().for_each(|line| println!("{line}")),
+                Err(e) => eprintln!("Cannot open DFU device {}: {e}", device.port()),
+            }
+        } else {
+            let info = device.info();
+            let mode = match device.mode() {
+                Some(DfuMode::Runtime) => "runtime",
+                Some(DfuMode::Dfu) => "dfu",
+                None => "unknown",
+            };
+            let bootloader = device.bootloader();
+            println!(
+                "[{:04x}:{:04x}] {} serial={} port={} mode={mode} driver={} bootloader={}",
+                info.vendor_id(),
+                info.product_id(),
+                device.product().unwrap_or("<unknown>"),
+                device.serial().unwrap_or("<none>"),
+                device.port(),
+                device.driver().as_deref().unwrap_or("<none>"),
+                bootloader.map_or_else(|| "<unknown>".to_string(), |b| b.to_string()),
+            );
+            #[cfg(target_os = "windows")]
+            println!("  path={}", device.interface_path());
+        }
+        if descriptors {
+            if let Err(e) = dump_descriptors(&device) {
+                eprintln!("Cannot read descriptors of {}: {e}", device.port());
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn dump_descriptors(device: &DfuDeviceInfo) -> anyhow::Result<()> {
+    let usb = device.info().open()?;
+    for function in dfu_nusb::scan_functions(&usb, &Timeouts::default())? {
+        let dfu = OpenOptions::new()
+            .interface(function.interface)
+            .open(device)?;
+        let raw = dfu.raw_descriptors();
+        for alt in &raw.alt_settings {
+            println!("  interface {} alt {}:", function.interface, alt.alt);
+            hexdump(&alt.bytes);
+        }
+        if let Some(functional) = &raw.functional {
+            println!("  interface {} functional descriptor:", function.interface);
+            hexdump(functional);
+        }
+    }
+    Ok(())
+}
+
+fn hexdump(bytes: &[u8]) {
+    for (i, line) in bytes.chunks(16).enumerate() {
+        let hex: Vec<_> = line.iter().map(|b| format!("{b:02x}")).collect();
+        println!("    {:04x}: {}", i * 16, hex.join(" "));
+    }
+}
@@ -0,0 +1,84 @@
+//! Print everything a device tells about its DFU capabilities, e.g. to add a quirk for it
+
+use anyhow::Context;
+use clap::Parser;
+use dfu_nusb::{DeviceFilter, OpenOptions};
+
+#[derive(clap::Parser)]
+pub struct Cli {
+    /// Specify Vendor/Product ID(s) of DFU device.
+    #[clap(long, short, value_parser = parse_vid_pid, name = "vendor>:<product")]
+    device: Option<(u16, u16)>,
+
+    /// Match the serial number of the device, `*` matching any sequence of characters.
+    #[clap(long)]
+    serial: Option<String>,
+
+    /// Open the device with this platform path (sysfs path, instance or interface path or
+    /// location ID).
+    #[clap(long)]
+    path: Option<String>,
+
+    /// Specify the DFU Interface number.
+    #[clap(long, short, default_value = "0")]
+    intf: u8,
+
+    /// Probe the capacity of plain DFU targets with uploads.
+    #[clap(long)]
+    probe_capacity: bool,
+}
+
+pub fn parse_vid_pid(s: &str) -> anyhow::Result<(u16, u16)> {
+    let (vid, pid) = s
+        .split_once(':')
+        .context("could not parse VID/PID (missing `:')")?;
+    let vid = u16::from_str_radix(vid, 16).context("could not parse VID")?;
+    let pid = u16::from_str_radix(pid, 16).context("could not parse PID")?;
+
+    Ok((vid, pid))
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let Cli {
+        device,
+        serial,
+        path,
+        intf,
+        probe_capacity,
+    } = Cli::parse();
+    let mut filter = DeviceFilter::new();
+    if let Some((vid, pid)) = device {
+        filter = filter.vid_pid(vid, pid);
+    }
+    if let Some(serial) = serial {
+        filter = filter.serial(serial);
+    }
+    if let Some(path) = path {
+        filter = filter.path(path);
+    }
+
+    let mut devices = dfu_nusb::list_devices(&filter)?;
+    let info = match devices.len() {
+        0 => return Err(dfu_nusb::Error::DeviceNotFound).context("could not open device"),
+        1 => devices.pop().unwrap(),
+        n => anyhow::bail!("{n} devices match, narrow the selection down"),
+    };
+    let device = OpenOptions::new()
+        .interface(intf)
+        .open(&info)
+        .context("could not open device")?;
+    if probe_capacity {
+        device
+            .probe_capacity()
+            .await
+            .context("could not probe the capacity of the target")?;
+    }
+    let report = device
+        .capability_report()
+        .await
+        .context("could not read the capabilities of the device")?;
+    println!("{report}");
+
+    Ok(())
+}
@@ -0,0 +1,89 @@
+//! Write a firmware file to a device on the smol executor rather than tokio, the way GUI
+//! applications embedding the library without a tokio runtime do
+
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use anyhow::Context;
+use clap::Parser;
+use dfu_nusb::{DeviceFilter, OpenOptions, Phase, Progress, StdTimer};
+
+#[derive(clap::Parser)]
+pub struct Cli {
+    /// Firmware file to write.
+    path: PathBuf,
+
+    /// Specify Vendor/Product ID(s) of DFU device.
+    #[clap(long, short, value_parser = parse_vid_pid, name = "vendor>:<product")]
+    device: Option<(u16, u16)>,
+
+    /// Specify the DFU Interface number.
+    #[clap(long, short, default_value = "0")]
+    intf: u8,
+
+    /// Specify the Altsetting of the DFU Interface by number.
+    #[clap(long, default_value = "0")]
+    alt: u8,
+}
+
+pub fn parse_vid_pid(s: &str) -> anyhow::Result<(u16, u16)> {
+    let (vid, pid) = s
+        .split_once(':')
+        .context("could not parse VID/PID (missing `:')")?;
+    let vid = u16::from_str_radix(vid, 16).context("could not parse VID")?;
+    let pid = u16::from_str_radix(pid, 16).context("could not parse PID")?;
+
+    Ok((vid, pid))
+}
+
+fn main() -> anyhow::Result<()> {
+    let Cli {
+        path,
+        device,
+        intf,
+        alt,
+    } = Cli::parse();
+    let filter = match device {
+        Some((vid, pid)) => DeviceFilter::new().vid_pid(vid, pid),
+        None => DeviceFilter::new(),
+    };
+
+    smol::block_on(async {
+        let file = smol::fs::File::open(&path)
+            .await
+            .with_context(|| format!("could not open {}", path.display()))?;
+        let length = u32::try_from(file.metadata().await?.len()).context("firmware too large")?;
+
+        let mut devices = dfu_nusb::list_devices(&filter)?;
+        let info = match devices.len() {
+            0 => return Err(dfu_nusb::Error::DeviceNotFound).context("could not open device"),
+            1 => devices.pop().unwrap(),
+            n => anyhow::bail!("{n} devices match, narrow the selection down"),
+        };
+        // The default timer follows the runtime features, which may include tokio
+        let device = OpenOptions::new()
+            .interface(intf)
+            .alt(alt)
+            .timer(Arc::new(StdTimer))
+            .open(&info)
+            .context("could not open device")?
+            .with_progress({
+                let mut written = 0;
+                move |progress| match progress {
+                    Progress::Phase(Phase::Manifest) => println!("Manifesting"),
+                    Progress::Written(n) => {
+                        written += n;
+                        println!("Written {written}/{length} bytes");
+                    }
+                    _ => (),
+                }
+            });
+
+        device
+            .download(file, length)
+            .await
+            .context("could not write the firmware")?;
+        println!("Done");
+        Ok(())
+    })
+}
@@ -0,0 +1,131 @@
+//! Bring-up diagnostics: repeatedly write a pseudorandom pattern to a scratch region of a
+//! device and read it back
+
+use anyhow::Context;
+use clap::Parser;
+use dfu_nusb::{DeviceFilter, OpenOptions, StressOptions};
+use std::process::ExitCode;
+
+#[derive(clap::Parser)]
+pub struct Cli {
+    /// Specify Vendor/Product ID(s) of DFU device.
+    #[clap(long, short, value_parser = parse_vid_pid, name = "vendor>:<product")]
+    device: Option<(u16, u16)>,
+
+    /// Match the serial number of the device, `*` matching any sequence of characters.
+    #[clap(long)]
+    serial: Option<String>,
+
+    /// Specify the DFU Interface number.
+    #[clap(long, short, default_value = "0")]
+    intf: u8,
+
+    /// Specify the Altsetting of the DFU Interface by number.
+    #[clap(long, short, default_value = "0")]
+    alt: u8,
+
+    /// Start of the scratch region of DfuSe devices, which is overwritten.
+    #[clap(long, value_parser = parse_address)]
+    address: u32,
+
+    /// Size of the scratch region in bytes.
+    #[clap(long)]
+    length: usize,
+
+    /// Number of write and read back cycles.
+    #[clap(long, default_value = "100")]
+    iterations: u32,
+
+    /// Seed of the patterns, to reproduce a previous run.
+    #[clap(long, value_parser = parse_address)]
+    seed: Option<u32>,
+
+    /// Stop at the first mismatch or failure.
+    #[clap(long)]
+    stop_on_error: bool,
+}
+
+pub fn parse_vid_pid(s: &str) -> anyhow::Result<(u16, u16)> {
+    let (vid, pid) = s
+        .split_once(':')
+        .context("could not parse VID/PID (missing `:')")?;
+    let vid = u16::from_str_radix(vid, 16).context("could not parse VID")?;
+    let pid = u16::from_str_radix(pid, 16).context("could not parse PID")?;
+
+    Ok((vid, pid))
+}
+
+pub fn parse_address(s: &str) -> anyhow::Result<u32> {
+    if s.to_ascii_lowercase().starts_with("0x") {
+        u32::from_str_radix(&s[2..], 16).context("could not parse number")
+    } else {
+        s.parse().context("could not parse number")
+    }
+}
+
+pub async fn run(opts: Cli) -> anyhow::Result<bool> {
+    let Cli {
+        device,
+        serial,
+        intf,
+        alt,
+        address,
+        length,
+        iterations,
+        seed,
+        stop_on_error,
+    } = opts;
+    let mut filter = DeviceFilter::new();
+    if let Some((vid, pid)) = device {
+        filter = filter.vid_pid(vid, pid);
+    }
+    if let Some(serial) = serial {
+        filter = filter.serial(serial);
+    }
+
+    let mut devices = dfu_nusb::list_devices(&filter)?;
+    let info = match devices.len() {
+        0 => return Err(dfu_nusb::Error::DeviceNotFound).context("could not open device"),
+        1 => devices.pop().unwrap(),
+        n => anyhow::bail!("{n} devices match, narrow the selection down"),
+    };
+    let mut device = OpenOptions::new()
+        .interface(intf)
+        .alt(alt)
+        .open(&info)
+        .context("could not open device")?;
+
+    let mut options = StressOptions::new(address, length)
+        .iterations(iterations)
+        .stop_on_error(stop_on_error);
+    if let Some(seed) = seed {
+        options = options.seed(seed);
+    }
+    println!(
+        "Writing {length} bytes to \"{}\" {iterations} times",
+        device.alt_name()
+    );
+    let report = device
+        .stress_test(&options)
+        .await
+        .context("could not run the stress test")?;
+    println!("{report}");
+
+    Ok(
+        report.passed() == report.iterations.len()
+            && report.iterations.len() == iterations as usize,
+    )
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    let opts = Cli::parse();
+    match run(opts).await {
+        Ok(true) => ExitCode::SUCCESS,
+        Ok(false) => ExitCode::FAILURE,
+        Err(e) => {
+            eprintln!("Error: {e:?}");
+            ExitCode::FAILURE
+        }
+    }
+}
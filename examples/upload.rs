@@ -0,0 +1,113 @@
+//! Read the memory of a device into a raw binary or, for DfuSe devices, a `.dfu` image which
+//! can be written back by any DfuSe tool
+
+use std::path::PathBuf;
+
+use anyhow::Context;
+use clap::Parser;
+use dfu_nusb::{DeviceFilter, OpenOptions, Progress};
+
+#[derive(clap::Parser)]
+pub struct Cli {
+    /// File to write; a `.dfu` extension writes a DfuSe image rather than raw data.
+    output: PathBuf,
+
+    /// Number of bytes to read, defaulting to the rest of the memory, probed with uploads for
+    /// plain DFU targets.
+    #[clap(long, short, value_parser = parse_number)]
+    length: Option<u32>,
+
+    /// Address to read from, defaulting to the start of the memory of DfuSe targets.
+    #[clap(long, short, value_parser = parse_number)]
+    address: Option<u32>,
+
+    /// Specify Vendor/Product ID(s) of DFU device.
+    #[clap(long, short, value_parser = parse_vid_pid, name = "vendor>:<product")]
+    device: Option<(u16, u16)>,
+
+    /// Match the serial number of the device, `*` matching any sequence of characters.
+    #[clap(long)]
+    serial: Option<String>,
+
+    /// Specify the DFU Interface number.
+    #[clap(long, short, default_value = "0")]
+    intf: u8,
+
+    /// Specify the Altsetting of the DFU Interface by number.
+    #[clap(long, default_value = "0")]
+    alt: u8,
+}
+
+pub fn parse_vid_pid(s: &str) -> anyhow::Result<(u16, u16)> {
+    let (vid, pid) = s
+        .split_once(':')
+        .context("could not parse VID/PID (missing `:')")?;
+    let vid = u16::from_str_radix(vid, 16).context("could not parse VID")?;
+    let pid = u16::from_str_radix(pid, 16).context("could not parse PID")?;
+
+    Ok((vid, pid))
+}
+
+pub fn parse_number(s: &str) -> anyhow::Result<u32> {
+    match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => u32::from_str_radix(hex, 16).context("could not parse number"),
+        None => s.parse().context("could not parse number"),
+    }
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let Cli {
+        output,
+        length,
+        address,
+        device,
+        serial,
+        intf,
+        alt,
+    } = Cli::parse();
+    let mut filter = DeviceFilter::new();
+    if let Some((vid, pid)) = device {
+        filter = filter.vid_pid(vid, pid);
+    }
+    if let Some(serial) = serial {
+        filter = filter.serial(serial);
+    }
+
+    let mut devices = dfu_nusb::list_devices(&filter)?;
+    let info = match devices.len() {
+        0 => return Err(dfu_nusb::Error::DeviceNotFound).context("could not open device"),
+        1 => devices.pop().unwrap(),
+        n => anyhow::bail!("{n} devices match, narrow the selection down"),
+    };
+    let device = OpenOptions::new()
+        .interface(intf)
+        .alt(alt)
+        .open(&info)
+        .context("could not open device")?;
+
+    let address = address.or(device.default_address()).unwrap_or_default();
+    let length = device
+        .upload_length(address, length.map(|length| length as usize))
+        .await
+        .context("could not find the size of the memory, use --length")?;
+    let bar = indicatif::ProgressBar::new(length as u64);
+    let progress = bar.clone();
+    let device = device.with_progress(move |event| {
+        if let Progress::Read(n) = event {
+            progress.inc(n as u64);
+        }
+    });
+    let data = if output.extension().is_some_and(|ext| ext == "dfu") {
+        device.read_image(address, length).await
+    } else {
+        device.read_memory(address, length).await
+    }
+    .context("could not read the memory of the device")?;
+    bar.finish();
+    std::fs::write(&output, &data)
+        .with_context(|| format!("could not write {}", output.display()))?;
+    println!("Wrote {} bytes to {}", data.len(), output.display());
+
+    Ok(())
+}
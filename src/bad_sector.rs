@@ -0,0 +1,358 @@
+use crate::padding::page_bounds;
+use crate::upload::{dfuse_command, ensure_idle, verify_block, wait_idle, DfuDevice};
+use crate::{info, DfuNusb, Error, DFUSE_ERASE, DFUSE_SET_ADDRESS, DFU_DNLOAD, REQUEST_TYPE_OUT};
+
+/// What to do when a DfuSe target reports errWRITE for a sector
+///
+/// NAND flash comes with bad blocks which can't be written. By default the download fails on
+/// the first one; with [`BadSectorPolicy::Skip`] the data meant for a bad sector is written
+/// to the following sectors instead, the way NAND bootloaders and file systems skip bad
+/// blocks, and the download goes on. Everything after a skipped sector ends up at a later
+/// address than in the firmware, so only use this for targets and images laid out for bad
+/// block skipping. Write protected sectors of NOR flash also report errWRITE.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum BadSectorPolicy {
+    /// Fail the download
+    #[default]
+    Abort,
+    /// Skip at most `limit` sectors failing with errWRITE, listing them in
+    /// [`DfuNusb::skipped_sectors`]
+    Skip {
+        /// Number of sectors which may be skipped before failing the download
+        limit: usize,
+    },
+}
+
+/// Where the blocks of a download end up, as bad sectors are skipped
+pub(crate) struct SectorMap {
+    base: u32,
+    pages: Vec<u32>,
+    limit: usize,
+    transfer_size: u32,
+    skip_erase: bool,
+    verify: bool,
+    /// CRC command verifying moved blocks, if the device computes their CRC
+    device_crc: Option<u8>,
+    /// Start address of the download
+    start: u32,
+    /// Address the next block is written to
+    address: u32,
+    /// Address set with the DfuSe set address command, which block numbers are based on
+    pointer: u32,
+    /// End of the memory erased so far
+    erased_end: u32,
+    /// Data written to the sector containing `address`, moved along if the sector is bad
+    sector: Vec<u8>,
+    /// Start addresses of the sectors skipped so far
+    skipped: Vec<u32>,
+}
+
+impl SectorMap {
+    /// Track a download of `length` bytes at `start` into the memory starting at `base` with
+    /// the given pages, skipping at most `limit` sectors
+    fn new(
+        base: u32,
+        pages: Vec<u32>,
+        limit: usize,
+        transfer_size: u32,
+        start: u32,
+        length: u32,
+    ) -> Self {
+        // The download erased every sector it overlaps
+        let last = start.saturating_add(length.max(1) - 1);
+        let erased_end = page_bounds(base, &pages, last).map_or(last, |(_, end)| end);
+        Self {
+            base,
+            pages,
+            limit,
+            transfer_size,
+            skip_erase: false,
+            verify: false,
+            device_crc: None,
+            start,
+            address: start,
+            pointer: start,
+            erased_end,
+            sector: Vec::new(),
+            skipped: Vec::new(),
+        }
+    }
+
+    /// Base address and offset of the next block, for [`verify_block`]
+    pub(crate) fn position(&self) -> (u32, u32) {
+        (self.pointer, self.address.wrapping_sub(self.pointer))
+    }
+
+    /// Start addresses of the sectors skipped so far
+    pub(crate) fn skipped(&self) -> &[u32] {
+        &self.skipped
+    }
+
+    fn bounds(&self, address: u32) -> Option<(u32, u32)> {
+        page_bounds(self.base, &self.pages, address)
+    }
+
+    /// Record a block written at the current address
+    pub(crate) fn written(&mut self, data: &[u8]) {
+        self.sector.extend_from_slice(data);
+        self.address = self.address.wrapping_add(data.len() as u32);
+        // Only keep the data of the sector the next block goes to
+        if let Some((start, _)) = self.bounds(self.address) {
+            let keep = self
+                .sector
+                .len()
+                .min(self.address.wrapping_sub(start) as usize);
+            self.sector.drain(..self.sector.len() - keep);
+        }
+    }
+
+    /// Erase the sectors up to `end` which weren't erased by the download, as data moved past
+    /// bad sectors goes beyond it
+    pub(crate) async fn erase_moved<D: DfuDevice>(
+        &mut self,
+        device: &D,
+        end: u32,
+    ) -> Result<(), Error> {
+        if self.skip_erase || self.erased_end >= end {
+            return Ok(());
+        }
+        while self.erased_end < end {
+            let (start, sector_end) = self
+                .bounds(self.erased_end)
+                .ok_or(dfu_core::Error::NoSpaceLeft)?;
+            dfuse_command(device, DFUSE_ERASE, start).await?;
+            self.erased_end = sector_end;
+        }
+        // The block numbers of the following blocks are based on the address pointer
+        dfuse_command(device, DFUSE_SET_ADDRESS, self.pointer).await
+    }
+
+    /// Write the data of the failed block at `next_block - 1` and of the sector it belongs to
+    /// to the following sectors, and point the block numbers from `next_block` at the end of
+    /// the moved data
+    pub(crate) async fn skip_bad_sector<D: DfuDevice>(
+        &mut self,
+        device: &D,
+        block: &[u8],
+        next_block: u16,
+        mut error: Error,
+    ) -> Result<(), Error> {
+        let mut pending = block.to_vec();
+        loop {
+            let Some((sector, end)) = self.bounds(self.address) else {
+                return Err(error);
+            };
+            if self.skipped.len() >= self.limit {
+                return Err(error);
+            }
+            self.skipped.push(sector);
+            info!("Skipping bad sector at {sector:#010x}");
+            ensure_idle(device).await?;
+
+            pending.splice(0..0, std::mem::take(&mut self.sector));
+            self.address = end;
+            match self.write_moved(device, &pending).await? {
+                None => break,
+                Some((written, e)) => {
+                    pending.drain(..written);
+                    error = e;
+                }
+            }
+        }
+
+        self.pointer = self
+            .address
+            .wrapping_sub(u32::from(next_block.wrapping_sub(2)).wrapping_mul(self.transfer_size));
+        dfuse_command(device, DFUSE_SET_ADDRESS, self.pointer).await
+    }
+
+    /// Write `data` from the start of the sector at the current address, returning the number
+    /// of bytes written and the error if a block fails with errWRITE
+    async fn write_moved<D: DfuDevice>(
+        &mut self,
+        device: &D,
+        data: &[u8],
+    ) -> Result<Option<(usize, Error)>, Error> {
+        let mut written = 0;
+        while written < data.len() {
+            let (_, end) = self
+                .bounds(self.address)
+                .ok_or(dfu_core::Error::NoSpaceLeft)?;
+            self.pointer = self.address;
+            self.erase_moved(device, end).await?;
+            dfuse_command(device, DFUSE_SET_ADDRESS, self.pointer).await?;
+
+            // Blocks don't cross the end of the sector, so a bad sector only holds its own data
+            let mut block: u16 = 2;
+            while written < data.len() && self.address < end {
+                let size = (self.transfer_size as usize)
+                    .min(data.len() - written)
+                    .min(end.wrapping_sub(self.address) as usize);
+                let chunk = &data[written..written + size];
+                let (pointer, offset) = self.position();
+                device
+                    .write_control(REQUEST_TYPE_OUT, DFU_DNLOAD, block, chunk)
+                    .await?;
+                match wait_idle(device).await {
+                    Ok(_) => (),
+                    Err(e) if e.is_write_protected() => return Ok(Some((written, e))),
+                    Err(e) => return Err(e),
+                }
+                let crc = self.device_crc.map(|command| (command, pointer, offset));
+                if self.verify && !verify_block(device, block, chunk, crc).await? {
+                    return Err(Error::VerifyMismatch {
+                        offset: self.address.wrapping_sub(self.start),
+                    });
+                }
+                self.written(chunk);
+                written += size;
+                block = block.wrapping_add(1);
+            }
+        }
+        Ok(None)
+    }
+}
+
+impl DfuNusb {
+    /// Skip sectors reporting errWRITE while writing, see [`BadSectorPolicy`]
+    pub fn bad_sector_policy(&mut self, policy: BadSectorPolicy) -> &mut Self {
+        self.bad_sectors = policy;
+        self
+    }
+
+    /// Start addresses of the sectors skipped by the last download, see [`BadSectorPolicy`]
+    pub fn skipped_sectors(&self) -> Vec<u32> {
+        self.skipped_sectors.lock().unwrap().clone()
+    }
+
+    /// Track the sectors written by a DfuSe download of `length` bytes at `start`, if bad
+    /// sectors are to be skipped
+    ///
+    /// With `device_crc`, moved blocks are verified with that CRC command.
+    pub(crate) fn sector_map(
+        &self,
+        start: u32,
+        length: u32,
+        device_crc: Option<u8>,
+    ) -> Option<SectorMap> {
+        self.skipped_sectors.lock().unwrap().clear();
+        let BadSectorPolicy::Skip { limit } = self.bad_sectors else {
+            return None;
+        };
+        let (base, pages) = (self.default_address()?, self.memory_layout()?.to_vec());
+        let transfer_size = u32::from(self.descriptor.transfer_size);
+        let mut map = SectorMap::new(base, pages, limit, transfer_size, start, length);
+        map.skip_erase = self.skip_erase;
+        map.verify = self.verify;
+        map.device_crc = device_crc;
+        Some(map)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use dfu_core::asynchronous::DfuAsyncIo;
+    use futures::executor::block_on;
+
+    use super::*;
+    use crate::fake::FakeDevice;
+
+    const BASE: u32 = 0x0800_0000;
+
+    fn map(limit: usize) -> SectorMap {
+        SectorMap::new(BASE, vec![1024; 4], limit, 256, BASE, 2048)
+    }
+
+    fn firmware(length: usize) -> Vec<u8> {
+        (0..length).map(|i| (i % 251) as u8).collect()
+    }
+
+    /// Write `data` from the start of the map the way downloads do
+    async fn download(device: &FakeDevice, map: &mut SectorMap, data: &[u8]) -> Result<(), Error> {
+        dfuse_command(device, DFUSE_SET_ADDRESS, BASE).await?;
+        for (block, chunk) in (2..).zip(data.chunks(256)) {
+            let (pointer, offset) = map.position();
+            map.erase_moved(device, pointer + offset + chunk.len() as u32)
+                .await?;
+            device
+                .write_control(REQUEST_TYPE_OUT, DFU_DNLOAD, block, chunk)
+                .await?;
+            match wait_idle(device).await {
+                Ok(_) => map.written(chunk),
+                Err(e) if e.is_write_protected() => {
+                    map.skip_bad_sector(device, chunk, block + 1, e).await?
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn sector_data_kept() {
+        let mut map = map(0);
+        assert_eq!(map.erased_end, BASE + 2048);
+        map.written(&[0; 768]);
+        assert_eq!((map.sector.len(), map.position()), (768, (BASE, 768)));
+        map.written(&[0; 512]);
+        assert_eq!((map.sector.len(), map.position()), (256, (BASE, 1280)));
+    }
+
+    #[test]
+    fn erased_end_rounded_to_sector() {
+        let map = SectorMap::new(BASE, vec![1024; 4], 0, 256, BASE + 256, 1024);
+        assert_eq!(map.erased_end, BASE + 2048);
+        let map = SectorMap::new(BASE, vec![1024; 4], 0, 256, BASE, 0);
+        assert_eq!(map.erased_end, BASE + 1024);
+    }
+
+    #[test]
+    fn bad_sector_skipped() {
+        let device = FakeDevice::dfuse(BASE, vec![1024; 4], 256).bad_sector(BASE + 1024);
+        let mut map = map(1);
+        let data = firmware(2048);
+        block_on(download(&device, &mut map, &data)).unwrap();
+        assert_eq!(map.skipped(), [BASE + 1024]);
+        assert_eq!(device.memory(BASE, 1024), data[..1024]);
+        assert_eq!(device.memory(BASE + 1024, 1024), [0xff; 1024]);
+        assert_eq!(device.memory(BASE + 2048, 1024), data[1024..]);
+    }
+
+    #[test]
+    fn consecutive_bad_sectors_skipped() {
+        let device = FakeDevice::dfuse(BASE, vec![1024; 4], 256)
+            .bad_sector(BASE)
+            .bad_sector(BASE + 1024);
+        let mut map = map(2);
+        let data = firmware(1536);
+        block_on(download(&device, &mut map, &data)).unwrap();
+        assert_eq!(map.skipped(), [BASE, BASE + 1024]);
+        assert_eq!(device.memory(BASE + 2048, 1536), data);
+    }
+
+    #[test]
+    fn skip_limit() {
+        let device = FakeDevice::dfuse(BASE, vec![1024; 4], 256)
+            .bad_sector(BASE + 1024)
+            .bad_sector(BASE + 2048);
+        let mut map = map(1);
+        let error = block_on(download(&device, &mut map, &firmware(2048))).unwrap_err();
+        assert!(error.is_write_protected());
+        assert_eq!(map.skipped(), [BASE + 1024]);
+    }
+
+    #[test]
+    fn no_limit() {
+        let device = FakeDevice::dfuse(BASE, vec![1024; 4], 256).bad_sector(BASE);
+        let error = block_on(download(&device, &mut map(0), &firmware(1024))).unwrap_err();
+        assert!(error.is_write_protected());
+    }
+
+    #[test]
+    fn out_of_sectors() {
+        let device = FakeDevice::dfuse(BASE, vec![1024; 4], 256).bad_sector(BASE + 3072);
+        let mut map = SectorMap::new(BASE, vec![1024; 4], 4, 256, BASE, 4096);
+        let error = block_on(download(&device, &mut map, &firmware(4096))).unwrap_err();
+        assert!(matches!(error, Error::Dfu(dfu_core::Error::NoSpaceLeft)));
+    }
+}
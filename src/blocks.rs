@@ -0,0 +1,44 @@
+use crate::file::crc32;
+
+/// Block of firmware as it was written to the device
+///
+/// Passed to the [`BlockHandler`]s of a device once the device reported the block written, so
+/// updaters can record exactly what was programmed, e.g. to validate an A/B slot later or to
+/// generate incremental patches against it.
+#[derive(Debug, Clone, Copy)]
+pub struct WrittenBlock<'a> {
+    /// Index of the block within the download, starting at 0
+    pub index: usize,
+    /// Address the block was written to, for DfuSe devices
+    pub address: Option<u32>,
+    /// Data of the block
+    pub data: &'a [u8],
+    /// CRC-32 (as computed by zlib or `crc32`) of the data
+    pub crc32: u32,
+}
+
+impl<'a> WrittenBlock<'a> {
+    pub(crate) fn new(index: usize, address: Option<u32>, data: &'a [u8]) -> Self {
+        Self {
+            index,
+            address,
+            data,
+            crc32: !crc32(data),
+        }
+    }
+}
+
+/// Receiver of the [`WrittenBlock`]s of a download
+pub trait BlockHandler: Send {
+    /// Handle a block the device wrote
+    fn block(&mut self, block: &WrittenBlock<'_>);
+}
+
+impl<F> BlockHandler for F
+where
+    F: FnMut(&WrittenBlock<'_>) + Send,
+{
+    fn block(&mut self, block: &WrittenBlock<'_>) {
+        self(block)
+    }
+}
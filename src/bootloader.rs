@@ -0,0 +1,199 @@
+use std::fmt;
+
+use crate::{DfuDeviceInfo, DfuNusb, Finalize};
+
+/// Bootloader recognised from the ids and strings of a device
+///
+/// Recognition is a heuristic: bootloaders don't identify themselves, so this relies on
+/// the ids and strings they ship with, which board vendors may change. See
+/// [`DfuDeviceInfo::bootloader`] and [`DfuNusb::bootloader`].
+///
+/// Recognition is reported, e.g. in the [`CapabilityReport`](crate::CapabilityReport), but
+/// doesn't change how the device is driven: a wrong guess mustn't break a download, so quirks
+/// like [`FinalStatus`](crate::FinalStatus) are left to the user. The only exception is
+/// [`Bootloader::finalize`], which [`flash_and_verify`](crate::flash_and_verify) follows to
+/// start the firmware.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Bootloader {
+    /// DfuSe bootloader in the system memory of STM32 microcontrollers
+    StDfuSe,
+    /// DFU gadget of u-boot
+    UBoot,
+    /// Bootloader of the Teensy boards, when built with DFU support (the stock HalfKay
+    /// bootloader is a HID device)
+    Teensy,
+    /// DFU reference implementations, like the examples shipped with dfu-util and libusb
+    Reference,
+    /// TinyUF2 built with DFU support
+    TinyUf2,
+}
+
+impl fmt::Display for Bootloader {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Bootloader::StDfuSe => "ST DfuSe ROM",
+            Bootloader::UBoot => "u-boot",
+            Bootloader::Teensy => "Teensy",
+            Bootloader::Reference => "DFU reference",
+            Bootloader::TinyUf2 => "TinyUF2",
+        })
+    }
+}
+
+impl Bootloader {
+    /// Recognise the bootloader of a device with the given ids and strings
+    ///
+    /// `dfuse` tells whether the device speaks DfuSe, if known.
+    pub fn detect(
+        device_ids: (u16, u16),
+        manufacturer: Option<&str>,
+        product: Option<&str>,
+        dfuse: Option<bool>,
+    ) -> Option<Self> {
+        let strings = [manufacturer, product]
+            .into_iter()
+            .flatten()
+            .map(str::to_ascii_lowercase)
+            .collect::<Vec<_>>();
+        let mentions = |needle: &str| strings.iter().any(|s| s.contains(needle));
+
+        if mentions("u-boot") || mentions("usb download gadget") {
+            Some(Bootloader::UBoot)
+        } else if mentions("tinyuf2") || mentions("uf2 bootloader") {
+            Some(Bootloader::TinyUf2)
+        } else if mentions("teensy") {
+            Some(Bootloader::Teensy)
+        } else if device_ids == (0x0483, 0xdf11) && dfuse != Some(false) {
+            Some(Bootloader::StDfuSe)
+        } else if mentions("dfu-util") || mentions("dfu reference") {
+            Some(Bootloader::Reference)
+        } else {
+            None
+        }
+    }
+
+    /// How to start the firmware once it was written, when a reset was asked for
+    ///
+    /// u-boot only leaves DFU mode after a DFU_DETACH; other bootloaders start the firmware on
+    /// a reset, which is also what [`flash_and_verify`](crate::flash_and_verify) does for
+    /// unrecognised devices.
+    pub fn finalize(self) -> Finalize {
+        match self {
+            Bootloader::UBoot => Finalize::DetachAndReset,
+            _ => Finalize::Reset,
+        }
+    }
+}
+
+impl DfuDeviceInfo {
+    /// Bootloader the device runs, if recognised without opening it, see [`Bootloader`]
+    pub fn bootloader(&self) -> Option<Bootloader> {
+        Bootloader::detect(
+            self.device_ids(),
+            self.info().manufacturer_string(),
+            self.product(),
+            None,
+        )
+    }
+}
+
+impl DfuNusb {
+    /// Bootloader the device runs, if recognised, see [`Bootloader`]
+    pub fn bootloader(&self) -> Option<Bootloader> {
+        Bootloader::detect(
+            self.device_ids?,
+            self.manufacturer.as_deref(),
+            self.product.as_deref(),
+            Some(self.is_dfuse()),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detect() {
+        for (ids, manufacturer, product, dfuse, expected) in [
+            (
+                (0x0483, 0xdf11),
+                Some("STMicroelectronics"),
+                Some("STM32  BOOTLOADER"),
+                Some(true),
+                Some(Bootloader::StDfuSe),
+            ),
+            (
+                (0x0483, 0xdf11),
+                None,
+                None,
+                None,
+                Some(Bootloader::StDfuSe),
+            ),
+            ((0x0483, 0xdf11), None, None, Some(false), None),
+            (
+                (0x0525, 0xa4a5),
+                Some("U-Boot"),
+                Some("USB download gadget"),
+                None,
+                Some(Bootloader::UBoot),
+            ),
+            (
+                (0x0483, 0xdf11),
+                None,
+                Some("USB download gadget"),
+                Some(true),
+                Some(Bootloader::UBoot),
+            ),
+            (
+                (0x239a, 0x0057),
+                Some("Adafruit"),
+                Some("TinyUF2 Bootloader"),
+                None,
+                Some(Bootloader::TinyUf2),
+            ),
+            (
+                (0x16c0, 0x0483),
+                Some("Teensyduino"),
+                Some("DFU"),
+                None,
+                Some(Bootloader::Teensy),
+            ),
+            // HalfKay, the stock Teensy bootloader, is a HID device
+            ((0x16c0, 0x0478), None, None, None, None),
+            (
+                (0x1d50, 0x6089),
+                Some("dfu-util"),
+                Some("DFU reference"),
+                Some(false),
+                Some(Bootloader::Reference),
+            ),
+            (
+                (0x1209, 0x2003),
+                Some("Acme"),
+                Some("Widget DFU"),
+                None,
+                None,
+            ),
+        ] {
+            assert_eq!(
+                Bootloader::detect(ids, manufacturer, product, dfuse),
+                expected,
+                "{ids:04x?} {manufacturer:?} {product:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn finalize() {
+        for (bootloader, expected) in [
+            (Bootloader::StDfuSe, Finalize::Reset),
+            (Bootloader::UBoot, Finalize::DetachAndReset),
+            (Bootloader::Teensy, Finalize::Reset),
+            (Bootloader::Reference, Finalize::Reset),
+            (Bootloader::TinyUf2, Finalize::Reset),
+        ] {
+            assert_eq!(bootloader.finalize(), expected, "{bootloader}");
+        }
+    }
+}
@@ -0,0 +1,84 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Bandwidth budget shared by several devices, e.g. all ports of a programming hub
+///
+/// Clones share the same budget, so attach a clone to every device flashed concurrently with
+/// [`DfuNusb::bandwidth_budget`](crate::DfuNusb::bandwidth_budget). Every firmware block
+/// downloaded or uploaded takes its size from a token bucket refilled at a fixed rate; blocks
+/// are submitted once their turn comes, so the devices share the bandwidth evenly rather
+/// than some of them starving into timeouts.
+#[derive(Debug, Clone)]
+pub struct BandwidthBudget {
+    rate: u32,
+    burst: u32,
+    bucket: Arc<Mutex<Bucket>>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    /// Available bytes; negative when blocks were promised bandwidth yet to come
+    tokens: f64,
+    /// Time of the last reservation, on the clock of the devices' [`Timer`](crate::Timer)
+    refilled: Option<Instant>,
+}
+
+impl BandwidthBudget {
+    /// Share `rate` bytes per second, allowing bursts of `burst` bytes
+    ///
+    /// The burst should be at least the transfer size of the devices, so a single block
+    /// doesn't always have to wait. A rate of 0 doesn't limit anything.
+    pub fn new(rate: u32, burst: u32) -> Self {
+        Self {
+            rate,
+            burst,
+            bucket: Arc::new(Mutex::new(Bucket {
+                tokens: f64::from(burst),
+                refilled: None,
+            })),
+        }
+    }
+
+    /// Bytes per second shared by all devices
+    pub fn rate(&self) -> u32 {
+        self.rate
+    }
+
+    /// Take `length` bytes from the budget at `now`, returning how long to wait before
+    /// submitting them
+    pub(crate) fn reserve(&self, length: usize, now: Instant) -> Duration {
+        if self.rate == 0 {
+            return Duration::ZERO;
+        }
+        let rate = f64::from(self.rate);
+        let mut bucket = self.bucket.lock().unwrap();
+        let elapsed = bucket.refilled.map_or(Duration::ZERO, |refilled| {
+            now.saturating_duration_since(refilled)
+        });
+        bucket.tokens = (bucket.tokens + elapsed.as_secs_f64() * rate).min(f64::from(self.burst));
+        bucket.refilled = Some(now);
+        bucket.tokens -= length as f64;
+        if bucket.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-bucket.tokens / rate)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn refill_follows_clock() {
+        let budget = BandwidthBudget::new(1000, 500);
+        let start = Instant::now();
+        assert_eq!(budget.reserve(500, start), Duration::ZERO);
+        assert_eq!(budget.reserve(250, start), Duration::from_millis(250));
+        // The clock is the caller's, however long the test actually took
+        let later = start + Duration::from_millis(750);
+        assert_eq!(budget.reserve(500, later), Duration::ZERO);
+        assert_eq!(budget.reserve(100, later), Duration::from_millis(100));
+    }
+}
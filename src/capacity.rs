@@ -0,0 +1,202 @@
+use nusb::transfer::TransferError;
+
+use crate::upload::{ensure_idle, DfuDevice};
+use crate::{info, warning, DfuNusb, Error, DFU_UPLOAD, REQUEST_TYPE_IN};
+
+/// Number of block numbers an upload can address
+const BLOCK_COUNT: u32 = 1 << 16;
+
+impl DfuNusb {
+    /// Returns the number of bytes the current target holds, if known
+    ///
+    /// For DfuSe targets this is the size of the memory layout from the download address on;
+    /// for plain DFU targets it is the capacity found by [`DfuNusb::probe_capacity`], if it was
+    /// called for the current alternative setting. Downloads larger than this fail with
+    /// [`Error::FirmwareTooLarge`] unless [`DfuNusb::force`] is set.
+    pub fn capacity(&self) -> Option<u64> {
+        match (self.memory_layout(), self.default_address(), self.address()) {
+            (Some(layout), Some(default), Some(address)) => {
+                let total: u64 = layout.iter().map(|&page| u64::from(page)).sum();
+                Some(total.saturating_sub(u64::from(address.saturating_sub(default))))
+            }
+            _ => *self.probed_capacity.lock().unwrap(),
+        }
+    }
+
+    /// Find the number of bytes a plain DFU target holds by uploading single blocks
+    ///
+    /// Plain DFU targets don't describe their memory, so this binary searches for the last
+    /// block number the device answers an upload with data for, aborting the upload after
+    /// each block. About 20 blocks are read, and at most 65536 blocks can be addressed, which
+    /// caps the result at 64 KiB times the transfer size. The device must serve uploads from
+    /// the block number of the request. Devices only accepting consecutive block numbers, or
+    /// counting blocks themselves, are detected by uploading block 1 on its own and comparing
+    /// it with block 1 uploaded after block 0, and fail with [`Error::CapacityProbeUnsupported`].
+    /// A device counting blocks itself isn't caught if blocks 0 and 1 hold the same data, e.g.
+    /// erased memory; a warning is logged then.
+    ///
+    /// The result is kept until the alternative setting changes, see [`DfuNusb::capacity`].
+    /// For DfuSe targets the size of the memory layout is returned without probing.
+    pub async fn probe_capacity(&self) -> Result<u64, Error> {
+        if let Some(capacity) = self.capacity() {
+            return Ok(capacity);
+        }
+        if !self.descriptor.can_upload && !self.force {
+            return Err(Error::UploadNotSupported);
+        }
+        let _operation = self.begin_operation()?;
+        let capacity = probe(self, usize::from(self.descriptor.transfer_size)).await?;
+        info!("Probed a capacity of {capacity} bytes");
+        *self.probed_capacity.lock().unwrap() = Some(capacity);
+        Ok(capacity)
+    }
+
+    /// Returns the number of bytes to upload from `address`: `length` if given, otherwise the
+    /// rest of the target's capacity, probed with [`DfuNusb::probe_capacity`] if needed
+    ///
+    /// Use it as the length of [`DfuNusb::upload`] and as the total of its progress, e.g. of a
+    /// [`ProgressGroup::member`](crate::ProgressGroup::member), when the caller gave no length.
+    pub async fn upload_length(&self, address: u32, length: Option<usize>) -> Result<usize, Error> {
+        match length {
+            Some(length) => Ok(length),
+            None => {
+                let capacity = self.probe_capacity().await?;
+                Ok(remaining(capacity, self.address().unwrap_or(0), address))
+            }
+        }
+    }
+}
+
+/// Bytes of a target holding `capacity` bytes from `start` on which are left from `address`
+fn remaining(capacity: u64, start: u32, address: u32) -> usize {
+    let left = capacity.saturating_sub(u64::from(address.saturating_sub(start)));
+    usize::try_from(left).unwrap_or(usize::MAX)
+}
+
+/// Find the capacity of a plain DFU target, see [`DfuNusb::probe_capacity`]
+async fn probe<D: DfuDevice>(device: &D, transfer_size: usize) -> Result<u64, Error> {
+    let mut first = vec![0; transfer_size];
+    let mut consecutive = vec![0; transfer_size];
+    let mut buffer = vec![0; transfer_size];
+
+    // Last block known to hold data and its length
+    ensure_idle(device).await?;
+    let (mut good, mut length) = (0, upload_block(device, 0, &mut first).await?);
+    if length == transfer_size {
+        // Block 1 read in the same upload as block 0, which every device supports
+        let n = upload_block(device, 1, &mut consecutive).await?;
+        ensure_idle(device).await?;
+        let alone = probe_block(device, 1, &mut buffer).await?;
+        if buffer[..alone] != consecutive[..n] {
+            return Err(Error::CapacityProbeUnsupported);
+        }
+        if alone == transfer_size && buffer == first {
+            warning!("Blocks 0 and 1 hold the same data, the probed capacity may be wrong");
+        }
+        if alone > 0 {
+            (good, length) = (1, alone);
+        }
+
+        // First block known to be past the end
+        let mut bad = if alone == 0 { 1 } else { BLOCK_COUNT };
+        while length == transfer_size && bad - good > 1 {
+            let block = good + (bad - good) / 2;
+            match probe_block(device, block as u16, &mut buffer).await? {
+                0 => bad = block,
+                n => (good, length) = (block, n),
+            }
+        }
+    }
+    ensure_idle(device).await?;
+
+    Ok(u64::from(good) * transfer_size as u64 + length as u64)
+}
+
+/// Upload block number `block` on its own, returning the number of bytes the device answered
+/// with, or 0 if it refused the request
+async fn probe_block<D: DfuDevice>(
+    device: &D,
+    block: u16,
+    buffer: &mut [u8],
+) -> Result<usize, Error> {
+    let n = upload_block(device, block, buffer).await?;
+    // A full block leaves the device in dfuUPLOAD-IDLE, a refused one in dfuERROR
+    ensure_idle(device).await?;
+    Ok(n)
+}
+
+/// Send a single DFU_UPLOAD request, returning 0 if the device refused it
+async fn upload_block<D: DfuDevice>(
+    device: &D,
+    block: u16,
+    buffer: &mut [u8],
+) -> Result<usize, Error> {
+    match device
+        .read_control(REQUEST_TYPE_IN, DFU_UPLOAD, block, buffer)
+        .await
+    {
+        Ok(n) => Ok(n),
+        Err(Error::Transfer(TransferError::Stall))
+        | Err(Error::Dfu(dfu_core::Error::StatusError(_))) => Ok(0),
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::executor::block_on;
+
+    use super::*;
+    use crate::fake::FakeDevice;
+
+    fn memory(length: usize) -> Vec<u8> {
+        (0..length).map(|i| (i % 251) as u8).collect()
+    }
+
+    #[test]
+    fn capacity() {
+        for length in [10, 64, 65, 1000, 1024, 64 * 1000 + 1] {
+            let device = FakeDevice::dfu(memory(length), 64);
+            assert_eq!(block_on(probe(&device, 64)).unwrap(), length as u64);
+        }
+    }
+
+    #[test]
+    fn upload_total() {
+        let device = FakeDevice::dfu(memory(1000), 64);
+        let capacity = block_on(probe(&device, 64)).unwrap();
+        assert_eq!(remaining(capacity, 0, 0), 1000);
+        assert_eq!(remaining(capacity, 0, 600), 400);
+        assert_eq!(remaining(capacity, 0, 2000), 0);
+        // DfuSe capacities count from the download address
+        assert_eq!(remaining(4096, 0x0800_0000, 0x0800_0400), 3072);
+    }
+
+    #[test]
+    fn consecutive_only() {
+        let device = FakeDevice::dfu(memory(1000), 64).consecutive_only();
+        assert!(matches!(
+            block_on(probe(&device, 64)),
+            Err(Error::CapacityProbeUnsupported)
+        ));
+    }
+
+    #[test]
+    fn counting_blocks() {
+        let device = FakeDevice::dfu(memory(1000), 64).counts_blocks();
+        assert!(matches!(
+            block_on(probe(&device, 64)),
+            Err(Error::CapacityProbeUnsupported)
+        ));
+    }
+
+    #[test]
+    fn counting_blocks_short() {
+        // Block 1 is short, the device answers the lone request with the full block 0
+        let device = FakeDevice::dfu(memory(100), 64).counts_blocks();
+        assert!(matches!(
+            block_on(probe(&device, 64)),
+            Err(Error::CapacityProbeUnsupported)
+        ));
+    }
+}
@@ -0,0 +1,60 @@
+use nusb::transfer::{Control, ControlType, Recipient};
+
+use crate::{DfuNusb, Error, DFU_ABORT};
+
+/// Clean-up done when a [`DfuNusb`] is closed or dropped
+///
+/// The interface is always released. By default nothing else is done, so the device stays in
+/// whatever state the last operation left it in.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ClosePolicy {
+    /// Send DFU_ABORT to bring the device back to the idle state
+    pub abort: bool,
+    /// Switch the interface back to alternative setting 0
+    pub reset_alt_setting: bool,
+}
+
+impl DfuNusb {
+    /// Set the clean-up done when the device is closed or dropped
+    pub fn close_policy(&mut self, policy: ClosePolicy) -> &mut Self {
+        self.close_policy = policy;
+        self
+    }
+
+    /// Close the device, applying the [`ClosePolicy`] and releasing the interface
+    ///
+    /// Unlike dropping the device this reports errors of the clean-up.
+    pub fn close(mut self) -> Result<(), Error> {
+        let policy = std::mem::take(&mut self.close_policy);
+        self.apply_close_policy(policy)
+    }
+
+    fn apply_close_policy(&self, policy: ClosePolicy) -> Result<(), Error> {
+        // A device which detached after manifestation isn't there anymore
+        if self.detached_during_manifest() {
+            return Ok(());
+        }
+        if policy.abort {
+            let control = Control {
+                control_type: ControlType::Class,
+                recipient: Recipient::Interface,
+                request: DFU_ABORT,
+                value: 0,
+                index: self.request_index(),
+            };
+            self.interface
+                .control_out_blocking(control, &[], self.timeouts.control)?;
+        }
+        if policy.reset_alt_setting {
+            self.interface.set_alt_setting(0)?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for DfuNusb {
+    fn drop(&mut self) {
+        let policy = std::mem::take(&mut self.close_policy);
+        let _ = self.apply_close_policy(policy);
+    }
+}
@@ -0,0 +1,46 @@
+use futures::{AsyncWrite, AsyncWriteExt};
+
+use crate::{DfuNusb, Error};
+
+/// Compression applied to uploaded data by [`DfuNusb::upload_compressed`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    /// gzip, readable with `gunzip`
+    #[cfg(feature = "gzip")]
+    Gzip,
+    /// Zstandard, readable with `zstd -d`
+    #[cfg(feature = "zstd")]
+    Zstd,
+}
+
+impl DfuNusb {
+    /// Upload `length` bytes of memory starting at `address`, compressing them into `writer`
+    ///
+    /// Blocks are compressed as they are read, so backups of large memories never have to fit
+    /// in memory or in a temporary file. `writer` is closed once the compressed stream is
+    /// complete. Returns the number of uncompressed bytes read from the device.
+    pub async fn upload_compressed<W: AsyncWrite + Unpin>(
+        &self,
+        address: u32,
+        length: usize,
+        writer: W,
+        compression: Compression,
+    ) -> Result<usize, Error> {
+        match compression {
+            #[cfg(feature = "gzip")]
+            Compression::Gzip => {
+                let mut encoder = async_compression::futures::write::GzipEncoder::new(writer);
+                let n = self.upload(address, length, &mut encoder).await?;
+                encoder.close().await.map_err(Error::Io)?;
+                Ok(n)
+            }
+            #[cfg(feature = "zstd")]
+            Compression::Zstd => {
+                let mut encoder = async_compression::futures::write::ZstdEncoder::new(writer);
+                let n = self.upload(address, length, &mut encoder).await?;
+                encoder.close().await.map_err(Error::Io)?;
+                Ok(n)
+            }
+        }
+    }
+}
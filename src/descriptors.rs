@@ -0,0 +1,47 @@
+use crate::DfuNusb;
+
+const DESCRIPTOR_TYPE_DFU_FUNCTIONAL: u8 = 0x21;
+
+/// Raw descriptors of one alternative setting of the DFU interface
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RawAltSetting {
+    /// Number of the alternative setting
+    pub alt: u8,
+    /// Interface descriptor, followed by all descriptors up to the next interface descriptor
+    pub bytes: Vec<u8>,
+}
+
+/// Descriptors of the DFU function exactly as advertised by the device
+///
+/// Meant for diagnostics, e.g. to hexdump what a misbehaving bootloader reports when filing a
+/// quirk request; the parsed values are available through [`DfuNusb`] itself.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RawDescriptors {
+    /// All alternative settings of the DFU interface
+    pub alt_settings: Vec<RawAltSetting>,
+    /// DFU functional descriptor, if any alternative setting carries one
+    pub functional: Option<Vec<u8>>,
+}
+
+impl DfuNusb {
+    /// Raw descriptors of the opened DFU interface
+    pub fn raw_descriptors(&self) -> RawDescriptors {
+        let alt_settings: Vec<_> = self
+            .interface
+            .descriptors()
+            .map(|alt| RawAltSetting {
+                alt: alt.alternate_setting(),
+                bytes: alt.descriptors().as_bytes().to_vec(),
+            })
+            .collect();
+        let functional = self.interface.descriptors().find_map(|alt| {
+            alt.descriptors()
+                .find(|d| d.descriptor_type() == DESCRIPTOR_TYPE_DFU_FUNCTIONAL)
+                .map(|d| d.to_vec())
+        });
+        RawDescriptors {
+            alt_settings,
+            functional,
+        }
+    }
+}
@@ -0,0 +1,178 @@
+use std::fmt;
+use std::str::FromStr;
+
+use crate::Error;
+
+/// DfuSe options, as given to the `-s`/`--dfuse-address` option of dfu-util
+///
+/// The textual form is `address[:modifier...]`, e.g. `0x08000000:leave:force`. The address
+/// may be omitted (`:mass-erase:force`) to use the default address of the target. A plain
+/// number modifier sets the length to transfer.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DfuseOptions {
+    /// Start address, overriding the one of the target
+    pub address: Option<u32>,
+    /// Number of bytes to transfer
+    pub length: Option<u32>,
+    /// Leave DFU mode after the operation (`leave`)
+    pub leave: bool,
+    /// Skip safety checks (`force`)
+    pub force: bool,
+    /// Erase the whole flash before the operation (`mass-erase`)
+    pub mass_erase: bool,
+    /// Remove the read protection of the flash (`unprotect`)
+    pub unprotect: bool,
+    /// The device resets itself after the operation (`will-reset`)
+    pub will_reset: bool,
+}
+
+fn parse_number(s: &str) -> Option<u32> {
+    match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => u32::from_str_radix(hex, 16).ok(),
+        None => s.parse().ok(),
+    }
+}
+
+impl FromStr for DfuseOptions {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.split(':');
+        let mut options = DfuseOptions::default();
+
+        let address = parts.next().unwrap_or_default();
+        if !address.is_empty() {
+            options.address =
+                Some(parse_number(address).ok_or_else(|| {
+                    Error::InvalidDfuseOptions(format!("invalid address {address}"))
+                })?);
+        }
+
+        for modifier in parts {
+            match modifier {
+                "leave" => options.leave = true,
+                "force" => options.force = true,
+                "mass-erase" => options.mass_erase = true,
+                "unprotect" => options.unprotect = true,
+                "will-reset" => options.will_reset = true,
+                _ => match parse_number(modifier) {
+                    Some(length) => options.length = Some(length),
+                    None => {
+                        return Err(Error::InvalidDfuseOptions(format!(
+                            "unknown modifier {modifier}"
+                        )))
+                    }
+                },
+            }
+        }
+
+        Ok(options)
+    }
+}
+
+impl fmt::Display for DfuseOptions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(address) = self.address {
+            write!(f, "{address:#010x}")?;
+        }
+        for (set, modifier) in [
+            (self.leave, "leave"),
+            (self.force, "force"),
+            (self.mass_erase, "mass-erase"),
+            (self.unprotect, "unprotect"),
+            (self.will_reset, "will-reset"),
+        ] {
+            if set {
+                write!(f, ":{modifier}")?;
+            }
+        }
+        if let Some(length) = self.length {
+            write!(f, ":{length}")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        let options = |address, length| DfuseOptions {
+            address,
+            length,
+            ..Default::default()
+        };
+        for (s, expected) in [
+            ("", options(None, None)),
+            ("0x08000000", options(Some(0x0800_0000), None)),
+            ("0X0800C000", options(Some(0x0800_c000), None)),
+            ("134217728", options(Some(0x0800_0000), None)),
+            ("0x08000000:1024", options(Some(0x0800_0000), Some(1024))),
+            ("0x08000000:0x400", options(Some(0x0800_0000), Some(1024))),
+            (
+                "0x08000000:leave:force",
+                DfuseOptions {
+                    leave: true,
+                    force: true,
+                    ..options(Some(0x0800_0000), None)
+                },
+            ),
+            (
+                ":mass-erase:force",
+                DfuseOptions {
+                    mass_erase: true,
+                    force: true,
+                    ..options(None, None)
+                },
+            ),
+            (
+                ":unprotect:will-reset",
+                DfuseOptions {
+                    unprotect: true,
+                    will_reset: true,
+                    ..options(None, None)
+                },
+            ),
+        ] {
+            assert_eq!(s.parse::<DfuseOptions>().unwrap(), expected, "{s:?}");
+        }
+    }
+
+    #[test]
+    fn parse_invalid() {
+        for s in [
+            "0x",
+            "0xg0000000",
+            "flash",
+            "0x08000000:leav",
+            "0x08000000:-1",
+            ":",
+        ] {
+            assert!(
+                matches!(
+                    s.parse::<DfuseOptions>(),
+                    Err(Error::InvalidDfuseOptions(_))
+                ),
+                "{s:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn display_round_trip() {
+        for s in [
+            "0x08000000:leave:force",
+            ":force:mass-erase",
+            "0x0800c000:1024",
+        ] {
+            let options = s.parse::<DfuseOptions>().unwrap();
+            assert_eq!(options.to_string(), s);
+            assert_eq!(
+                options.to_string().parse::<DfuseOptions>().unwrap(),
+                options
+            );
+        }
+    }
+}
@@ -0,0 +1,613 @@
+use std::time::{Duration, Instant};
+
+use dfu_core::functional_descriptor::FunctionalDescriptor;
+
+use crate::{read_string, DeviceIdentity, DfuNusb, Error, OpenOptions, Timeouts, Timer};
+
+const DFU_CLASS: u8 = 0xfe;
+const DFU_SUBCLASS: u8 = 0x01;
+const DFU_PROTOCOL_RUNTIME: u8 = 0x01;
+const DFU_PROTOCOL_DFU: u8 = 0x02;
+/// Device interface class of USB devices on Windows
+#[cfg(target_os = "windows")]
+const GUID_DEVINTERFACE_USB_DEVICE: &str = "{a5dcbf10-6530-11d2-901f-00c04fb951ed}";
+
+/// Mode a DFU interface is in, from its interface protocol
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DfuMode {
+    /// The application is running and only offers DFU_DETACH (protocol 1)
+    Runtime,
+    /// The bootloader is running and accepts firmware (protocol 2)
+    Dfu,
+}
+
+impl DfuMode {
+    /// Mode of an interface with the given protocol, if it is a known DFU protocol
+    pub fn from_protocol(protocol: u8) -> Option<Self> {
+        match protocol {
+            DFU_PROTOCOL_RUNTIME => Some(DfuMode::Runtime),
+            DFU_PROTOCOL_DFU => Some(DfuMode::Dfu),
+            _ => None,
+        }
+    }
+
+    /// Returns whether the device has to be detached into DFU mode before flashing
+    pub fn requires_detach(self) -> bool {
+        self == DfuMode::Runtime
+    }
+}
+
+/// Criteria used to select DFU devices
+#[derive(Debug, Clone, Default)]
+pub struct DeviceFilter {
+    vendor_id: Option<u16>,
+    product_id: Option<u16>,
+    serial: Option<String>,
+    product: Option<String>,
+    bus_device: Option<(u8, u8)>,
+    path: Option<String>,
+    mode: Option<DfuMode>,
+    reenumerated_ids: Option<(u16, u16)>,
+}
+
+impl DeviceFilter {
+    /// Create a filter matching every DFU device
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Only match devices with the given vendor and product id
+    pub fn vid_pid(mut self, vendor_id: u16, product_id: u16) -> Self {
+        self.vendor_id = Some(vendor_id);
+        self.product_id = Some(product_id);
+        self
+    }
+
+    /// Declare the vendor and product id the device takes after a detach or reset
+    ///
+    /// Applications often use other ids in runtime mode than their bootloader, e.g.
+    /// `1d50:6089` detaching into `0483:df11`. Devices with these ids match as well, and the
+    /// [`DeviceIdentity`](crate::DeviceIdentity) of a matched device only accepts a device
+    /// with its own or these ids on its port when re-enumerating; anything else fails with
+    /// [`Error::UnexpectedDevice`].
+    pub fn reenumerates_as(mut self, vendor_id: u16, product_id: u16) -> Self {
+        self.reenumerated_ids = Some((vendor_id, product_id));
+        self
+    }
+
+    /// Only match devices with the given vendor id
+    pub fn vendor_id(mut self, vendor_id: u16) -> Self {
+        self.vendor_id = Some(vendor_id);
+        self
+    }
+
+    /// Only match devices with the given product id
+    pub fn product_id(mut self, product_id: u16) -> Self {
+        self.product_id = Some(product_id);
+        self
+    }
+
+    /// Only match devices whose serial number matches the glob `pattern`
+    ///
+    /// `*` matches any sequence of characters and `?` matches a single character, e.g.
+    /// `PROTO-*`.
+    pub fn serial(mut self, pattern: impl Into<String>) -> Self {
+        self.serial = Some(pattern.into());
+        self
+    }
+
+    /// Only match devices whose product string matches the glob `pattern`
+    ///
+    /// See [`DeviceFilter::serial`] for the supported syntax.
+    pub fn product(mut self, pattern: impl Into<String>) -> Self {
+        self.product = Some(pattern.into());
+        self
+    }
+
+    /// Only match the device with the given bus number and device address, as shown by
+    /// `lsusb` (e.g. `003:012`)
+    pub fn bus_device(mut self, bus: u8, address: u8) -> Self {
+        self.bus_device = Some((bus, address));
+        self
+    }
+
+    /// Only match the device with the given platform specific path
+    ///
+    /// See [`OpenOptions::open_by_path`] for the accepted paths.
+    pub fn path(mut self, path: impl Into<String>) -> Self {
+        self.path = Some(path.into());
+        self
+    }
+
+    /// Only match devices with a DFU interface in the given mode
+    ///
+    /// Devices whose interfaces can't be enumerated without opening them (e.g. non-composite
+    /// devices on Windows) can't be excluded and always match.
+    pub fn mode(mut self, mode: DfuMode) -> Self {
+        self.mode = Some(mode);
+        self
+    }
+
+    /// Check whether the device matches this filter
+    pub fn matches(&self, info: &nusb::DeviceInfo) -> bool {
+        fn matches_string(pattern: &Option<String>, value: Option<&str>) -> bool {
+            match (pattern, value) {
+                (None, _) => true,
+                (Some(pattern), Some(value)) => glob_match(pattern, value),
+                (Some(_), None) => false,
+            }
+        }
+
+        let ids = (info.vendor_id(), info.product_id());
+        (self.reenumerated_ids == Some(ids)
+            || self.vendor_id.map_or(true, |vid| info.vendor_id() == vid)
+                && self.product_id.map_or(true, |pid| info.product_id() == pid))
+            && matches_string(&self.serial, info.serial_number())
+            && matches_string(&self.product, info.product_string())
+            && self.bus_device.map_or(true, |bus_device| {
+                (info.bus_number(), info.device_address()) == bus_device
+            })
+            && self
+                .path
+                .as_ref()
+                .map_or(true, |path| matches_path(info, path))
+            && self.mode.map_or(true, |mode| {
+                info.interfaces().next().is_none() || modes(info).any(|m| m == mode)
+            })
+    }
+}
+
+/// Match `value` against a glob `pattern` supporting `*` and `?`
+fn glob_match(pattern: &str, value: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let value: Vec<char> = value.chars().collect();
+    let (mut p, mut v) = (0, 0);
+    // Position of the last `*` in the pattern and the value position it was tried at
+    let mut backtrack = None;
+
+    while v < value.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, v));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == value[v] => {
+                p += 1;
+                v += 1;
+            }
+            _ => match backtrack {
+                Some((star, tried)) => {
+                    p = star + 1;
+                    v = tried + 1;
+                    backtrack = Some((star, tried + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// A device exposing a DFU interface
+#[derive(Debug, Clone)]
+pub struct DfuDeviceInfo {
+    info: nusb::DeviceInfo,
+    /// Ids the device may take after a re-enumeration, see [`DeviceFilter::reenumerates_as`]
+    pub(crate) expected_ids: Option<(u16, u16)>,
+    /// Ids the device had before it re-enumerated with other ones
+    pub(crate) previous_ids: Option<(u16, u16)>,
+}
+
+impl DfuDeviceInfo {
+    /// Enumeration information of the device
+    pub fn info(&self) -> &nusb::DeviceInfo {
+        &self.info
+    }
+
+    /// Product string of the device, if any
+    pub fn product(&self) -> Option<&str> {
+        self.info.product_string()
+    }
+
+    /// Serial number of the device, if any
+    pub fn serial(&self) -> Option<&str> {
+        self.info.serial_number()
+    }
+
+    /// Platform specific description of the physical port the device is connected to
+    pub fn port(&self) -> String {
+        port(&self.info)
+    }
+
+    /// Check whether the device is the one at the platform specific `path`
+    ///
+    /// See [`OpenOptions::open_by_path`] for the accepted paths.
+    pub fn matches_path(&self, path: &str) -> bool {
+        matches_path(&self.info, path)
+    }
+
+    /// Mode of the DFU interfaces of the device, if known without opening it
+    ///
+    /// Returns `None` when the interfaces can't be enumerated (e.g. non-composite devices on
+    /// Windows); [`DfuFunction::mode`] reads it from the opened device instead. A
+    /// device with interfaces in both modes is reported in [`DfuMode::Dfu`].
+    pub fn mode(&self) -> Option<DfuMode> {
+        modes(&self.info).max_by_key(|&mode| mode == DfuMode::Dfu)
+    }
+
+    /// Name of the driver the DFU interface is bound to, if known
+    ///
+    /// On Linux this is the kernel driver of the first DFU interface (e.g. `usbfs` while opened
+    /// by this crate, or a vendor driver preventing access). On Windows it is the driver of the
+    /// device as a whole, e.g. `WinUSB` or `usbccgp` for composite devices whose functions
+    /// have drivers of their own.
+    pub fn driver(&self) -> Option<String> {
+        driver(&self.info)
+    }
+
+    /// Device interface path of the device, as used by `SetupDi`/`CM_*` APIs and WinUSB
+    ///
+    /// Such paths are accepted by [`DeviceFilter::path`] and [`OpenOptions::open_by_path`].
+    #[cfg(target_os = "windows")]
+    pub fn interface_path(&self) -> String {
+        format!(
+            "\\\\?\\{}#{GUID_DEVINTERFACE_USB_DEVICE}",
+            self.info.instance_id().to_string_lossy().replace('\\', "#")
+        )
+    }
+
+    /// Vendor and product id of the device
+    pub fn device_ids(&self) -> (u16, u16) {
+        (self.info.vendor_id(), self.info.product_id())
+    }
+
+    /// Vendor and product id the device had before it re-enumerated with different ones, when
+    /// it was found again with [`DeviceIdentity::find`]
+    pub fn previous_ids(&self) -> Option<(u16, u16)> {
+        self.previous_ids
+    }
+
+    /// Physical identity of the device, to find it again after it re-enumerated
+    pub fn identity(&self) -> DeviceIdentity {
+        DeviceIdentity::of(self)
+    }
+
+    /// Open the device and its DFU interface using the given alternative setting
+    ///
+    /// See [`OpenOptions`] for more control over how the device is opened.
+    pub fn open(&self, interface: u8, alt: u8) -> Result<DfuNusb, Error> {
+        OpenOptions::new().interface(interface).alt(alt).open(self)
+    }
+
+    /// Describe the DFU functions of the device in the format of `dfu-util --list`
+    ///
+    /// Returns one `Found DFU: [vid:pid] ver=…, devnum=…, cfg=…, intf=…, path="…", alt=…,
+    /// name="…", serial="…"` line per alternative setting, with `Found Runtime` for interfaces
+    /// in runtime mode, so scripts parsing dfu-util output keep working. The names of the
+    /// alternative settings are read within the control timeout of `timeouts`.
+    pub fn dfu_util_list(&self, timeouts: &Timeouts) -> Result<Vec<String>, Error> {
+        let device = self.info.open()?;
+        let mut lines = Vec::new();
+        for function in scan_functions(&device, timeouts)? {
+            let mode = match function.mode() {
+                Some(DfuMode::Runtime) => "Runtime",
+                _ => "DFU",
+            };
+            for alt in &function.alt_settings {
+                lines.push(format!(
+                    "Found {mode}: [{:04x}:{:04x}] ver={:04x}, devnum={}, cfg={}, intf={}, \
+                    path=\"{}\", alt={}, name=\"{}\", serial=\"{}\"",
+                    self.info.vendor_id(),
+                    self.info.product_id(),
+                    self.info.device_version(),
+                    self.info.device_address(),
+                    function.configuration,
+                    function.interface,
+                    self.port(),
+                    alt.alt,
+                    alt.name.as_deref().unwrap_or("UNKNOWN"),
+                    self.serial().unwrap_or("UNKNOWN"),
+                ));
+            }
+        }
+        Ok(lines)
+    }
+}
+
+/// Platform specific description of the physical port the device is connected to
+fn port(info: &nusb::DeviceInfo) -> String {
+    #[cfg(target_os = "linux")]
+    {
+        info.sysfs_path()
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default()
+    }
+    #[cfg(target_os = "macos")]
+    {
+        format!("{:#010x}", info.location_id())
+    }
+    #[cfg(target_os = "windows")]
+    {
+        format!(
+            "{}#{}",
+            info.parent_instance_id().to_string_lossy(),
+            info.port_number()
+        )
+    }
+    #[cfg(not(any(target_os = "linux", target_os = "macos", target_os = "windows")))]
+    {
+        format!("{:03}:{:03}", info.bus_number(), info.device_address())
+    }
+}
+
+/// Check whether the device is the one at the platform specific `path`
+fn matches_path(info: &nusb::DeviceInfo, path: &str) -> bool {
+    #[cfg(target_os = "linux")]
+    {
+        // udev hands out paths relative to /sys, compare the last component only
+        let name = std::path::Path::new(path).file_name();
+        name.is_some() && name == info.sysfs_path().file_name()
+    }
+    #[cfg(target_os = "macos")]
+    {
+        let location = match path.strip_prefix("0x") {
+            Some(hex) => u32::from_str_radix(hex, 16).ok(),
+            None => path.parse().ok(),
+        };
+        location == Some(info.location_id())
+    }
+    #[cfg(target_os = "windows")]
+    {
+        // Device interface paths are the instance ID with `#` separators, a `\\?\` prefix and
+        // the interface class GUID appended
+        let instance_id = match path.strip_prefix("\\\\?\\") {
+            Some(interface_path) => interface_path
+                .rsplit_once('#')
+                .map_or(interface_path, |(id, _guid)| id)
+                .replace('#', "\\"),
+            None => path.to_string(),
+        };
+        info.instance_id()
+            .to_string_lossy()
+            .eq_ignore_ascii_case(&instance_id)
+    }
+    #[cfg(not(any(target_os = "linux", target_os = "macos", target_os = "windows")))]
+    {
+        path == port(info)
+    }
+}
+
+/// Modes of the DFU interfaces listed in the enumeration information
+fn modes(info: &nusb::DeviceInfo) -> impl Iterator<Item = DfuMode> + '_ {
+    info.interfaces()
+        .filter(|i| i.class() == DFU_CLASS && i.subclass() == DFU_SUBCLASS)
+        .filter_map(|i| DfuMode::from_protocol(i.protocol()))
+}
+
+/// Driver bound to the DFU interface, or to the whole device where that's all that is known
+fn driver(info: &nusb::DeviceInfo) -> Option<String> {
+    #[cfg(target_os = "linux")]
+    {
+        let number = info
+            .interfaces()
+            .find(|i| i.class() == DFU_CLASS && i.subclass() == DFU_SUBCLASS)?
+            .interface_number();
+        let sysfs = info.sysfs_path();
+        let prefix = format!("{}:", sysfs.file_name()?.to_string_lossy());
+        let suffix = format!(".{number}");
+        // Interface directories are named `<port>:<configuration>.<interface>`
+        let interface = std::fs::read_dir(sysfs).ok()?.flatten().find(|entry| {
+            let name = entry.file_name();
+            let name = name.to_string_lossy();
+            name.starts_with(&prefix) && name.ends_with(&suffix)
+        })?;
+        let driver = std::fs::read_link(interface.path().join("driver")).ok()?;
+        Some(driver.file_name()?.to_string_lossy().into_owned())
+    }
+    #[cfg(target_os = "windows")]
+    {
+        info.driver().map(str::to_string)
+    }
+    #[cfg(not(any(target_os = "linux", target_os = "windows")))]
+    {
+        let _ = info;
+        None
+    }
+}
+
+fn has_dfu_interface(info: &nusb::DeviceInfo) -> bool {
+    // Interface information isn't available on all platforms (e.g. Windows for non-composite
+    // devices), in which case the device can't be excluded up front.
+    info.interfaces().next().is_none()
+        || info
+            .interfaces()
+            .any(|i| i.class() == DFU_CLASS && i.subclass() == DFU_SUBCLASS)
+}
+
+/// List the DFU devices matching the filter
+pub fn list_devices(filter: &DeviceFilter) -> Result<Vec<DfuDeviceInfo>, Error> {
+    Ok(nusb::list_devices()?
+        .filter(|info| filter.matches(info) && has_dfu_interface(info))
+        .map(|info| DfuDeviceInfo {
+            info,
+            expected_ids: filter.reenumerated_ids,
+            previous_ids: None,
+        })
+        .collect())
+}
+
+/// Wait until a DFU device matching the filter is connected, checking every `poll_interval`
+///
+/// Sleeps with `timer`, so this doesn't block the executor, like the replug waits of
+/// [`flash_and_verify`](crate::flash_and_verify); see [`wait_for_device_blocking`] for code
+/// without an async runtime. Fails with [`Error::DeviceNotFound`] if no
+/// device shows up within `timeout`; pass [`Duration::MAX`] to wait forever. The first matching
+/// device is returned if several match.
+pub async fn wait_for_device(
+    filter: &DeviceFilter,
+    poll_interval: Duration,
+    timeout: Duration,
+    timer: &dyn Timer,
+) -> Result<DfuDeviceInfo, Error> {
+    let start = timer.now();
+    loop {
+        if let Some(device) = list_devices(filter)?.into_iter().next() {
+            return Ok(device);
+        }
+        let elapsed = timer.now().saturating_duration_since(start);
+        if elapsed >= timeout {
+            return Err(Error::DeviceNotFound);
+        }
+        timer.sleep(poll_interval.min(timeout - elapsed)).await;
+    }
+}
+
+/// Wait until a DFU device matching the filter is connected, checking every `poll_interval`
+///
+/// This blocks the calling thread, for programs without an async runtime; use
+/// [`wait_for_device`] from async code. Fails with [`Error::DeviceNotFound`] if no device shows
+/// up within `timeout`; pass [`Duration::MAX`] to wait forever. The first matching device is
+/// returned if several match.
+pub fn wait_for_device_blocking(
+    filter: &DeviceFilter,
+    poll_interval: Duration,
+    timeout: Duration,
+) -> Result<DfuDeviceInfo, Error> {
+    let start = Instant::now();
+    loop {
+        if let Some(device) = list_devices(filter)?.into_iter().next() {
+            return Ok(device);
+        }
+        let elapsed = start.elapsed();
+        if elapsed >= timeout {
+            return Err(Error::DeviceNotFound);
+        }
+        std::thread::sleep(poll_interval.min(timeout - elapsed));
+    }
+}
+
+/// Alternative setting of a DFU function
+#[derive(Debug, Clone)]
+pub struct DfuAltSetting {
+    /// Number of the alternative setting
+    pub alt: u8,
+    /// Name of the alternative setting; describes the memory layout for DfuSe targets
+    pub name: Option<String>,
+}
+
+/// A DFU function exposed by a device
+#[derive(Debug, Clone)]
+pub struct DfuFunction {
+    /// Value of the configuration the interface belongs to
+    pub configuration: u8,
+    /// Number of the interface
+    pub interface: u8,
+    /// Interface protocol; 1 for runtime mode, 2 for DFU mode
+    pub protocol: u8,
+    /// Functional descriptor of the interface
+    pub descriptor: FunctionalDescriptor,
+    /// Alternative settings of the interface
+    pub alt_settings: Vec<DfuAltSetting>,
+}
+
+impl DfuFunction {
+    /// Mode of the interface, if its protocol is a known DFU protocol
+    pub fn mode(&self) -> Option<DfuMode> {
+        DfuMode::from_protocol(self.protocol)
+    }
+}
+
+/// Find all DFU functions of an opened device
+///
+/// Composite devices may expose more than one DFU interface; this returns all of them so the
+/// caller can pick the one to open. The names of the alternative settings are read within the
+/// control timeout of `timeouts`.
+pub fn scan_functions(
+    device: &nusb::Device,
+    timeouts: &Timeouts,
+) -> Result<Vec<DfuFunction>, Error> {
+    // Unconfigured devices don't have an active configuration, fall back to the first one
+    let configuration = match device.active_configuration() {
+        Ok(configuration) => configuration,
+        Err(_) => device
+            .configurations()
+            .next()
+            .ok_or(Error::ConfigurationNotFound(0))?,
+    };
+
+    let mut functions = Vec::new();
+    for group in configuration.interfaces() {
+        let alts: Vec<_> = group
+            .alt_settings()
+            .filter(|alt| alt.class() == DFU_CLASS && alt.subclass() == DFU_SUBCLASS)
+            .collect();
+        // The functional descriptor is commonly only attached to one of the alternative settings
+        let Some(descriptor) = alts
+            .iter()
+            .find_map(|alt| {
+                alt.descriptors()
+                    .find_map(|d| FunctionalDescriptor::from_bytes(&d))
+            })
+            .transpose()?
+        else {
+            continue;
+        };
+
+        let mut alt_settings = Vec::new();
+        for alt in &alts {
+            let name = match alt.string_index() {
+                Some(index) => Some(read_string(device, index, timeouts.control)?),
+                None => None,
+            };
+            alt_settings.push(DfuAltSetting {
+                alt: alt.alternate_setting(),
+                name,
+            });
+        }
+
+        functions.push(DfuFunction {
+            configuration: configuration.configuration_value(),
+            interface: group.interface_number(),
+            protocol: alts[0].protocol(),
+            descriptor,
+            alt_settings,
+        });
+    }
+
+    Ok(functions)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn glob() {
+        for (pattern, value, expected) in [
+            ("", "", true),
+            ("", "a", false),
+            ("*", "", true),
+            ("*", "anything", true),
+            ("STM32*", "STM32 BOOTLOADER", true),
+            ("STM32*", "GD32 BOOTLOADER", false),
+            ("*BOOT*", "STM32 BOOTLOADER", true),
+            ("*LOADER", "STM32 BOOTLOADER", true),
+            ("*LOADER", "STM32 BOOTLOADERS", false),
+            ("1-1.?", "1-1.4", true),
+            ("1-1.?", "1-1.14", false),
+            ("1-*.2", "1-1.4.2", true),
+            ("a*b*c", "aXbYbZc", true),
+            ("a*b*c", "aXbYbZ", false),
+            ("a**", "a", true),
+            ("?", "é", true),
+        ] {
+            assert_eq!(
+                glob_match(pattern, value),
+                expected,
+                "{pattern:?} against {value:?}"
+            );
+        }
+    }
+}
@@ -0,0 +1,517 @@
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+
+use dfu_core::{asynchronous::DfuAsyncIo, download, get_status, ChainedCommand, DfuSansIo};
+use futures::AsyncRead;
+
+use crate::file::{crc32, crc32_update};
+use crate::padding::page_start;
+use crate::session::JsonFields;
+use crate::upload::{device_crc, dfuse_command};
+use crate::{
+    DfuNusb, Error, FirmwareSource, HookPoint, Padding, Phase, ReaderSource, DFUSE_SET_ADDRESS,
+    DFU_DNLOAD,
+};
+
+/// Reader handing out chunks of (at most) the transfer size
+struct ChunkReader<S> {
+    source: S,
+    buf: Box<[u8]>,
+    level: usize,
+    /// Number of fill bytes still to be returned before the data of the source
+    front: usize,
+    /// Pad the last chunk to the full size
+    pad_end: bool,
+    fill: u8,
+    /// CRC-32 of the data read from the source so far, before the final inversion
+    crc: u32,
+}
+
+impl<S: FirmwareSource> ChunkReader<S> {
+    fn new(size: usize, source: S) -> Self {
+        Self {
+            source,
+            buf: vec![0; size].into_boxed_slice(),
+            level: 0,
+            front: 0,
+            pad_end: false,
+            fill: 0xff,
+            crc: 0xffff_ffff,
+        }
+    }
+
+    /// Return `front` fill bytes before the source and pad the last chunk as set in `padding`
+    fn padded(mut self, front: usize, padding: &Padding) -> Self {
+        self.front = front;
+        self.pad_end = padding.final_block;
+        self.fill = padding.fill;
+        self
+    }
+
+    async fn fill_buf(&mut self) -> Result<&[u8], Error> {
+        let n = self.front.min(self.buf.len() - self.level);
+        self.buf[self.level..self.level + n].fill(self.fill);
+        self.level += n;
+        self.front -= n;
+        while self.level < self.buf.len() {
+            let r = self.source.read_chunk(&mut self.buf[self.level..]).await?;
+            self.crc = crc32_update(self.crc, &self.buf[self.level..self.level + r]);
+            if r == 0 {
+                if self.pad_end && self.level > 0 {
+                    self.buf[self.level..].fill(self.fill);
+                    self.level = self.buf.len();
+                }
+                break;
+            }
+            self.level += r;
+        }
+        Ok(&self.buf[..self.level])
+    }
+
+    fn consume(&mut self, amt: usize) {
+        if amt >= self.level {
+            self.level = 0;
+        } else {
+            self.buf.copy_within(amt..self.level, 0);
+            self.level -= amt;
+        }
+    }
+}
+
+/// Contiguous memory written by a DfuSe download, to be read back
+struct Region {
+    address: u32,
+    /// Offset of the data in the download
+    offset: u32,
+    data: Vec<u8>,
+}
+
+/// Add a block written at `address` to the regions to read back
+fn add_region(regions: &mut Vec<Region>, address: u32, offset: u32, data: &[u8]) {
+    match regions.last_mut() {
+        Some(last)
+            if last.address.wrapping_add(last.data.len() as u32) == address
+                && last.offset + last.data.len() as u32 == offset =>
+        {
+            last.data.extend_from_slice(data)
+        }
+        _ => regions.push(Region {
+            address,
+            offset,
+            data: data.to_vec(),
+        }),
+    }
+}
+
+/// Forget the data written from `address` on, which was moved past a bad sector
+fn truncate_regions(regions: &mut Vec<Region>, address: u32) {
+    regions.retain(|region| region.address < address);
+    if let Some(last) = regions.last_mut() {
+        let keep = address.wrapping_sub(last.address) as usize;
+        last.data.truncate(keep);
+    }
+}
+
+/// Advance the state machine past a request that wasn't sent to the device
+fn skip_wait<T>(cmd: get_status::WaitState<T>) -> Result<T, Error> {
+    let cmd = cmd.chain(get_status::GetStatusMessage {
+        status: dfu_core::Status::Ok,
+        poll_timeout: 0,
+        state: dfu_core::State::DfuDnloadIdle,
+        index: 0,
+    })?;
+    match cmd.next() {
+        get_status::Step::Break(cmd) => Ok(cmd),
+        get_status::Step::Wait(..) => unreachable!("state reached after a matching status"),
+    }
+}
+
+impl DfuNusb {
+    /// Download a firmware of `length` bytes into the device from a reader
+    ///
+    /// Compared to [`dfu_core::asynchronous::DfuASync::download`] this checks the firmware fits
+    /// the target (see [`DfuNusb::force`]) and reports the number of pages to erase in
+    /// [`Progress::Erase`](crate::Progress::Erase) events.
+    pub async fn download<R: AsyncRead + Unpin>(
+        &self,
+        reader: R,
+        length: u32,
+    ) -> Result<(), Error> {
+        self.check_download(Some(length))?;
+        self.download_reader(ReaderSource::new(reader), length, true)
+            .await
+    }
+
+    /// Download a firmware of unknown length from a stream
+    ///
+    /// Unlike [`dfu_core::asynchronous::DfuASync::download`] this doesn't require the size
+    /// up-front, so it can be used with non-seekable sources like pipes. As DfuSe devices need
+    /// to erase the target region before writing, the stream is read completely into memory
+    /// first for those.
+    pub async fn download_stream<R: AsyncRead + Unpin>(&self, reader: R) -> Result<(), Error> {
+        self.download_source(ReaderSource::new(reader)).await
+    }
+
+    /// Number of pages erased by a DfuSe download of `length` bytes
+    fn erase_pages(&self, length: u32) -> Option<usize> {
+        // Pages are taken from the start of the memory layout, whatever the start address
+        let mut erased = 0u64;
+        let pages = self
+            .memory_layout()?
+            .iter()
+            .take_while(|&&page| {
+                let more = erased < u64::from(length);
+                erased += u64::from(page);
+                more
+            })
+            .count();
+        Some(pages)
+    }
+
+    async fn wait_status<T>(
+        &self,
+        mut cmd: get_status::WaitState<T>,
+        buffer: &mut [u8],
+    ) -> Result<T, Error> {
+        loop {
+            cmd = match cmd.next() {
+                get_status::Step::Break(cmd) => break Ok(cmd),
+                get_status::Step::Wait(cmd, poll_timeout) => {
+                    DfuAsyncIo::sleep(self, Duration::from_millis(poll_timeout)).await;
+                    let (cmd, mut control) = cmd.get_status(buffer);
+                    let n = control.execute_async(self).await?;
+                    cmd.chain(&buffer[..n])??
+                }
+            };
+        }
+    }
+
+    /// Read back the regions written by a DfuSe download, each in a single upload, or have the
+    /// device compute their CRC with `device_crc`, then point the block numbers of the download
+    /// back at `pointer`
+    async fn verify_regions(
+        &self,
+        regions: &[Region],
+        device_crc: Option<u8>,
+        pointer: u32,
+    ) -> Result<(), Error> {
+        for region in regions {
+            let length = region.data.len();
+            let mismatch = match device_crc {
+                Some(command) => {
+                    let crc =
+                        self::device_crc(self, command, region.address, length as u32).await?;
+                    (crc != Some(!crc32(&region.data))).then_some(0)
+                }
+                None => {
+                    let mut read = Vec::with_capacity(length);
+                    self.upload_into(region.address, length, &mut read, Phase::Verify)
+                        .await?;
+                    region
+                        .data
+                        .iter()
+                        .zip(&read)
+                        .position(|(a, b)| a != b)
+                        .or((read.len() < length).then_some(read.len()))
+                }
+            };
+            if let Some(index) = mismatch {
+                return Err(Error::VerifyMismatch {
+                    offset: region.offset + index as u32,
+                });
+            }
+        }
+        dfuse_command(self, DFUSE_SET_ADDRESS, pointer).await?;
+        self.ensure_idle().await
+    }
+
+    /// Download from `reader`; unless `leave` is set the download isn't terminated with the
+    /// zero-length request that makes DfuSe devices leave DFU mode
+    pub(crate) async fn download_reader<S: FirmwareSource>(
+        &self,
+        reader: S,
+        length: u32,
+        leave: bool,
+    ) -> Result<(), Error> {
+        let _operation = self.begin_operation()?;
+        if let Some(session) = self.reporter.lock().unwrap().session() {
+            let digest = reader.digest().map(|digest| format!("{digest:08x}"));
+            session.start(
+                self.session_parameters(length)
+                    .string("digest", digest.as_deref()),
+            );
+        }
+        let result = self.download_loop(reader, length, leave).await;
+        if let Some(session) = self.reporter.lock().unwrap().session() {
+            session.finish(result.as_ref().copied().map_err(ToString::to_string));
+        }
+        result.map(|_| ())
+    }
+
+    /// Log a retry or a recovery from a failure to the session log, if any
+    pub(crate) fn log_recovery(&self, action: &str, fields: JsonFields) {
+        if let Some(session) = self.reporter.lock().unwrap().session() {
+            session.recovery(action, fields);
+        }
+    }
+
+    /// Download from `reader`, returning the CRC-32 of the firmware read from it
+    async fn download_loop<S: FirmwareSource>(
+        &self,
+        reader: S,
+        length: u32,
+        leave: bool,
+    ) -> Result<u32, Error> {
+        self.check_write(DFU_DNLOAD)?;
+        self.reset_polled();
+        self.reset_hook_failures();
+        self.verified.store(false, Ordering::Relaxed);
+        if self.verify
+            && (!self.descriptor.can_upload
+                || !(self.is_dfuse() || self.descriptor.manifestation_tolerant))
+        {
+            return Err(Error::VerifyNotSupported);
+        }
+        let mut start = self.address().unwrap_or_default();
+        let mut front = 0;
+        if let (true, Some(base), Some(layout)) = (
+            self.padding.align_start,
+            self.default_address(),
+            self.memory_layout(),
+        ) {
+            if let Some(page) = page_start(base, layout, start) {
+                front = start - page;
+                start = page;
+            }
+        }
+        let mut dfu = DfuSansIo::new(self.descriptor);
+        if self.override_address.is_some() || front > 0 {
+            dfu.set_address(start);
+        }
+        let firmware_length = length;
+        // The length only matters for erasing, where padding has to be taken into account
+        let transfer_size = u32::from(self.descriptor.transfer_size);
+        let length = match length.checked_add(front) {
+            Some(length) if self.padding.final_block => length
+                .div_ceil(transfer_size)
+                .checked_mul(transfer_size)
+                .unwrap_or(length),
+            Some(length) => length,
+            None => length,
+        };
+        let mut reader = ChunkReader::new(self.descriptor.transfer_size as usize, reader)
+            .padded(front as usize, &self.padding);
+        if reader.fill_buf().await?.is_empty() {
+            return Ok(!reader.crc);
+        }
+        // CRC command verifying blocks, if the device supports it
+        let device_crc = match self.crc_command {
+            Some(command) if self.verify && self.is_dfuse() => self
+                .read_dfuse_commands()
+                .await?
+                .contains(&command)
+                .then_some(command),
+            _ => None,
+        };
+        self.reporter
+            .lock()
+            .unwrap()
+            .start_download(self.erase_pages(length), self.descriptor.transfer_size);
+        let mut sectors = self
+            .is_dfuse()
+            .then(|| self.sector_map(start, length, device_crc))
+            .flatten();
+
+        let mut buffer = [0; 6];
+        let cmd = dfu.download(&self.protocol, length)?;
+        let (cmd, mut control) = cmd.get_status(&mut buffer);
+        let n = control.execute_async(self).await?;
+        let (cmd, control) = cmd.chain(&buffer[..n])?;
+        if let Some(control) = control {
+            control.execute_async(self).await?;
+        }
+        let (cmd, mut control) = cmd.get_status(&mut buffer);
+        let n = control.execute_async(self).await?;
+        let mut download_loop = cmd.chain(&buffer[..n])??;
+        // Blocks 0 and 1 are reserved for DfuSe commands
+        let mut block: u16 = if self.is_dfuse() { 2 } else { 0 };
+        let mut offset = 0u32;
+        // Data written, read back at the end of the download
+        let mut written = Vec::new();
+        let mut regions = Vec::new();
+        let mut erasing = false;
+        let mut manifested = false;
+
+        loop {
+            download_loop = match download_loop.next() {
+                download::Step::Break => {
+                    manifested = true;
+                    break;
+                }
+                download::Step::Erase(cmd) if self.skip_erase => {
+                    let (cmd, _) = cmd.erase()?;
+                    skip_wait(cmd)?
+                }
+                download::Step::Erase(cmd) => {
+                    if !erasing {
+                        self.run_hooks(HookPoint::BeforeErase, firmware_length)
+                            .await?;
+                        erasing = true;
+                    }
+                    let (cmd, control) = cmd.erase()?;
+                    control.execute_async(self).await?;
+                    self.wait_status(cmd, &mut buffer).await?
+                }
+                download::Step::SetAddress(cmd) => {
+                    let (cmd, control) = cmd.set_address();
+                    control.execute_async(self).await?;
+                    self.wait_status(cmd, &mut buffer).await?
+                }
+                download::Step::DownloadChunk(cmd) => {
+                    let chunk = reader.fill_buf().await?;
+                    if chunk.is_empty() && self.verify && self.is_dfuse() {
+                        let pointer = sectors.as_ref().map_or(start, |s| s.position().0);
+                        self.verify_regions(&regions, device_crc, pointer).await?;
+                        self.verified.store(!regions.is_empty(), Ordering::Relaxed);
+                        self.run_late_hooks(HookPoint::AfterVerify, firmware_length)
+                            .await;
+                    }
+                    if chunk.is_empty() && !leave {
+                        self.ensure_idle().await?;
+                        break;
+                    }
+                    if offset == 0 && !chunk.is_empty() {
+                        self.run_hooks(HookPoint::BeforeFirstWrite, firmware_length)
+                            .await?;
+                    }
+                    let position = match &mut sectors {
+                        Some(sectors) => {
+                            let (pointer, offset) = sectors.position();
+                            let end = pointer
+                                .wrapping_add(offset)
+                                .wrapping_add(chunk.len() as u32);
+                            sectors.erase_moved(self, end).await?;
+                            (pointer, offset)
+                        }
+                        None => (start, offset),
+                    };
+                    let (cmd, control) = cmd.download(chunk)?;
+                    let n = control.execute_async(self).await?;
+                    let data = ((self.verify || sectors.is_some()) && n > 0)
+                        .then(|| reader.buf[..n].to_vec());
+                    reader.consume(n);
+                    let (cmd, moved) = match &mut sectors {
+                        Some(sectors) => match self.wait_idle().await {
+                            Ok(_) => {
+                                sectors.written(data.as_deref().unwrap_or_default());
+                                (skip_wait(cmd)?, false)
+                            }
+                            Err(e) if e.is_write_protected() => {
+                                let data = data.as_deref().unwrap_or_default();
+                                let next = block.wrapping_add(1);
+                                let skipped = sectors.skipped().len();
+                                let result = sectors.skip_bad_sector(self, data, next, e).await;
+                                for &address in &sectors.skipped()[skipped..] {
+                                    truncate_regions(&mut regions, address);
+                                    let fields = JsonFields::default()
+                                        .number("address", Some(address.into()));
+                                    self.log_recovery("bad_sector", fields);
+                                }
+                                *self.skipped_sectors.lock().unwrap() = sectors.skipped().to_vec();
+                                result?;
+                                (skip_wait(cmd)?, true)
+                            }
+                            Err(e) => return Err(e),
+                        },
+                        None => (self.wait_status(cmd, &mut buffer).await?, false),
+                    };
+
+                    // Moved data was verified as it was written again
+                    if let Some(data) = data.filter(|_| self.verify && !moved) {
+                        if self.is_dfuse() {
+                            let address = position.0.wrapping_add(position.1);
+                            add_region(&mut regions, address, offset, &data);
+                        } else {
+                            written.extend_from_slice(&data);
+                        }
+                    }
+                    block = block.wrapping_add(1);
+                    offset += n as u32;
+                    cmd
+                }
+                download::Step::UsbReset => {
+                    DfuAsyncIo::usb_reset(self).await?;
+                    manifested = true;
+                    break;
+                }
+            }
+        }
+        if manifested {
+            self.run_late_hooks(HookPoint::AfterManifest, firmware_length)
+                .await;
+        }
+
+        if self.verify && !self.is_dfuse() {
+            let mut read = Vec::with_capacity(written.len());
+            self.upload_into(0, written.len(), &mut read, Phase::Verify)
+                .await?;
+            if let Some(offset) = written.iter().zip(&read).position(|(a, b)| a != b) {
+                return Err(Error::VerifyMismatch {
+                    offset: offset as u32,
+                });
+            }
+            if read.len() < written.len() {
+                return Err(Error::VerifyMismatch {
+                    offset: read.len() as u32,
+                });
+            }
+            self.verified.store(!written.is_empty(), Ordering::Relaxed);
+            self.run_late_hooks(HookPoint::AfterVerify, firmware_length)
+                .await;
+        }
+
+        Ok(!reader.crc)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn regions(regions: &[Region]) -> Vec<(u32, u32, usize)> {
+        regions
+            .iter()
+            .map(|r| (r.address, r.offset, r.data.len()))
+            .collect()
+    }
+
+    #[test]
+    fn contiguous_blocks_merged() {
+        let mut list = Vec::new();
+        add_region(&mut list, 0x0800_0000, 0, &[0; 256]);
+        add_region(&mut list, 0x0800_0100, 256, &[0; 256]);
+        // Moved past a bad sector
+        add_region(&mut list, 0x0800_0800, 512, &[0; 256]);
+        add_region(&mut list, 0x0800_0900, 768, &[0; 100]);
+        assert_eq!(
+            regions(&list),
+            [(0x0800_0000, 0, 512), (0x0800_0800, 512, 356)]
+        );
+    }
+
+    #[test]
+    fn truncated_at_bad_sector() {
+        let mut list = Vec::new();
+        add_region(&mut list, 0x0800_0000, 0, &[0; 1024]);
+        add_region(&mut list, 0x0800_0800, 1024, &[0; 512]);
+        truncate_regions(&mut list, 0x0800_0900);
+        assert_eq!(
+            regions(&list),
+            [(0x0800_0000, 0, 1024), (0x0800_0800, 1024, 256)]
+        );
+        truncate_regions(&mut list, 0x0800_0400);
+        assert_eq!(regions(&list), [(0x0800_0000, 0, 1024)]);
+        truncate_regions(&mut list, 0x0800_0000);
+        assert_eq!(regions(&list), []);
+    }
+}
@@ -0,0 +1,66 @@
+//! Process exit codes for command line frontends
+//!
+//! These are stable, so shell scripts and CI jobs can branch on the cause of a failure
+//! without parsing stderr. [`Error::exit_code`] maps library errors onto them.
+
+use crate::{Error, ErrorKind};
+
+/// Failure not caused by the device, e.g. an unreadable firmware file
+pub const FAILURE: u8 = 1;
+/// Invalid arguments or options, or an operation the device doesn't support
+pub const USAGE: u8 = 2;
+/// The firmware doesn't fit, isn't meant for the device or is corrupted
+pub const WRONG_IMAGE: u8 = 3;
+/// No matching device was found, or more than one
+pub const DEVICE_NOT_FOUND: u8 = 4;
+/// The firmware read back differs from the one written
+pub const VERIFY_FAILED: u8 = 5;
+/// USB communication failed or the device went away
+pub const USB_ERROR: u8 = 6;
+/// The device reported an error through its DFU status
+pub const DEVICE_ERROR: u8 = 7;
+/// The operation was cancelled by the user, as by Ctrl-C (128 + SIGINT)
+pub const CANCELLED: u8 = 130;
+
+impl Error {
+    /// Exit code a command line frontend should end with after this error, see [`crate::exit`]
+    pub fn exit_code(&self) -> u8 {
+        match self {
+            Error::DeviceNotFound | Error::MultipleDevices(_) | Error::UnexpectedDevice { .. } => {
+                DEVICE_NOT_FOUND
+            }
+            Error::FirmwareTooLarge { .. }
+            | Error::SuffixMismatch { .. }
+            | Error::InvalidFirmware(_)
+            | Error::DigestMismatch { .. } => WRONG_IMAGE,
+            Error::VerifyMismatch { .. } => VERIFY_FAILED,
+            _ => match self.kind() {
+                ErrorKind::Host => FAILURE,
+                ErrorKind::Usage => USAGE,
+                ErrorKind::DeviceStatus => DEVICE_ERROR,
+                ErrorKind::Transient | ErrorKind::Disconnected | ErrorKind::Protocol => USB_ERROR,
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn host_io_errors() {
+        for error in [
+            // ENOSPC
+            std::io::Error::from_raw_os_error(28),
+            std::io::Error::from(std::io::ErrorKind::BrokenPipe),
+            // ENODEV, a disconnect when it comes from a USB transfer
+            std::io::Error::from_raw_os_error(19),
+        ] {
+            let error = Error::Io(error);
+            assert_eq!(error.kind(), ErrorKind::Host, "{error}");
+            assert!(!error.is_retryable(), "{error}");
+            assert_eq!(error.exit_code(), FAILURE, "{error}");
+        }
+    }
+}
@@ -0,0 +1,309 @@
+//! Simulated DFU and DfuSe device for the unit tests of the request sequences
+
+use std::sync::Mutex;
+use std::time::Duration;
+
+use dfu_core::asynchronous::DfuAsyncIo;
+use dfu_core::functional_descriptor::FunctionalDescriptor;
+use dfu_core::memory_layout::MemoryLayout;
+use dfu_core::{DfuProtocol, State, Status};
+use nusb::transfer::TransferError;
+
+use crate::{
+    Error, DFUSE_ERASE, DFUSE_SET_ADDRESS, DFU_ABORT, DFU_CLRSTATUS, DFU_DNLOAD, DFU_GETSTATE,
+    DFU_GETSTATUS, DFU_UPLOAD,
+};
+
+/// Device holding `memory`, answering requests the way DFU bootloaders do
+pub(crate) struct FakeDevice {
+    descriptor: FunctionalDescriptor,
+    protocol: DfuProtocol<MemoryLayout>,
+    inner: Mutex<Inner>,
+}
+
+struct Inner {
+    memory: Vec<u8>,
+    base: u32,
+    pages: Vec<u32>,
+    /// Start addresses of the sectors failing with errWRITE
+    bad: Vec<u32>,
+    /// Only serve uploads of consecutive block numbers
+    consecutive_only: bool,
+    /// Serve uploads from its own block counter, ignoring the block number of the request
+    counts_blocks: bool,
+    state: State,
+    status: Status,
+    /// DfuSe address pointer
+    pointer: u32,
+    /// Block number of the last upload, while in dfuUPLOAD-IDLE
+    last_upload: Option<u16>,
+    /// Whether the last download request was written successfully, until the status is read
+    pending: Option<bool>,
+}
+
+impl FakeDevice {
+    /// DfuSe device with erased flash made of `pages` from `base`
+    pub(crate) fn dfuse(base: u32, pages: Vec<u32>, transfer_size: u16) -> Self {
+        let size = pages.iter().sum::<u32>() as usize;
+        let protocol = DfuProtocol::Dfuse {
+            address: base,
+            memory_layout: MemoryLayout::from(pages.clone()),
+        };
+        Self::new(protocol, vec![0xff; size], base, pages, transfer_size)
+    }
+
+    /// Plain DFU device holding `memory`
+    pub(crate) fn dfu(memory: Vec<u8>, transfer_size: u16) -> Self {
+        Self::new(DfuProtocol::Dfu, memory, 0, Vec::new(), transfer_size)
+    }
+
+    fn new(
+        protocol: DfuProtocol<MemoryLayout>,
+        memory: Vec<u8>,
+        base: u32,
+        pages: Vec<u32>,
+        transfer_size: u16,
+    ) -> Self {
+        Self {
+            descriptor: FunctionalDescriptor {
+                can_download: true,
+                can_upload: true,
+                manifestation_tolerant: true,
+                will_detach: false,
+                detach_timeout: 0,
+                transfer_size,
+                dfu_version: (0x01, 0x1a),
+            },
+            protocol,
+            inner: Mutex::new(Inner {
+                memory,
+                base,
+                pages,
+                bad: Vec::new(),
+                consecutive_only: false,
+                counts_blocks: false,
+                state: State::DfuIdle,
+                status: Status::Ok,
+                pointer: base,
+                last_upload: None,
+                pending: None,
+            }),
+        }
+    }
+
+    /// Make writes to the sector starting at `address` fail with errWRITE
+    pub(crate) fn bad_sector(self, address: u32) -> Self {
+        self.inner.lock().unwrap().bad.push(address);
+        self
+    }
+
+    /// Refuse uploads of block numbers not following the previous one
+    pub(crate) fn consecutive_only(self) -> Self {
+        self.inner.lock().unwrap().consecutive_only = true;
+        self
+    }
+
+    /// Serve uploads from a block counter starting at 0, whatever block number is asked for
+    pub(crate) fn counts_blocks(self) -> Self {
+        self.inner.lock().unwrap().counts_blocks = true;
+        self
+    }
+
+    /// Contents of `length` bytes of memory at `address`
+    pub(crate) fn memory(&self, address: u32, length: usize) -> Vec<u8> {
+        let inner = self.inner.lock().unwrap();
+        let start = (address - inner.base) as usize;
+        inner.memory[start..start + length].to_vec()
+    }
+}
+
+impl Inner {
+    fn is_dfuse(&self) -> bool {
+        !self.pages.is_empty()
+    }
+
+    /// Start and end of the page containing `address`
+    fn page(&self, address: u32) -> Option<(u32, u32)> {
+        crate::padding::page_bounds(self.base, &self.pages, address)
+    }
+
+    /// Offset in memory of block number `block`
+    fn offset(&self, block: u16, transfer_size: u16) -> usize {
+        let block = if self.is_dfuse() { block - 2 } else { block };
+        let address = if self.is_dfuse() { self.pointer } else { 0 };
+        (address - self.base) as usize + usize::from(block) * usize::from(transfer_size)
+    }
+
+    /// Refuse a request, as devices do by stalling it
+    fn stall(&mut self) -> Error {
+        self.state = State::DfuError;
+        self.status = Status::ErrStalledpkt;
+        Error::Transfer(TransferError::Stall)
+    }
+
+    fn download(&mut self, block: u16, data: &[u8], transfer_size: u16) -> Result<bool, Error> {
+        if !matches!(self.state, State::DfuIdle | State::DfuDnloadIdle) || data.is_empty() {
+            return Err(self.stall());
+        }
+        if self.is_dfuse() && block == 0 {
+            let address = match data {
+                &[_, a, b, c, d] => u32::from_le_bytes([a, b, c, d]),
+                _ => return Err(self.stall()),
+            };
+            return Ok(match (data[0], self.page(address)) {
+                (DFUSE_SET_ADDRESS, Some(_)) => {
+                    self.pointer = address;
+                    true
+                }
+                (DFUSE_ERASE, Some((start, end))) => {
+                    let range = (start - self.base) as usize..(end - self.base) as usize;
+                    self.memory[range].fill(0xff);
+                    true
+                }
+                _ => false,
+            });
+        }
+
+        let offset = self.offset(block, transfer_size);
+        if offset + data.len() > self.memory.len() {
+            return Ok(false);
+        }
+        let address = self.base + offset as u32;
+        if let Some((start, _)) = self.page(address) {
+            if self.bad.contains(&start) {
+                return Ok(false);
+            }
+        }
+        self.memory[offset..offset + data.len()].copy_from_slice(data);
+        Ok(true)
+    }
+
+    fn upload(
+        &mut self,
+        block: u16,
+        buffer: &mut [u8],
+        transfer_size: u16,
+    ) -> Result<usize, Error> {
+        let next = match (self.state, self.last_upload) {
+            (State::DfuIdle, _) => 0,
+            (State::DfuUploadIdle, Some(last)) => last.wrapping_add(1),
+            _ => return Err(self.stall()),
+        };
+        let consecutive = block == next;
+        let block = if self.counts_blocks { next } else { block };
+        if self.is_dfuse() && block == 0 {
+            let commands = [0x00, DFUSE_SET_ADDRESS, DFUSE_ERASE];
+            buffer[..commands.len()].copy_from_slice(&commands);
+            self.state = State::DfuUploadIdle;
+            self.last_upload = Some(block);
+            return Ok(commands.len());
+        }
+        if (self.is_dfuse() && block < 2) || (self.consecutive_only && !consecutive) {
+            return Err(self.stall());
+        }
+
+        let offset = self.offset(block, transfer_size).min(self.memory.len());
+        let n = buffer.len().min(self.memory.len() - offset);
+        buffer[..n].copy_from_slice(&self.memory[offset..offset + n]);
+        if n < buffer.len() {
+            self.state = State::DfuIdle;
+            self.last_upload = None;
+        } else {
+            self.state = State::DfuUploadIdle;
+            self.last_upload = Some(block);
+        }
+        Ok(n)
+    }
+
+    fn get_status(&mut self) -> [u8; 6] {
+        // The device goes busy once the status of a block is asked for, and reports the
+        // outcome on the next status request
+        match (self.state, self.pending) {
+            (State::DfuDnloadSync, Some(_)) => self.state = State::DfuDnbusy,
+            (State::DfuDnbusy, Some(true)) => {
+                self.state = State::DfuDnloadIdle;
+                self.pending = None;
+            }
+            (State::DfuDnbusy, Some(false)) => {
+                self.state = State::DfuError;
+                self.status = Status::ErrWrite;
+                self.pending = None;
+            }
+            _ => (),
+        }
+        [u8::from(self.status), 0, 0, 0, u8::from(self.state), 0]
+    }
+}
+
+impl DfuAsyncIo for FakeDevice {
+    type Read = usize;
+    type Write = usize;
+    type Reset = ();
+    type Error = Error;
+    type MemoryLayout = MemoryLayout;
+
+    async fn read_control(
+        &self,
+        _request_type: u8,
+        request: u8,
+        value: u16,
+        buffer: &mut [u8],
+    ) -> Result<usize, Error> {
+        let mut inner = self.inner.lock().unwrap();
+        match request {
+            DFU_GETSTATUS => {
+                let status = inner.get_status();
+                let n = buffer.len().min(status.len());
+                buffer[..n].copy_from_slice(&status[..n]);
+                Ok(n)
+            }
+            DFU_GETSTATE => {
+                buffer[0] = u8::from(inner.state);
+                Ok(1)
+            }
+            DFU_UPLOAD => inner.upload(value, buffer, self.descriptor.transfer_size),
+            _ => Err(inner.stall()),
+        }
+    }
+
+    async fn write_control(
+        &self,
+        _request_type: u8,
+        request: u8,
+        value: u16,
+        buffer: &[u8],
+    ) -> Result<usize, Error> {
+        let mut inner = self.inner.lock().unwrap();
+        match request {
+            DFU_DNLOAD => {
+                let written = inner.download(value, buffer, self.descriptor.transfer_size)?;
+                inner.state = State::DfuDnloadSync;
+                inner.pending = Some(written);
+            }
+            DFU_CLRSTATUS if inner.state == State::DfuError => {
+                inner.state = State::DfuIdle;
+                inner.status = Status::Ok;
+            }
+            DFU_ABORT if inner.state != State::DfuError => {
+                inner.state = State::DfuIdle;
+                inner.last_upload = None;
+            }
+            _ => return Err(inner.stall()),
+        }
+        Ok(buffer.len())
+    }
+
+    async fn usb_reset(&self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    async fn sleep(&self, _duration: Duration) {}
+
+    fn protocol(&self) -> &DfuProtocol<MemoryLayout> {
+        &self.protocol
+    }
+
+    fn functional_descriptor(&self) -> &FunctionalDescriptor {
+        &self.descriptor
+    }
+}
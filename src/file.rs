@@ -0,0 +1,342 @@
+//! DFU file format helpers, usable without a device
+//!
+//! These cover the DFU suffix (DFU 1.1 appendix B), its CRC and DfuSe images (UM0391), e.g.
+//! to produce `.dfu` files from a build script:
+//!
+//! ```
+//! use dfu_nusb::file::DfuSuffix;
+//!
+//! let mut firmware = vec![0u8; 1024];
+//! DfuSuffix::new(0x0483, 0xdf11).append_to(&mut firmware);
+//! assert!(DfuSuffix::parse(&firmware).is_some_and(|s| s.crc_valid(&firmware)));
+//! ```
+
+/// CRC32 of `data` as used by the DFU suffix
+///
+/// This is the IEEE 802.3 CRC without the final inversion.
+pub fn crc32(data: &[u8]) -> u32 {
+    crc32_update(0xffff_ffff, data)
+}
+
+/// Continue the CRC32 computation of [`crc32`] with more data
+pub(crate) fn crc32_update(crc: u32, data: &[u8]) -> u32 {
+    data.iter().fold(crc, |crc, &byte| {
+        (0..8).fold(crc ^ u32::from(byte), |crc, _| {
+            if crc & 1 != 0 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            }
+        })
+    })
+}
+
+/// DFU suffix appended to firmware files
+///
+/// A value of `0xffff` for the ids and version means the firmware is not specific to a
+/// particular vendor, product or device release.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DfuSuffix {
+    /// bcdDevice: release number of the device the firmware is for
+    pub device_version: u16,
+    /// idProduct: product id of the device the firmware is for
+    pub product_id: u16,
+    /// idVendor: vendor id of the device the firmware is for
+    pub vendor_id: u16,
+    /// bcdDFU: DFU specification release number
+    pub dfu_version: u16,
+    /// bLength: length of the suffix
+    pub length: u8,
+    /// dwCRC: CRC over the firmware file, excluding this field
+    pub crc: u32,
+}
+
+impl DfuSuffix {
+    /// Size of the standard DFU suffix
+    pub const LENGTH: usize = 16;
+
+    /// Create a DFU 1.1 suffix for firmware of the given device, for any device release
+    ///
+    /// Use `0x011a` as [`DfuSuffix::dfu_version`] for DfuSe images.
+    pub fn new(vendor_id: u16, product_id: u16) -> Self {
+        Self {
+            device_version: 0xffff,
+            product_id,
+            vendor_id,
+            dfu_version: 0x0100,
+            length: Self::LENGTH as u8,
+            crc: 0,
+        }
+    }
+
+    /// Parse the DFU suffix at the end of a firmware file
+    ///
+    /// Returns `None` if the data doesn't end with a DFU suffix.
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        let start = data.len().checked_sub(Self::LENGTH)?;
+        let suffix = &data[start..];
+        let u16_at = |i: usize| u16::from_le_bytes([suffix[i], suffix[i + 1]]);
+
+        if &suffix[8..11] != b"UFD" || usize::from(suffix[11]) < Self::LENGTH {
+            return None;
+        }
+
+        Some(Self {
+            device_version: u16_at(0),
+            product_id: u16_at(2),
+            vendor_id: u16_at(4),
+            dfu_version: u16_at(6),
+            length: suffix[11],
+            crc: u32::from_le_bytes([suffix[12], suffix[13], suffix[14], suffix[15]]),
+        })
+    }
+
+    /// Serialize the suffix
+    pub fn to_bytes(&self) -> [u8; Self::LENGTH] {
+        let mut bytes = [0; Self::LENGTH];
+        bytes[0..2].copy_from_slice(&self.device_version.to_le_bytes());
+        bytes[2..4].copy_from_slice(&self.product_id.to_le_bytes());
+        bytes[4..6].copy_from_slice(&self.vendor_id.to_le_bytes());
+        bytes[6..8].copy_from_slice(&self.dfu_version.to_le_bytes());
+        bytes[8..11].copy_from_slice(b"UFD");
+        bytes[11] = Self::LENGTH as u8;
+        bytes[12..].copy_from_slice(&self.crc.to_le_bytes());
+        bytes
+    }
+
+    /// Append the suffix to `firmware`, computing its CRC
+    pub fn append_to(mut self, firmware: &mut Vec<u8>) {
+        self.length = Self::LENGTH as u8;
+        let bytes = self.to_bytes();
+        firmware.extend_from_slice(&bytes[..Self::LENGTH - 4]);
+        self.crc = crc32(firmware);
+        firmware.extend_from_slice(&self.crc.to_le_bytes());
+    }
+
+    /// Check the CRC of the suffix against the firmware file `data` it was parsed from
+    pub fn crc_valid(&self, data: &[u8]) -> bool {
+        data.len() >= 4 && crc32(&data[..data.len() - 4]) == self.crc
+    }
+
+    /// Check whether the firmware is meant for a device with the given ids
+    pub fn matches(&self, vendor_id: u16, product_id: u16) -> bool {
+        (self.vendor_id == 0xffff || self.vendor_id == vendor_id)
+            && (self.product_id == 0xffff || self.product_id == product_id)
+    }
+}
+
+/// Prefix at the start of DfuSe images
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DfusePrefix {
+    /// bVersion: format version, `1`
+    pub version: u8,
+    /// DFUImageSize: size of the image without the suffix, including this prefix
+    pub image_size: u32,
+    /// bTargets: number of targets in the image
+    pub targets: u8,
+}
+
+impl DfusePrefix {
+    /// Size of the prefix
+    pub const LENGTH: usize = 11;
+
+    /// Create the prefix of an image of `image_size` bytes with `targets` targets
+    pub fn new(image_size: u32, targets: u8) -> Self {
+        Self {
+            version: 1,
+            image_size,
+            targets,
+        }
+    }
+
+    /// Parse the prefix at the start of a DfuSe image
+    ///
+    /// Returns `None` if the data doesn't start with a DfuSe prefix.
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        let prefix = data.get(..Self::LENGTH)?;
+        if &prefix[..5] != b"DfuSe" {
+            return None;
+        }
+        Some(Self {
+            version: prefix[5],
+            image_size: u32::from_le_bytes([prefix[6], prefix[7], prefix[8], prefix[9]]),
+            targets: prefix[10],
+        })
+    }
+
+    /// Serialize the prefix
+    pub fn to_bytes(&self) -> [u8; Self::LENGTH] {
+        let mut bytes = [0; Self::LENGTH];
+        bytes[..5].copy_from_slice(b"DfuSe");
+        bytes[5] = self.version;
+        bytes[6..10].copy_from_slice(&self.image_size.to_le_bytes());
+        bytes[10] = self.targets;
+        bytes
+    }
+}
+
+/// Target of a [`DfuseImage`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct ImageTarget {
+    alt: u8,
+    name: Option<String>,
+    elements: Vec<(u32, Vec<u8>)>,
+}
+
+/// Builder of DfuSe images (UM0391), as read by [`FirmwareInfo::parse`](crate::FirmwareInfo::parse)
+/// and DfuSe tools
+///
+/// ```
+/// use dfu_nusb::file::{DfuSuffix, DfuseImage};
+/// use dfu_nusb::FirmwareInfo;
+///
+/// let image = DfuseImage::new()
+///     .element(0, Some("Internal Flash"), 0x0800_0000, vec![0u8; 1024])
+///     .build(DfuSuffix::new(0x0483, 0xdf11));
+/// let info = FirmwareInfo::parse(&image).unwrap();
+/// assert_eq!(info.targets.unwrap()[0].elements[0].address, 0x0800_0000);
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DfuseImage {
+    targets: Vec<ImageTarget>,
+}
+
+impl DfuseImage {
+    /// Size of the prefix of a target
+    const TARGET_PREFIX_LENGTH: usize = 274;
+
+    /// Create an image without targets
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add an element with `data` at `address` to the target of alternative setting `alt`
+    ///
+    /// The target is created with `name`, truncated to 254 bytes, on its first element.
+    pub fn element(mut self, alt: u8, name: Option<&str>, address: u32, data: Vec<u8>) -> Self {
+        match self.targets.iter_mut().find(|target| target.alt == alt) {
+            Some(target) => target.elements.push((address, data)),
+            None => self.targets.push(ImageTarget {
+                alt,
+                name: name.map(ToString::to_string),
+                elements: vec![(address, data)],
+            }),
+        }
+        self
+    }
+
+    /// Serialize the image, followed by `suffix` with bcdDFU `0x011a` and its CRC
+    pub fn build(&self, mut suffix: DfuSuffix) -> Vec<u8> {
+        let mut image = vec![0; DfusePrefix::LENGTH];
+        for target in &self.targets {
+            let size: usize = target.elements.iter().map(|(_, data)| 8 + data.len()).sum();
+            let mut prefix = [0; Self::TARGET_PREFIX_LENGTH];
+            prefix[..6].copy_from_slice(b"Target");
+            prefix[6] = target.alt;
+            if let Some(name) = &target.name {
+                // The name is zero terminated
+                let name = &name.as_bytes()[..name.len().min(254)];
+                prefix[7..11].copy_from_slice(&1u32.to_le_bytes());
+                prefix[11..11 + name.len()].copy_from_slice(name);
+            }
+            prefix[266..270].copy_from_slice(&(size as u32).to_le_bytes());
+            prefix[270..].copy_from_slice(&(target.elements.len() as u32).to_le_bytes());
+            image.extend_from_slice(&prefix);
+            for (address, data) in &target.elements {
+                image.extend_from_slice(&address.to_le_bytes());
+                image.extend_from_slice(&(data.len() as u32).to_le_bytes());
+                image.extend_from_slice(data);
+            }
+        }
+        let prefix = DfusePrefix::new(image.len() as u32, self.targets.len() as u8);
+        image[..DfusePrefix::LENGTH].copy_from_slice(&prefix.to_bytes());
+
+        suffix.dfu_version = 0x011a;
+        suffix.append_to(&mut image);
+        image
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn crc() {
+        for (data, expected) in [
+            (&b""[..], 0xffff_ffff),
+            (b"123456789", !0xcbf4_3926),
+            (b"\0", !0xd202_ef8d),
+        ] {
+            assert_eq!(crc32(data), expected, "{data:?}");
+        }
+        assert_eq!(crc32_update(crc32(b"1234"), b"56789"), crc32(b"123456789"));
+    }
+
+    #[test]
+    fn suffix_round_trip() {
+        let mut firmware = b"firmware".to_vec();
+        let suffix = DfuSuffix {
+            device_version: 0x0200,
+            ..DfuSuffix::new(0x0483, 0xdf11)
+        };
+        suffix.append_to(&mut firmware);
+        assert_eq!(firmware.len(), 8 + DfuSuffix::LENGTH);
+
+        let parsed = DfuSuffix::parse(&firmware).unwrap();
+        assert_eq!(
+            parsed,
+            DfuSuffix {
+                crc: crc32(&firmware[..firmware.len() - 4]),
+                ..suffix
+            }
+        );
+        assert!(parsed.crc_valid(&firmware));
+        assert_eq!(&parsed.to_bytes()[..], &firmware[8..]);
+
+        firmware[0] ^= 1;
+        assert!(!parsed.crc_valid(&firmware));
+    }
+
+    #[test]
+    fn suffix_parse_invalid() {
+        let mut firmware = Vec::new();
+        DfuSuffix::new(0x0483, 0xdf11).append_to(&mut firmware);
+        for (name, data) in [
+            ("too short", firmware[1..].to_vec()),
+            ("no signature", {
+                let mut data = firmware.clone();
+                data[8..11].copy_from_slice(b"DFU");
+                data
+            }),
+            ("short bLength", {
+                let mut data = firmware.clone();
+                data[11] = 15;
+                data
+            }),
+        ] {
+            assert_eq!(DfuSuffix::parse(&data), None, "{name}");
+        }
+    }
+
+    #[test]
+    fn suffix_matches() {
+        for ((vendor_id, product_id), expected) in [
+            ((0x0483, 0xdf11), true),
+            ((0x0483, 0x5740), false),
+            ((0x28e9, 0xdf11), false),
+        ] {
+            let suffix = DfuSuffix::new(0x0483, 0xdf11);
+            assert_eq!(suffix.matches(vendor_id, product_id), expected);
+        }
+        assert!(DfuSuffix::new(0xffff, 0xffff).matches(0x28e9, 0x0189));
+    }
+
+    #[test]
+    fn prefix_round_trip() {
+        let prefix = DfusePrefix::new(0x1234, 2);
+        assert_eq!(DfusePrefix::parse(&prefix.to_bytes()), Some(prefix));
+        assert_eq!(DfusePrefix::parse(&prefix.to_bytes()[..10]), None);
+        assert_eq!(DfusePrefix::parse(b"DfuSx\x01\0\0\0\0\x01"), None);
+    }
+}
@@ -0,0 +1,200 @@
+use std::time::Duration;
+
+use dfu_core::{DfuIo, State};
+use embedded_storage::nor_flash::{
+    check_erase, check_read, check_write, ErrorType, NorFlash, NorFlashError, NorFlashErrorKind,
+    ReadNorFlash,
+};
+
+use crate::{
+    DfuNusb, Error, DFUSE_ERASE, DFUSE_SET_ADDRESS, DFU_ABORT, DFU_DNLOAD, DFU_GETSTATUS,
+    DFU_UPLOAD, REQUEST_TYPE_IN, REQUEST_TYPE_OUT,
+};
+
+/// Error of a [`DfuFlash`] operation
+#[derive(Debug, thiserror::Error)]
+pub enum FlashError {
+    /// The request doesn't fit the flash geometry
+    #[error("Invalid flash access: {0}")]
+    Flash(NorFlashErrorKind),
+    /// Talking to the device failed
+    #[error(transparent)]
+    Dfu(#[from] Error),
+}
+
+impl NorFlashError for FlashError {
+    fn kind(&self) -> NorFlashErrorKind {
+        match self {
+            FlashError::Flash(kind) => *kind,
+            FlashError::Dfu(_) => NorFlashErrorKind::Other,
+        }
+    }
+}
+
+impl From<NorFlashErrorKind> for FlashError {
+    fn from(kind: NorFlashErrorKind) -> Self {
+        FlashError::Flash(kind)
+    }
+}
+
+/// [`embedded_storage`] flash backed by the memory of a DfuSe target
+///
+/// Offsets are relative to the start address of the target. Reads are done with uploads,
+/// erases with DfuSe page erase commands and writes with downloads. `ERASE_SIZE` is the erase
+/// granularity exposed to users of the traits; every page of the target must fit in an
+/// `ERASE_SIZE` aligned block.
+pub struct DfuFlash<const ERASE_SIZE: usize> {
+    dfu: DfuNusb,
+    base: u32,
+    pages: Vec<u32>,
+}
+
+impl<const ERASE_SIZE: usize> DfuFlash<ERASE_SIZE> {
+    /// Wrap a DfuSe device, checking its memory layout against `ERASE_SIZE`
+    pub fn new(dfu: DfuNusb) -> Result<Self, Error> {
+        let (Some(base), Some(layout)) = (dfu.default_address(), dfu.memory_layout()) else {
+            return Err(Error::DfuseRequired);
+        };
+        let pages = layout.to_vec();
+
+        let mut start = 0usize;
+        for &page in &pages {
+            if start % ERASE_SIZE + page as usize > ERASE_SIZE {
+                return Err(Error::FlashGeometry(format!(
+                    "page at {:#010x} crosses an erase block of {ERASE_SIZE} bytes",
+                    base as usize + start
+                )));
+            }
+            start += page as usize;
+        }
+
+        Ok(Self { dfu, base, pages })
+    }
+
+    /// Get back the wrapped device
+    pub fn into_inner(self) -> DfuNusb {
+        self.dfu
+    }
+
+    fn get_state(&self) -> Result<State, Error> {
+        let mut buffer = [0; 6];
+        loop {
+            let n = DfuIo::read_control(&self.dfu, REQUEST_TYPE_IN, DFU_GETSTATUS, 0, &mut buffer)?;
+            if n < buffer.len() {
+                return Err(dfu_core::Error::ResponseTooShort {
+                    got: n,
+                    expected: buffer.len(),
+                }
+                .into());
+            }
+            match State::from(buffer[4]) {
+                State::DfuDnbusy | State::DfuDnloadSync => {
+                    self.dfu
+                        .timer
+                        .sleep_blocking(Duration::from_millis(u64::from_le_bytes([
+                            buffer[1], buffer[2], buffer[3], 0, 0, 0, 0, 0,
+                        ])))
+                }
+                State::DfuError => {
+                    return Err(dfu_core::Error::StatusError(buffer[0].into()).into())
+                }
+                state => return Ok(state),
+            }
+        }
+    }
+
+    /// Send a DNLOAD request and wait for the device to process it
+    fn dnload(&self, block: u16, data: &[u8]) -> Result<(), Error> {
+        DfuIo::write_control(&self.dfu, REQUEST_TYPE_OUT, DFU_DNLOAD, block, data)?;
+        self.get_state()?;
+        Ok(())
+    }
+
+    fn abort(&self) -> Result<(), Error> {
+        DfuIo::write_control(&self.dfu, REQUEST_TYPE_OUT, DFU_ABORT, 0, &[])?;
+        match self.get_state()? {
+            State::DfuIdle => Ok(()),
+            state => Err(dfu_core::Error::InvalidState {
+                got: state,
+                expected: State::DfuIdle,
+            }
+            .into()),
+        }
+    }
+
+    fn set_address(&self, offset: u32) -> Result<(), Error> {
+        let mut command = [DFUSE_SET_ADDRESS, 0, 0, 0, 0];
+        command[1..].copy_from_slice(&(self.base + offset).to_le_bytes());
+        self.dnload(0, &command)
+    }
+
+    fn transfer_size(&self) -> usize {
+        usize::from(self.dfu.descriptor.transfer_size)
+    }
+}
+
+impl<const ERASE_SIZE: usize> ErrorType for DfuFlash<ERASE_SIZE> {
+    type Error = FlashError;
+}
+
+impl<const ERASE_SIZE: usize> ReadNorFlash for DfuFlash<ERASE_SIZE> {
+    const READ_SIZE: usize = 1;
+
+    fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+        check_read(self, offset, bytes.len())?;
+        self.abort()?;
+        self.set_address(offset)?;
+        self.abort()?;
+
+        // Block numbers 0 and 1 are reserved for DfuSe commands
+        for (block, chunk) in (2..).zip(bytes.chunks_mut(self.transfer_size())) {
+            let n = DfuIo::read_control(&self.dfu, REQUEST_TYPE_IN, DFU_UPLOAD, block, chunk)?;
+            if n < chunk.len() {
+                return Err(Error::from(dfu_core::Error::ResponseTooShort {
+                    got: n,
+                    expected: chunk.len(),
+                })
+                .into());
+            }
+        }
+        self.abort()?;
+        Ok(())
+    }
+
+    fn capacity(&self) -> usize {
+        self.pages.iter().map(|&page| page as usize).sum()
+    }
+}
+
+impl<const ERASE_SIZE: usize> NorFlash for DfuFlash<ERASE_SIZE> {
+    const WRITE_SIZE: usize = 1;
+    const ERASE_SIZE: usize = ERASE_SIZE;
+
+    fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error> {
+        check_erase(self, from, to)?;
+        self.abort()?;
+
+        let mut start = 0u32;
+        for &page in &self.pages {
+            if (from..to).contains(&start) {
+                let mut command = [DFUSE_ERASE, 0, 0, 0, 0];
+                command[1..].copy_from_slice(&(self.base + start).to_le_bytes());
+                self.dnload(0, &command)?;
+            }
+            start += page;
+        }
+        self.abort()?;
+        Ok(())
+    }
+
+    fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+        check_write(self, offset, bytes.len())?;
+        self.abort()?;
+        self.set_address(offset)?;
+        for (block, chunk) in (2..).zip(bytes.chunks(self.transfer_size())) {
+            self.dnload(block, chunk)?;
+        }
+        self.abort()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,519 @@
+use std::time::{Duration, Instant};
+
+use crate::file::{DfuSuffix, DfusePrefix};
+use crate::session::JsonFields;
+use crate::source::Checked;
+use crate::{
+    info, list_devices, BandwidthBudget, Bootloader, DeviceFilter, DeviceIdentity, DfuDeviceInfo,
+    DfuNusb, DownloadReport, Error, ErrorKind, FinalStatus, FirmwareInfo, FirmwareSource,
+    MatchStrategy, OpenOptions, ProgressGroup, SessionLog, Timer,
+};
+
+/// Interval at which the device list is checked while waiting for a replug
+const REPLUG_POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// What to do with the device once the firmware was written
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Finalize {
+    /// Leave the device as the download left it
+    #[default]
+    None,
+    /// Reset the device
+    Reset,
+    /// Send a DFU_DETACH followed by a reset, which some bootloaders (e.g. u-boot) need
+    /// before starting the new firmware
+    DetachAndReset,
+}
+
+/// Options of [`flash_and_verify`]
+#[derive(Debug, Clone, Default)]
+pub struct FlashOptions {
+    open: OpenOptions,
+    address: Option<u32>,
+    skip_erase: bool,
+    verify: bool,
+    force: bool,
+    finalize: Finalize,
+    replug_attempts: u32,
+    match_strategy: MatchStrategy,
+    unprotect: bool,
+    budget: Option<BandwidthBudget>,
+    tolerant_completion: Option<bool>,
+    group: Option<ProgressGroup>,
+    session: Option<SessionLog>,
+}
+
+impl FlashOptions {
+    /// Create options writing to interface 0, alternative setting 0 without verification
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set how the DFU interface is opened
+    pub fn open_options(mut self, open: OpenOptions) -> Self {
+        self.open = open;
+        self
+    }
+
+    /// Override the address raw DfuSe firmware is written to
+    pub fn address(mut self, address: u32) -> Self {
+        self.address = Some(address);
+        self
+    }
+
+    /// Don't erase before writing, see [`DfuNusb::skip_erase`](crate::DfuNusb::skip_erase)
+    pub fn skip_erase(mut self, skip: bool) -> Self {
+        self.skip_erase = skip;
+        self
+    }
+
+    /// Read back the firmware, see [`DfuNusb::verify`](crate::DfuNusb::verify)
+    pub fn verify(mut self, verify: bool) -> Self {
+        self.verify = verify;
+        self
+    }
+
+    /// Bypass safety checks, see [`DfuNusb::force`](crate::DfuNusb::force)
+    pub fn force(mut self, force: bool) -> Self {
+        self.force = force;
+        self
+    }
+
+    /// Set what to do once the firmware was written
+    ///
+    /// [`Finalize::Reset`] becomes whatever the recognised bootloader needs, see
+    /// [`Bootloader::finalize`].
+    pub fn finalize(mut self, finalize: Finalize) -> Self {
+        self.finalize = finalize;
+        self
+    }
+
+    /// Recover from up to `attempts` disconnections during the download
+    ///
+    /// When the device drops off the bus, e.g. through a marginal cable, the same device is
+    /// waited for (up to the [`Timeouts::reset`](crate::Timeouts::reset) of the
+    /// [`FlashOptions::open_options`], recognised according to `strategy`), re-opened, brought
+    /// back to dfuIDLE and the download is restarted from the beginning. Disabled by default.
+    pub fn replug_recovery(mut self, attempts: u32, strategy: MatchStrategy) -> Self {
+        self.replug_attempts = attempts;
+        self.match_strategy = strategy;
+        self
+    }
+
+    /// Remove the flash protection of DfuSe devices refusing to write
+    ///
+    /// When the download to a DfuSe target fails with errWRITE (see
+    /// [`Error::is_write_protected`]), the protection is removed with
+    /// [`DfuNusb::unprotect`](crate::DfuNusb::unprotect), the device is waited for to leave the
+    /// bus and re-enumerate (recognised as set by [`FlashOptions::replug_recovery`]) and the
+    /// download is restarted. Disabled by default.
+    ///
+    /// This is destructive: removing the protection mass erases the whole flash, including
+    /// anything outside the firmware like settings or calibration data, and a sector failing
+    /// to write for another reason, e.g. worn out flash, triggers it as well.
+    pub fn unprotect(mut self, unprotect: bool) -> Self {
+        self.unprotect = unprotect;
+        self
+    }
+
+    /// Share the bandwidth with the other devices flashed concurrently, see
+    /// [`BandwidthBudget`]
+    pub fn bandwidth_budget(mut self, budget: BandwidthBudget) -> Self {
+        self.budget = Some(budget);
+        self
+    }
+
+    /// Count a timeout of the status requests after the last block as success, for
+    /// bootloaders starting the firmware without answering them, see [`FinalStatus`]
+    ///
+    /// `false` requires the status to be answered, overriding the bootloader quirks.
+    pub fn tolerant_completion(mut self, tolerant: bool) -> Self {
+        self.tolerant_completion = Some(tolerant);
+        self
+    }
+
+    /// Report the progress to `group`, under the port of the device, to follow all devices
+    /// flashed concurrently as one, see [`ProgressGroup`]
+    ///
+    /// The device is finished in the group once [`flash_and_verify`] returns; close the group
+    /// with [`ProgressGroup::close`] once every device was started.
+    pub fn progress_group(mut self, group: ProgressGroup) -> Self {
+        self.group = Some(group);
+        self
+    }
+
+    /// Record the operations on the device in an audit log, see [`SessionLog`]
+    ///
+    /// The log follows the device when it is reopened after removing the protection or a
+    /// replug, which are logged as `recovery` events.
+    pub fn session_log(mut self, log: SessionLog) -> Self {
+        self.session = Some(log);
+        self
+    }
+}
+
+/// Summary of a [`flash_and_verify`] run
+#[derive(Debug)]
+pub struct FlashReport {
+    /// Physical identity of the flashed device
+    pub identity: DeviceIdentity,
+    /// Name of the alternative setting written to
+    pub alt_name: String,
+    /// Address the firmware was written to, for raw DfuSe firmware
+    pub address: Option<u32>,
+    /// Number of bytes written, excluding the DFU suffix
+    pub size: usize,
+    /// Per-element results, for DfuSe images
+    pub image: Option<DownloadReport>,
+    /// Whether the firmware was read back, or checked by the device, and matched
+    pub verified: bool,
+    /// Finalization done after the download
+    pub finalize: Finalize,
+    /// Number of times the download was restarted after the device was replugged
+    pub recoveries: u32,
+    /// Whether the flash protection had to be removed
+    pub unprotected: bool,
+    /// Time taken by the whole operation
+    pub elapsed: Duration,
+}
+
+impl FlashReport {
+    /// Returns whether the whole firmware was written
+    ///
+    /// This is only `false` for DfuSe images of which some elements failed.
+    pub fn is_complete(&self) -> bool {
+        self.image
+            .as_ref()
+            .map_or(true, DownloadReport::is_complete)
+    }
+}
+
+/// Write `firmware` to the device matching `filter`
+///
+/// This performs the usual sequence of finding and opening the device, checking the DFU
+/// suffix and size of the firmware, erasing, downloading, verifying and finalizing. DfuSe
+/// images are written element by element, see
+/// [`DfuNusb::download_image`](crate::DfuNusb::download_image). Fails with
+/// [`Error::MultipleDevices`] rather than guessing if more than one device matches.
+///
+/// Raw firmware is streamed from the source into the device, like
+/// [`DfuNusb::download_source`](crate::DfuNusb::download_source) does, so its DFU suffix is
+/// only checked once the end of the source was read, before the download is completed. The
+/// firmware is read into memory first if it is a DfuSe image, or if removing the protection
+/// or a replug may require writing it again.
+pub async fn flash_and_verify<S: FirmwareSource>(
+    filter: &DeviceFilter,
+    firmware: S,
+    options: &FlashOptions,
+) -> Result<FlashReport, Error> {
+    let start = Instant::now();
+    let mut devices = list_devices(filter)?;
+    let info = match devices.len() {
+        0 => return Err(Error::DeviceNotFound),
+        1 => devices.pop().unwrap(),
+        n => return Err(Error::MultipleDevices(n)),
+    };
+
+    let identity = info.identity();
+    let mut address = info.info().device_address();
+    let firmware =
+        Firmware::read(firmware, options.unprotect || options.replug_attempts > 0).await?;
+    // Bytes actually written, without the suffix and the DfuSe prefixes
+    let payload_size = match &firmware {
+        Firmware::Buffered(firmware) => Some(FirmwareInfo::parse(firmware)?.payload_size as u64),
+        Firmware::Streamed { source, .. } => source.length_hint().map(u64::from),
+    };
+    // Held across replugs, so the device isn't seen as finished while it is reopened
+    let _member = options
+        .group
+        .as_ref()
+        .map(|group| group.member(info.port(), payload_size));
+    let mut device = open(&info, options, payload_size)?;
+    let timeouts = options.open.timeouts;
+    let mut recoveries = 0;
+    let mut unprotected = false;
+    let (image, size) = match firmware {
+        Firmware::Streamed { source, head } => {
+            let mut stream = Streamed {
+                device: &device,
+                source,
+                held: head,
+                end: false,
+                size: 0,
+            };
+            device.download_source(&mut stream).await?;
+            (None, stream.size)
+        }
+        Firmware::Buffered(firmware) => loop {
+            let result = write(&mut device, &firmware).await;
+            // The device mass erases its flash before resetting
+            let leave = if options.unprotect
+                && !unprotected
+                && device.is_dfuse()
+                && failed_with(&result, Error::is_write_protected)
+            {
+                info!("Device is write protected, removing the protection");
+                unprotected = true;
+                device.log_recovery("unprotect", JsonFields::default());
+                device.unprotect().await?;
+                timeouts.mass_erase
+            } else if recoveries < options.replug_attempts
+                && failed_with(&result, |e| e.kind() == ErrorKind::Disconnected)
+            {
+                recoveries += 1;
+                info!(
+                    "Device disconnected, waiting for it to come back (recovery {recoveries} of {})",
+                    options.replug_attempts
+                );
+                let fields = JsonFields::default().number("attempt", Some(recoveries.into()));
+                device.log_recovery("replug", fields);
+                timeouts.reset
+            } else {
+                break result?;
+            };
+            drop(device);
+            let timer = options.open.timer_or_default();
+            let info = wait_for_replug(
+                &identity,
+                address,
+                options.match_strategy,
+                &*timer,
+                leave,
+                timeouts.reset,
+            )
+            .await?;
+            address = info.info().device_address();
+            device = open(&info, options, payload_size)?;
+            device.ensure_idle().await?;
+        },
+    };
+
+    let alt_name = device.alt_name().to_string();
+    let address = image.is_none().then(|| device.address()).flatten();
+    let verified = match &image {
+        Some(image) => image.is_complete() && image.elements.iter().all(|e| e.verified),
+        None => device.verified(),
+    };
+    // Don't start a partially written firmware
+    let finalize = match options.finalize {
+        _ if !image.as_ref().map_or(true, DownloadReport::is_complete) => Finalize::None,
+        Finalize::Reset => device
+            .bootloader()
+            .map_or(Finalize::Reset, Bootloader::finalize),
+        finalize => finalize,
+    };
+    if finalize != Finalize::None {
+        let dfu = device.into_async_dfu();
+        if finalize == Finalize::DetachAndReset {
+            // Not strictly meant to be sent after a download, see the download example
+            let _ = dfu.detach().await;
+        }
+        dfu.usb_reset().await?;
+    }
+
+    Ok(FlashReport {
+        identity,
+        alt_name,
+        address,
+        size,
+        image,
+        verified,
+        finalize,
+        recoveries,
+        unprotected,
+        elapsed: start.elapsed(),
+    })
+}
+
+/// Firmware given to [`flash_and_verify`]
+enum Firmware<S> {
+    /// Read into memory, for DfuSe images and downloads which may have to start over
+    Buffered(Vec<u8>),
+    /// Raw firmware, written once as it is read; `head` was read to tell it apart from a
+    /// DfuSe image
+    Streamed { source: Checked<S>, head: Vec<u8> },
+}
+
+impl<S: FirmwareSource> Firmware<S> {
+    /// Read enough of `source` to recognise DfuSe images, or all of it with `buffer`
+    async fn read(source: S, buffer: bool) -> Result<Self, Error> {
+        let mut source = Checked::new(source);
+        let mut head = Vec::new();
+        let mut chunk = vec![0; 4096];
+        loop {
+            if !buffer && head.len() >= DfusePrefix::LENGTH && DfusePrefix::parse(&head).is_none() {
+                return Ok(Firmware::Streamed { source, head });
+            }
+            let n = source.read_chunk(&mut chunk).await?;
+            if n == 0 {
+                return Ok(Firmware::Buffered(head));
+            }
+            head.extend_from_slice(&chunk[..n]);
+        }
+    }
+}
+
+/// Raw firmware streamed into the device, without its DFU suffix
+///
+/// The suffix is only known once the end of the source was read, so the bytes which may be
+/// part of it are held back until then. The length hint of the source, which includes the
+/// suffix, is passed on as an upper bound.
+struct Streamed<'a, S> {
+    device: &'a DfuNusb,
+    source: Checked<S>,
+    /// Bytes read from the source but not handed out yet
+    held: Vec<u8>,
+    /// Whether the end of the source was read and the suffix removed
+    end: bool,
+    /// Bytes handed out
+    size: usize,
+}
+
+impl<S: FirmwareSource> Streamed<'_, S> {
+    /// Longest DFU suffix, as its length is a byte
+    const MAX_SUFFIX: usize = u8::MAX as usize;
+
+    /// Check the DFU suffix at the end of the held bytes and drop it
+    fn finish(&mut self) -> Result<(), Error> {
+        self.end = true;
+        if let Some(suffix) = DfuSuffix::parse(&self.held) {
+            let length = usize::from(suffix.length);
+            if length > self.held.len() {
+                return Err(Error::InvalidFirmware("truncated DFU suffix".to_string()));
+            }
+            self.device.check_suffix(&suffix)?;
+            self.held.truncate(self.held.len() - length);
+        }
+        Ok(())
+    }
+}
+
+impl<S: FirmwareSource> FirmwareSource for Streamed<'_, S> {
+    fn length_hint(&self) -> Option<u32> {
+        self.source.length_hint()
+    }
+
+    async fn read_chunk(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        while !self.end && self.held.len() <= Self::MAX_SUFFIX {
+            let level = self.held.len();
+            self.held.resize(level + buf.len().max(Self::MAX_SUFFIX), 0);
+            let n = self.source.read_chunk(&mut self.held[level..]).await?;
+            self.held.truncate(level + n);
+            if n == 0 {
+                self.finish()?;
+            }
+        }
+        let available = match self.end {
+            true => self.held.len(),
+            false => self.held.len() - Self::MAX_SUFFIX,
+        };
+        let n = buf.len().min(available);
+        buf[..n].copy_from_slice(&self.held[..n]);
+        self.held.drain(..n);
+        self.size += n;
+        Ok(n)
+    }
+}
+
+fn open(info: &DfuDeviceInfo, options: &FlashOptions, size: Option<u64>) -> Result<DfuNusb, Error> {
+    let mut device = options.open.open(info)?;
+    if let Some(group) = &options.group {
+        // A replugged device starts over under the same name
+        device = device.with_progress(group.member(info.port(), size));
+    }
+    if let Some(log) = &options.session {
+        device = device.with_session_log(log.clone());
+    }
+    device
+        .force(options.force)
+        .skip_erase(options.skip_erase)
+        .verify(options.verify);
+    if let Some(address) = options.address {
+        device.override_address(address);
+    }
+    if let Some(budget) = &options.budget {
+        device.bandwidth_budget(budget.clone());
+    }
+    if let Some(tolerant) = options.tolerant_completion {
+        device.final_status_quirk(if tolerant {
+            FinalStatus::TolerateTimeout
+        } else {
+            FinalStatus::Required
+        });
+    }
+    Ok(device)
+}
+
+/// Write the firmware, returning the report for DfuSe images and the size written
+async fn write(
+    device: &mut DfuNusb,
+    firmware: &[u8],
+) -> Result<(Option<DownloadReport>, usize), Error> {
+    let suffix = DfuSuffix::parse(firmware);
+    if let Some(suffix) = &suffix {
+        device.check_suffix(suffix)?;
+    }
+
+    if DfusePrefix::parse(firmware).is_some() {
+        let report = device.download_image(firmware).await?;
+        let size = report.elements.iter().map(|e| e.size as usize).sum();
+        Ok((Some(report), size))
+    } else {
+        let payload = match &suffix {
+            Some(suffix) => &firmware[..firmware.len() - usize::from(suffix.length)],
+            None => firmware,
+        };
+        let length =
+            u32::try_from(payload.len()).map_err(|_| dfu_core::Error::OutOfCapabilities)?;
+        device.download(payload, length).await?;
+        Ok((None, payload.len()))
+    }
+}
+
+/// Returns whether writing failed with an error matching `predicate`, including for single
+/// elements of a DfuSe image
+fn failed_with(
+    result: &Result<(Option<DownloadReport>, usize), Error>,
+    predicate: impl Fn(&Error) -> bool,
+) -> bool {
+    match result {
+        Err(e) => predicate(e),
+        Ok((Some(report), _)) => report.failure().is_some_and(|(_, e)| predicate(e)),
+        Ok((None, _)) => false,
+    }
+}
+
+/// Wait up to `leave` for the device at `address` to leave the bus, then up to `timeout` for
+/// it to re-enumerate
+///
+/// A device found again at another address re-enumerated already; one found at the same
+/// address may be the old one about to reset, which mustn't be opened.
+async fn wait_for_replug(
+    identity: &DeviceIdentity,
+    address: u8,
+    strategy: MatchStrategy,
+    timer: &dyn Timer,
+    leave: Duration,
+    timeout: Duration,
+) -> Result<DfuDeviceInfo, Error> {
+    let mut start = timer.now();
+    let mut left = false;
+    loop {
+        match identity.find(strategy)? {
+            Some(info) if left || info.info().device_address() != address => return Ok(info),
+            Some(_) => (),
+            None if !left => {
+                left = true;
+                start = timer.now();
+            }
+            None => (),
+        }
+        if timer.now().saturating_duration_since(start) >= if left { timeout } else { leave } {
+            if !left {
+                info!("Device didn't leave the bus");
+            }
+            return Err(Error::DeviceNotFound);
+        }
+        timer.sleep(REPLUG_POLL_INTERVAL).await;
+    }
+}
@@ -0,0 +1,265 @@
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+
+use futures::Stream;
+
+use crate::{Phase, Progress, ProgressHandler};
+
+/// Progress of several devices flashed at the same time, aggregated for a single dashboard
+///
+/// Clones share the same group, like [`BandwidthBudget`](crate::BandwidthBudget). Attach a
+/// [`ProgressGroup::member`] to every device with
+/// [`DfuNusb::with_progress`](crate::DfuNusb::with_progress), or set the group through
+/// [`FlashOptions::progress_group`](crate::FlashOptions::progress_group), and follow the
+/// whole rack through [`ProgressGroup::subscribe`] until the group is
+/// [closed](ProgressGroup::close) and every device finished:
+///
+/// ```
+/// use dfu_nusb::{Phase, Progress, ProgressGroup, ProgressHandler};
+///
+/// let group = ProgressGroup::new();
+/// let mut first = group.member("1-1.1", Some(1024));
+/// let mut second = group.member("1-1.2", Some(1024));
+/// first.progress(Progress::Phase(Phase::Download));
+/// first.progress(Progress::Written(1024));
+/// second.progress(Progress::Written(256));
+///
+/// let progress = group.snapshot();
+/// assert_eq!((progress.written(), progress.total()), (1280, Some(2048)));
+/// assert_eq!(progress.slowest().unwrap().name, "1-1.2");
+///
+/// // No more devices are coming; streams end once the members are dropped
+/// group.close();
+/// drop((first, second));
+/// let last = futures::executor::block_on_stream(group.subscribe()).last();
+/// assert!(last.unwrap().is_finished());
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct ProgressGroup {
+    shared: Arc<Mutex<Shared>>,
+}
+
+#[derive(Debug, Default)]
+struct Shared {
+    devices: Vec<DeviceProgress>,
+    /// Number of [`GroupMember`]s alive for each device
+    members: Vec<usize>,
+    /// Incremented on every change, so streams know whether they are up to date
+    version: u64,
+    /// Set by [`ProgressGroup::close`]
+    closed: bool,
+    wakers: Vec<Waker>,
+}
+
+impl Shared {
+    fn changed(&mut self) {
+        self.version += 1;
+        for waker in self.wakers.drain(..) {
+            waker.wake();
+        }
+    }
+}
+
+/// Progress of one device of a [`ProgressGroup`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeviceProgress {
+    /// Name the device was added with, e.g. its port
+    pub name: String,
+    /// Current phase of the device
+    pub phase: Option<Phase>,
+    /// Bytes written to the device
+    pub written: u64,
+    /// Bytes read from the device, e.g. to verify it
+    pub read: u64,
+    /// Number of bytes to write, if known
+    pub total: Option<u64>,
+    /// Whether the device is done, as every [`GroupMember`] of it was dropped
+    pub finished: bool,
+}
+
+impl DeviceProgress {
+    /// Fraction of the firmware written, if its size is known
+    pub fn fraction(&self) -> Option<f64> {
+        self.total
+            .map(|total| self.written as f64 / total.max(1) as f64)
+    }
+}
+
+/// Aggregated progress of a [`ProgressGroup`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GroupProgress {
+    /// Progress of each device, in the order they were added
+    pub devices: Vec<DeviceProgress>,
+}
+
+impl GroupProgress {
+    /// Bytes written to all devices
+    pub fn written(&self) -> u64 {
+        self.devices.iter().map(|d| d.written).sum()
+    }
+
+    /// Bytes read from all devices
+    pub fn read(&self) -> u64 {
+        self.devices.iter().map(|d| d.read).sum()
+    }
+
+    /// Bytes to write to all devices, if known for every device
+    pub fn total(&self) -> Option<u64> {
+        self.devices.iter().map(|d| d.total).sum()
+    }
+
+    /// Device which isn't finished and is the furthest behind, by fraction of its firmware
+    /// written or, if the size isn't known, by bytes written
+    pub fn slowest(&self) -> Option<&DeviceProgress> {
+        self.devices.iter().filter(|d| !d.finished).min_by(|a, b| {
+            match (a.fraction(), b.fraction()) {
+                (Some(a), Some(b)) => a.total_cmp(&b),
+                _ => a.written.cmp(&b.written),
+            }
+        })
+    }
+
+    /// Returns whether all devices are finished
+    pub fn is_finished(&self) -> bool {
+        self.devices.iter().all(|d| d.finished)
+    }
+}
+
+impl ProgressGroup {
+    /// Create an empty group
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a device named `name` which will get `total` bytes written, returning the
+    /// [`ProgressHandler`] to attach to it
+    ///
+    /// Adding a device with the name of one added before starts it over, e.g. when a
+    /// download is restarted after the device was replugged. A device is finished once all
+    /// its members were dropped, so holding one across a restart keeps the device from being
+    /// seen as finished in between.
+    pub fn member(&self, name: impl Into<String>, total: Option<u64>) -> GroupMember {
+        let name = name.into();
+        let mut shared = self.shared.lock().unwrap();
+        let progress = DeviceProgress {
+            name: name.clone(),
+            phase: None,
+            written: 0,
+            read: 0,
+            total,
+            finished: false,
+        };
+        let index = match shared.devices.iter().position(|d| d.name == name) {
+            Some(index) => {
+                shared.devices[index] = progress;
+                shared.members[index] += 1;
+                index
+            }
+            None => {
+                shared.devices.push(progress);
+                shared.members.push(1);
+                shared.devices.len() - 1
+            }
+        };
+        shared.changed();
+        GroupMember {
+            shared: self.shared.clone(),
+            index,
+        }
+    }
+
+    /// Current progress of the group
+    pub fn snapshot(&self) -> GroupProgress {
+        GroupProgress {
+            devices: self.shared.lock().unwrap().devices.clone(),
+        }
+    }
+
+    /// Declare that no more devices will be added to the group
+    ///
+    /// Streams of the group end once it is closed and every device finished. Devices may
+    /// finish and others start at any time before that, e.g. in a rack flashed in several
+    /// rounds.
+    pub fn close(&self) {
+        let mut shared = self.shared.lock().unwrap();
+        shared.closed = true;
+        shared.changed();
+    }
+
+    /// Follow the progress of the group
+    ///
+    /// The stream yields the current progress right away and then whenever it changed;
+    /// changes made while the consumer is busy are merged into a single item, so a slow
+    /// dashboard never holds up the downloads. It ends once the group was
+    /// [closed](ProgressGroup::close) and every device finished.
+    pub fn subscribe(&self) -> GroupStream {
+        GroupStream {
+            shared: self.shared.clone(),
+            seen: None,
+        }
+    }
+}
+
+/// [`ProgressHandler`] of one device of a [`ProgressGroup`]
+#[derive(Debug)]
+pub struct GroupMember {
+    shared: Arc<Mutex<Shared>>,
+    index: usize,
+}
+
+impl ProgressHandler for GroupMember {
+    fn progress(&mut self, progress: Progress) {
+        let mut shared = self.shared.lock().unwrap();
+        let device = &mut shared.devices[self.index];
+        match progress {
+            Progress::Phase(phase) => device.phase = Some(phase),
+            Progress::Written(n) => device.written += n as u64,
+            Progress::Read(n) => device.read += n as u64,
+            Progress::Erase { .. } | Progress::Busy(_) => return,
+        }
+        shared.changed();
+    }
+}
+
+impl Drop for GroupMember {
+    fn drop(&mut self) {
+        let mut shared = self.shared.lock().unwrap();
+        shared.members[self.index] -= 1;
+        if shared.members[self.index] == 0 {
+            shared.devices[self.index].finished = true;
+        }
+        shared.changed();
+    }
+}
+
+/// Stream returned by [`ProgressGroup::subscribe`]
+#[derive(Debug)]
+pub struct GroupStream {
+    shared: Arc<Mutex<Shared>>,
+    seen: Option<u64>,
+}
+
+impl Stream for GroupStream {
+    type Item = GroupProgress;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<GroupProgress>> {
+        let mut shared = self.shared.lock().unwrap();
+        if self.seen != Some(shared.version) {
+            let progress = GroupProgress {
+                devices: shared.devices.clone(),
+            };
+            let version = shared.version;
+            drop(shared);
+            self.seen = Some(version);
+            return Poll::Ready(Some(progress));
+        }
+        if shared.closed && shared.members.iter().all(|&n| n == 0) {
+            return Poll::Ready(None);
+        }
+        if !shared.wakers.iter().any(|w| w.will_wake(cx.waker())) {
+            shared.wakers.push(cx.waker().clone());
+        }
+        Poll::Pending
+    }
+}
@@ -0,0 +1,127 @@
+use std::fmt;
+use std::future::Future;
+
+use futures::future::BoxFuture;
+
+use crate::{warning, DeviceIdentity, DfuNusb, Error};
+
+/// Error returned by a [`Hook`]
+pub type HookError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Point of a download at which [`Hook`]s run
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum HookPoint {
+    /// Before the first page is erased, for DfuSe devices
+    BeforeErase,
+    /// Before the first block of firmware is sent
+    BeforeFirstWrite,
+    /// Once the device left DFU mode or was reset at the end of the download
+    AfterManifest,
+    /// Once the firmware written was successfully read back or checked by the device
+    AfterVerify,
+}
+
+impl fmt::Display for HookPoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            HookPoint::BeforeErase => "before erase",
+            HookPoint::BeforeFirstWrite => "before first write",
+            HookPoint::AfterManifest => "after manifest",
+            HookPoint::AfterVerify => "after verify",
+        })
+    }
+}
+
+/// Device and download a [`Hook`] runs for
+#[derive(Debug, Clone)]
+pub struct HookContext {
+    /// Point of the download the hook runs at
+    pub point: HookPoint,
+    /// Physical identity of the device, if it was opened through [`OpenOptions::open`](crate::OpenOptions::open)
+    pub identity: Option<DeviceIdentity>,
+    /// Vendor and product IDs of the device
+    pub device_ids: Option<(u16, u16)>,
+    /// Serial number string of the device
+    pub serial: Option<String>,
+    /// Alternative setting written to
+    pub alt: u8,
+    /// Name of the alternative setting
+    pub alt_name: String,
+    /// Start address of the download, for DfuSe devices
+    pub address: Option<u32>,
+    /// Size of the firmware, if known up-front
+    pub length: Option<u32>,
+}
+
+/// Caller provided step run at a [`HookPoint`] of every download
+///
+/// Implemented for async closures taking a [`HookContext`], e.g. to toggle a boot pin through
+/// a GPIO expander before the first write or to notify a backend once the firmware was
+/// verified. The download waits for the hook. Hooks running before the firmware is written
+/// fail the download with [`Error::Hook`]; once it was written, at
+/// [`HookPoint::AfterManifest`] and [`HookPoint::AfterVerify`], the download succeeds anyway
+/// and the failures are kept for [`DfuNusb::take_hook_failures`].
+pub trait Hook: Send + Sync {
+    /// Run the hook
+    fn run(&self, context: HookContext) -> BoxFuture<'static, Result<(), HookError>>;
+}
+
+impl<F, Fut> Hook for F
+where
+    F: Fn(HookContext) -> Fut + Send + Sync,
+    Fut: Future<Output = Result<(), HookError>> + Send + 'static,
+{
+    fn run(&self, context: HookContext) -> BoxFuture<'static, Result<(), HookError>> {
+        Box::pin(self(context))
+    }
+}
+
+impl DfuNusb {
+    /// Run `hook` at `point` of every download, see [`Hook`]
+    ///
+    /// Can be called repeatedly; hooks registered for the same point run in order.
+    pub fn with_hook(mut self, point: HookPoint, hook: impl Hook + 'static) -> Self {
+        self.hooks.push((point, Box::new(hook)));
+        self
+    }
+
+    /// Take the [`Error::Hook`]s of the hooks which failed after the firmware was written by
+    /// the last download, see [`Hook`]
+    pub fn take_hook_failures(&self) -> Vec<Error> {
+        std::mem::take(&mut *self.hook_failures.lock().unwrap())
+    }
+
+    /// Start a download, forgetting the hook failures of the last one
+    pub(crate) fn reset_hook_failures(&self) {
+        self.hook_failures.lock().unwrap().clear();
+    }
+
+    /// Run the hooks registered for `point` once the firmware was written, keeping failures
+    /// for [`DfuNusb::take_hook_failures`] rather than failing the download
+    pub(crate) async fn run_late_hooks(&self, point: HookPoint, length: u32) {
+        if let Err(e) = self.run_hooks(point, length).await {
+            warning!("{e}, the firmware was written");
+            self.hook_failures.lock().unwrap().push(e);
+        }
+    }
+
+    /// Run the hooks registered for `point` of a download of `length` bytes
+    pub(crate) async fn run_hooks(&self, point: HookPoint, length: u32) -> Result<(), Error> {
+        for (_, hook) in self.hooks.iter().filter(|(p, _)| *p == point) {
+            let context = HookContext {
+                point,
+                identity: self.identity.clone(),
+                device_ids: self.device_ids,
+                serial: self.serial.clone(),
+                alt: self.alt,
+                alt_name: self.alt_name.clone(),
+                address: self.address(),
+                length: (length != u32::MAX).then_some(length),
+            };
+            hook.run(context)
+                .await
+                .map_err(|source| Error::Hook { point, source })?;
+        }
+        Ok(())
+    }
+}
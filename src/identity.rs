@@ -0,0 +1,106 @@
+use crate::{list_devices, DeviceFilter, DfuDeviceInfo, Error};
+
+/// How to recognise a device again after it re-enumerated
+///
+/// Devices may come back with a different vendor and product id after a detach, so those
+/// aren't taken into account, unless the expected ones were declared with
+/// [`DeviceFilter::reenumerates_as`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum MatchStrategy {
+    /// Same physical port; works for identical boards without serial numbers
+    #[default]
+    Port,
+    /// Same serial number, wherever the device is plugged in
+    Serial,
+    /// Same physical port and serial number
+    PortAndSerial,
+}
+
+/// Physical identity of a device, used to find it again after a re-enumeration
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeviceIdentity {
+    port: String,
+    serial: Option<String>,
+    device_ids: (u16, u16),
+    expected_ids: Option<(u16, u16)>,
+    previous_ids: Option<(u16, u16)>,
+}
+
+impl DeviceIdentity {
+    /// Identity of an enumerated device
+    pub fn of(info: &DfuDeviceInfo) -> Self {
+        Self {
+            port: info.port(),
+            serial: info.serial().map(str::to_string),
+            device_ids: info.device_ids(),
+            expected_ids: info.expected_ids,
+            previous_ids: info.previous_ids,
+        }
+    }
+
+    /// Physical port of the device, see [`DfuDeviceInfo::port`]
+    pub fn port(&self) -> &str {
+        &self.port
+    }
+
+    /// Serial number of the device, if any
+    pub fn serial(&self) -> Option<&str> {
+        self.serial.as_deref()
+    }
+
+    /// Vendor and product id of the device when it was identified
+    pub fn device_ids(&self) -> (u16, u16) {
+        self.device_ids
+    }
+
+    /// Vendor and product id of the device before it re-enumerated with different ones
+    pub fn previous_ids(&self) -> Option<(u16, u16)> {
+        self.previous_ids
+    }
+
+    /// Check whether `info` is the same physical device according to `strategy`
+    ///
+    /// A device without serial number never matches when the strategy uses serial numbers.
+    pub fn matches(&self, info: &DfuDeviceInfo, strategy: MatchStrategy) -> bool {
+        let same_port = || info.port() == self.port;
+        let same_serial = || self.serial.is_some() && info.serial() == self.serial.as_deref();
+        match strategy {
+            MatchStrategy::Port => same_port(),
+            MatchStrategy::Serial => same_serial(),
+            MatchStrategy::PortAndSerial => same_port() && same_serial(),
+        }
+    }
+
+    /// Find the device among the currently connected DFU devices
+    ///
+    /// When the ids the device re-enumerates with were declared with
+    /// [`DeviceFilter::reenumerates_as`], a device with other ids fails with
+    /// [`Error::UnexpectedDevice`] rather than being taken for this one. The identity of the
+    /// device found keeps the ids it had before in
+    /// [`DeviceIdentity::previous_ids`], and accepts going back to them.
+    pub fn find(&self, strategy: MatchStrategy) -> Result<Option<DfuDeviceInfo>, Error> {
+        let Some(mut info) = list_devices(&DeviceFilter::new())?
+            .into_iter()
+            .find(|info| self.matches(info, strategy))
+        else {
+            return Ok(None);
+        };
+        let ids = info.device_ids();
+        if ids == self.device_ids {
+            info.expected_ids = self.expected_ids;
+            info.previous_ids = self.previous_ids;
+        } else if self.expected_ids.map_or(true, |expected| expected == ids)
+            || self.previous_ids == Some(ids)
+        {
+            info.expected_ids = Some(self.device_ids);
+            info.previous_ids = Some(self.device_ids);
+        } else {
+            return Err(Error::UnexpectedDevice {
+                port: info.port(),
+                expected: self.expected_ids.unwrap_or(self.device_ids),
+                found: ids,
+            });
+        }
+        Ok(Some(info))
+    }
+}
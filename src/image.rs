@@ -0,0 +1,165 @@
+use std::fmt;
+
+use crate::{DfuNusb, Error, FirmwareInfo};
+
+/// Outcome of writing one element of a firmware image
+#[derive(Debug)]
+pub enum ElementStatus {
+    /// The element was written completely
+    Written,
+    /// Writing the element failed; its memory is likely partially written
+    Failed(Error),
+    /// The element wasn't written as an earlier one failed; its memory is untouched
+    NotAttempted,
+}
+
+/// Result of writing one element of a firmware image
+#[derive(Debug)]
+pub struct ElementReport {
+    /// Alternative setting the element was written to
+    pub alt: u8,
+    /// Start address of the element
+    pub address: u32,
+    /// Size of the element
+    pub size: u32,
+    /// What happened to the element
+    pub status: ElementStatus,
+    /// Start addresses of the bad sectors skipped while writing the element, see
+    /// [`BadSectorPolicy`](crate::BadSectorPolicy)
+    pub skipped_sectors: Vec<u32>,
+    /// Whether the element was read back and matched, see [`DfuNusb::verified`]
+    pub verified: bool,
+}
+
+/// Per-element results of writing a multi-element firmware image
+///
+/// Elements are listed in the order they are written; a failure stops the download, so the
+/// report tells exactly which parts of the device were updated.
+#[derive(Debug, Default)]
+pub struct DownloadReport {
+    /// Elements of the image
+    pub elements: Vec<ElementReport>,
+}
+
+impl DownloadReport {
+    /// Returns whether all elements were written
+    pub fn is_complete(&self) -> bool {
+        self.elements
+            .iter()
+            .all(|e| matches!(e.status, ElementStatus::Written))
+    }
+
+    /// Returns the element which failed and its error, if any
+    pub fn failure(&self) -> Option<(&ElementReport, &Error)> {
+        self.elements.iter().find_map(|e| match &e.status {
+            ElementStatus::Failed(error) => Some((e, error)),
+            _ => None,
+        })
+    }
+
+    /// Returns the error of the element which failed, if any
+    pub fn into_failure(self) -> Option<Error> {
+        self.elements.into_iter().find_map(|e| match e.status {
+            ElementStatus::Failed(error) => Some(error),
+            _ => None,
+        })
+    }
+}
+
+impl fmt::Display for DownloadReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, element) in self.elements.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            write!(
+                f,
+                "alt {} {:#010x} ({} bytes): ",
+                element.alt, element.address, element.size
+            )?;
+            match &element.status {
+                ElementStatus::Written => write!(f, "written")?,
+                ElementStatus::Failed(error) => write!(f, "FAILED: {error}")?,
+                ElementStatus::NotAttempted => write!(f, "not attempted")?,
+            }
+            if !element.skipped_sectors.is_empty() {
+                let sectors: Vec<_> = element
+                    .skipped_sectors
+                    .iter()
+                    .map(|sector| format!("{sector:#010x}"))
+                    .collect();
+                write!(f, ", skipped bad sectors {}", sectors.join(", "))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl DfuNusb {
+    /// Download all elements of all targets of a DfuSe image, with or without DFU suffix
+    ///
+    /// Each target is written to its alternative setting. Errors writing an element end up in
+    /// the report rather than in the returned result, which only fails if the image can't be
+    /// parsed. The device only leaves DFU mode once the last element was written.
+    pub async fn download_image(&mut self, image: &[u8]) -> Result<DownloadReport, Error> {
+        let targets = FirmwareInfo::parse(image)?
+            .targets
+            .ok_or_else(|| Error::InvalidFirmware("not a DfuSe image".to_string()))?;
+
+        let mut report = DownloadReport::default();
+        for target in &targets {
+            for element in &target.elements {
+                report.elements.push(ElementReport {
+                    alt: target.alt,
+                    address: element.address,
+                    size: element.size,
+                    status: ElementStatus::NotAttempted,
+                    skipped_sectors: Vec::new(),
+                    verified: false,
+                });
+            }
+        }
+
+        // Collected, as the closures held across the downloads would keep the future from
+        // being Send
+        let elements: Vec<_> = targets
+            .iter()
+            .flat_map(|t| t.elements.iter().map(move |e| (t.alt, e)))
+            .collect();
+        for (i, (alt, element)) in elements.into_iter().enumerate() {
+            let last = i + 1 == report.elements.len();
+            let data = &image[element.offset..element.offset + element.size as usize];
+            let result = self
+                .download_element(alt, element.address, data, last)
+                .await;
+            report.elements[i].skipped_sectors = self.skipped_sectors();
+            report.elements[i].verified = self.verified();
+
+            match result {
+                Ok(()) => report.elements[i].status = ElementStatus::Written,
+                Err(e) => {
+                    report.elements[i].status = ElementStatus::Failed(e);
+                    break;
+                }
+            }
+        }
+
+        Ok(report)
+    }
+
+    async fn download_element(
+        &mut self,
+        alt: u8,
+        address: u32,
+        data: &[u8],
+        leave: bool,
+    ) -> Result<(), Error> {
+        if alt != self.alt {
+            self.set_alt(alt)?;
+        }
+        self.override_address = Some(address);
+        let length = data.len() as u32;
+        self.check_download(Some(length))?;
+        self.download_reader(data, length, leave).await
+    }
+}
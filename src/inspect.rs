@@ -0,0 +1,265 @@
+use std::fmt;
+
+use crate::file::{DfuSuffix, DfusePrefix};
+use crate::Error;
+
+/// Element of a DfuSe image: a block of data written at an address
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DfuseElement {
+    /// Start address of the element
+    pub address: u32,
+    /// Size of the element data
+    pub size: u32,
+    /// Offset of the element data in the file
+    pub offset: usize,
+}
+
+/// Target of a DfuSe image, written to one alternative setting
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DfuseTarget {
+    /// Alternative setting the target is meant for
+    pub alt: u8,
+    /// Name of the target, if it has one
+    pub name: Option<String>,
+    /// Elements of the target
+    pub elements: Vec<DfuseElement>,
+}
+
+/// Description of a firmware file, as shown by `inspect`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FirmwareInfo {
+    /// DFU suffix of the file, if any
+    pub suffix: Option<DfuSuffix>,
+    /// CRC computed over the file, excluding the CRC field of the suffix
+    pub crc: Option<u32>,
+    /// Targets of a DfuSe image; `None` for raw images
+    pub targets: Option<Vec<DfuseTarget>>,
+    /// Number of bytes written to the device
+    pub payload_size: usize,
+}
+
+impl FirmwareInfo {
+    /// Describe the firmware file `data`, without needing a device
+    pub fn parse(data: &[u8]) -> Result<Self, Error> {
+        let suffix = DfuSuffix::parse(data);
+        let (crc, payload) = match &suffix {
+            Some(suffix) => (
+                Some(crate::file::crc32(&data[..data.len() - 4])),
+                &data[..data.len().saturating_sub(usize::from(suffix.length))],
+            ),
+            None => (None, data),
+        };
+
+        let (targets, payload_size) = if DfusePrefix::parse(payload).is_some() {
+            let targets = parse_dfuse(payload)?;
+            let size = targets
+                .iter()
+                .flat_map(|t| &t.elements)
+                .map(|e| e.size as usize)
+                .sum();
+            (Some(targets), size)
+        } else {
+            (None, payload.len())
+        };
+
+        Ok(Self {
+            suffix,
+            crc,
+            targets,
+            payload_size,
+        })
+    }
+
+    /// Whether the CRC of the suffix matches the file, if it has a suffix
+    pub fn crc_valid(&self) -> Option<bool> {
+        Some(self.suffix?.crc == self.crc?)
+    }
+}
+
+/// Parse the targets of a DfuSe image (UM0391)
+fn parse_dfuse(data: &[u8]) -> Result<Vec<DfuseTarget>, Error> {
+    let invalid = |what: &str| Error::InvalidFirmware(format!("truncated DfuSe {what}"));
+    let u32_at = |data: &[u8], i: usize| {
+        data.get(i..i + 4)
+            .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+    };
+
+    let count = DfusePrefix::parse(data)
+        .ok_or_else(|| invalid("prefix"))?
+        .targets;
+    let mut offset = DfusePrefix::LENGTH;
+    let mut targets = Vec::with_capacity(count.into());
+
+    for _ in 0..count {
+        // Target prefix: signature, alt, named flag, name, size, number of elements
+        let prefix = data
+            .get(offset..offset + 274)
+            .ok_or_else(|| invalid("target"))?;
+        if &prefix[..6] != b"Target" {
+            return Err(Error::InvalidFirmware(format!(
+                "bad DfuSe target signature at {offset:#x}"
+            )));
+        }
+        let name = (u32_at(prefix, 7) != Some(0)).then(|| {
+            let name = &prefix[11..266];
+            let end = name.iter().position(|&b| b == 0).unwrap_or(name.len());
+            String::from_utf8_lossy(&name[..end]).into_owned()
+        });
+        let elements_count = u32_at(prefix, 270).unwrap_or_default();
+        offset += 274;
+
+        let mut elements = Vec::new();
+        for _ in 0..elements_count {
+            let (Some(address), Some(size)) = (u32_at(data, offset), u32_at(data, offset + 4))
+            else {
+                return Err(invalid("element"));
+            };
+            offset += 8;
+            if data.len() < offset + size as usize {
+                return Err(invalid("element"));
+            }
+            elements.push(DfuseElement {
+                address,
+                size,
+                offset,
+            });
+            offset += size as usize;
+        }
+
+        targets.push(DfuseTarget {
+            alt: prefix[6],
+            name,
+            elements,
+        });
+    }
+
+    Ok(targets)
+}
+
+impl fmt::Display for FirmwareInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.suffix {
+            Some(suffix) => {
+                writeln!(f, "DFU suffix:")?;
+                writeln!(
+                    f,
+                    "  device: {:04x}:{:04x} release {:04x}",
+                    suffix.vendor_id, suffix.product_id, suffix.device_version
+                )?;
+                writeln!(f, "  DFU version: {:04x}", suffix.dfu_version)?;
+                writeln!(f, "  length: {}", suffix.length)?;
+                let status = match self.crc_valid() {
+                    Some(true) => "valid".to_string(),
+                    _ => format!("INVALID, computed {:#010x}", self.crc.unwrap_or_default()),
+                };
+                writeln!(f, "  CRC: {:#010x} ({status})", suffix.crc)?;
+            }
+            None => writeln!(f, "No DFU suffix")?,
+        }
+
+        if let Some(targets) = &self.targets {
+            writeln!(f, "DfuSe image with {} target(s):", targets.len())?;
+            for target in targets {
+                writeln!(
+                    f,
+                    "  alt {} \"{}\", {} element(s):",
+                    target.alt,
+                    target.name.as_deref().unwrap_or(""),
+                    target.elements.len()
+                )?;
+                for element in &target.elements {
+                    writeln!(
+                        f,
+                        "    {:#010x}-{:#010x} ({} bytes)",
+                        element.address,
+                        element
+                            .address
+                            .saturating_add(element.size.saturating_sub(1)),
+                        element.size
+                    )?;
+                }
+            }
+        }
+
+        write!(f, "Payload: {} bytes", self.payload_size)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::file::DfuseImage;
+
+    #[test]
+    fn dfuse_image() {
+        let image = DfuseImage::new()
+            .element(0, Some("Internal Flash"), 0x0800_0000, vec![1; 16])
+            .element(0, None, 0x0800_4000, vec![2; 8])
+            .element(1, None, 0x1fff_7800, vec![3; 4])
+            .build(DfuSuffix::new(0x0483, 0xdf11));
+        let info = FirmwareInfo::parse(&image).unwrap();
+
+        assert_eq!(info.crc_valid(), Some(true));
+        assert_eq!(info.suffix.unwrap().dfu_version, 0x011a);
+        assert_eq!(info.payload_size, 28);
+        let targets = info.targets.unwrap();
+        assert_eq!(
+            targets
+                .iter()
+                .map(|t| (t.alt, t.name.as_deref(), t.elements.len()))
+                .collect::<Vec<_>>(),
+            [(0, Some("Internal Flash"), 2), (1, None, 1)]
+        );
+        for (element, data) in targets.iter().flat_map(|t| &t.elements).zip([1, 2, 3]) {
+            let bytes = &image[element.offset..element.offset + element.size as usize];
+            assert!(bytes.iter().all(|&b| b == data), "{element:?}");
+        }
+        assert_eq!(targets[0].elements[1].address, 0x0800_4000);
+    }
+
+    #[test]
+    fn raw_image() {
+        for (data, suffix, payload_size) in [
+            (vec![0xaa; 32], false, 32),
+            (
+                {
+                    let mut data = vec![0xaa; 32];
+                    DfuSuffix::new(0x1209, 0x2003).append_to(&mut data);
+                    data
+                },
+                true,
+                32,
+            ),
+        ] {
+            let info = FirmwareInfo::parse(&data).unwrap();
+            assert_eq!(info.suffix.is_some(), suffix);
+            assert_eq!(info.crc_valid(), suffix.then_some(true));
+            assert_eq!((info.targets, info.payload_size), (None, payload_size));
+        }
+    }
+
+    #[test]
+    fn truncated_dfuse() {
+        let image = DfuseImage::new()
+            .element(0, None, 0x0800_0000, vec![0; 16])
+            .build(DfuSuffix::new(0x0483, 0xdf11));
+        let payload = &image[..image.len() - DfuSuffix::LENGTH];
+        for length in [
+            DfusePrefix::LENGTH + 100,
+            payload.len() - 20,
+            payload.len() - 1,
+        ] {
+            assert!(
+                matches!(
+                    parse_dfuse(&payload[..length]),
+                    Err(Error::InvalidFirmware(_))
+                ),
+                "{length}"
+            );
+        }
+
+        let mut bad = payload.to_vec();
+        bad[DfusePrefix::LENGTH..DfusePrefix::LENGTH + 6].copy_from_slice(b"Tarjet");
+        assert!(matches!(parse_dfuse(&bad), Err(Error::InvalidFirmware(_))));
+    }
+}
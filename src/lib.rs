@@ -1,11 +1,124 @@
-use std::time::Duration;
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 use dfu_core::{
     asynchronous::DfuAsyncIo, functional_descriptor::FunctionalDescriptor, DfuIo, DfuProtocol,
 };
-use nusb::transfer::{Control, ControlIn, ControlOut, ControlType, Recipient, TransferError};
+use nusb::transfer::{
+    Completion, Control, ControlIn, ControlOut, ControlType, Recipient, TransferError,
+};
 use thiserror::Error;
 
+mod bad_sector;
+pub use bad_sector::BadSectorPolicy;
+mod blocks;
+pub use blocks::{BlockHandler, WrittenBlock};
+mod bootloader;
+pub use bootloader::Bootloader;
+mod budget;
+pub use budget::BandwidthBudget;
+mod capacity;
+mod close;
+pub use close::ClosePolicy;
+#[cfg(any(feature = "gzip", feature = "zstd"))]
+mod compression;
+#[cfg(any(feature = "gzip", feature = "zstd"))]
+pub use compression::Compression;
+mod descriptors;
+pub use descriptors::{RawAltSetting, RawDescriptors};
+mod dfuse;
+pub use dfuse::DfuseOptions;
+mod discovery;
+pub use discovery::{
+    list_devices, scan_functions, wait_for_device, wait_for_device_blocking, DeviceFilter,
+    DfuAltSetting, DfuDeviceInfo, DfuFunction, DfuMode,
+};
+mod download;
+pub mod exit;
+mod flasher;
+pub use flasher::{flash_and_verify, Finalize, FlashOptions, FlashReport};
+mod group;
+pub use group::{DeviceProgress, GroupMember, GroupProgress, GroupStream, ProgressGroup};
+mod hooks;
+pub use hooks::{Hook, HookContext, HookError, HookPoint};
+mod identity;
+mod image;
+pub use image::{DownloadReport, ElementReport, ElementStatus};
+mod inspect;
+pub use inspect::{DfuseElement, DfuseTarget, FirmwareInfo};
+mod lock;
+pub use lock::DeviceLock;
+mod logging;
+pub use identity::{DeviceIdentity, MatchStrategy};
+use logging::{debug, info, warning};
+#[cfg(test)]
+mod fake;
+#[cfg(feature = "embedded-storage")]
+mod flash;
+#[cfg(feature = "embedded-storage")]
+pub use flash::{DfuFlash, FlashError};
+mod open;
+pub use open::{OpenOptions, Probe};
+mod pacing;
+pub use pacing::Pacing;
+mod padding;
+pub use padding::Padding;
+mod protect;
+mod quirks;
+pub use quirks::{FinalStatus, RequestIndex};
+mod report;
+pub use report::{CapabilityReport, ReportAltSetting};
+pub mod file;
+mod progress;
+pub use file::DfuSuffix;
+mod session;
+pub use session::SessionLog;
+mod snapshot;
+pub use snapshot::{DebugSnapshot, SentRequest};
+mod source;
+#[cfg(feature = "reqwest")]
+pub use source::HttpSource;
+pub use source::{FileSource, FirmwareSource, ReaderSource};
+mod stress;
+pub use stress::{StressIteration, StressOptions, StressOutcome, StressReport};
+mod timeouts;
+mod timer;
+#[cfg(feature = "indicatif")]
+pub use progress::ProgressBarAdapter;
+pub use progress::{Phase, Progress, ProgressHandler};
+pub use timeouts::Timeouts;
+#[cfg(feature = "async-std")]
+pub use timer::AsyncStdTimer;
+#[cfg(feature = "tokio")]
+pub use timer::TokioTimer;
+pub use timer::{Sleep, StdTimer, Timer};
+mod upload;
+mod version;
+mod warning;
+pub use version::{version_string, FirmwareVersion, ParseVersionError};
+pub use warning::Warning;
+
+const DESCRIPTOR_TYPE_DEVICE: u8 = 0x01;
+/// bmRequestType of DFU class requests to the interface, host to device
+const REQUEST_TYPE_OUT: u8 = 0b0010_0001;
+/// bmRequestType of DFU class requests to the interface, device to host
+const REQUEST_TYPE_IN: u8 = 0b1010_0001;
+const DFU_DETACH: u8 = 0;
+const DFU_DNLOAD: u8 = 1;
+const DFU_UPLOAD: u8 = 2;
+const DFU_GETSTATUS: u8 = 3;
+const DFU_CLRSTATUS: u8 = 4;
+const DFU_GETSTATE: u8 = 5;
+const DFU_ABORT: u8 = 6;
+/// DfuSe Set Address Pointer command (UM0424), sent as a DNLOAD of block 0
+const DFUSE_SET_ADDRESS: u8 = 0x21;
+/// DfuSe Erase command (UM0424), sent as a DNLOAD of block 0
+const DFUSE_ERASE: u8 = 0x41;
+/// DfuSe Read Unprotect command (AN3156), sent as a DNLOAD of block 0
+const DFUSE_READ_UNPROTECT: u8 = 0x92;
+
 pub type DfuASync = dfu_core::asynchronous::DfuASync<DfuNusb, Error>;
 pub type DfuSync = dfu_core::sync::DfuSync<DfuNusb, Error>;
 
@@ -13,72 +126,1005 @@ pub type DfuSync = dfu_core::sync::DfuSync<DfuNusb, Error>;
 pub enum Error {
     #[error("Device not found")]
     DeviceNotFound,
+    #[error(
+        "Unexpected device {:04x}:{:04x} on port {port}, expected {:04x}:{:04x}",
+        found.0, found.1, expected.0, expected.1
+    )]
+    UnexpectedDevice {
+        port: String,
+        expected: (u16, u16),
+        found: (u16, u16),
+    },
+    #[error("{0} devices match, use a more specific filter")]
+    MultipleDevices(usize),
+    #[error("Device is in use by another process: {0}")]
+    DeviceLocked(String),
+    #[error("Interface {0} does not answer DFU requests")]
+    NotADfuInterface(u8),
     #[error("Functional Desciptor not found")]
     FunctionalDescriptorNotFound,
     #[error("Alternative setting not found")]
     AltSettingNotFound,
+    #[error("Configuration {0} not found")]
+    ConfigurationNotFound(u8),
+    #[error("Selecting a configuration is not supported on this platform")]
+    ConfigurationUnsupported,
+    #[error("Refusing to write to dangerous target \"{0}\"")]
+    DangerousTarget(String),
+    #[error("Device does not support downloads")]
+    DownloadNotSupported,
+    #[error("Device does not support uploads")]
+    UploadNotSupported,
+    #[error("Device does not use the DfuSe protocol")]
+    DfuseRequired,
+    #[error("Device only uploads consecutive blocks, so its capacity can't be probed")]
+    CapacityProbeUnsupported,
+    #[error("Device can't be verified; it must support uploads and stay in DFU mode")]
+    VerifyNotSupported,
+    #[error("Verification failed at offset {offset:#x}")]
+    VerifyMismatch { offset: u32 },
+    #[error("Firmware of {size} bytes does not fit in the {capacity} bytes of the target")]
+    FirmwareTooLarge { size: u32, capacity: u64 },
+    #[error(
+        "Firmware is for device {expected_vid:04x}:{expected_pid:04x}, not {vid:04x}:{pid:04x}"
+    )]
+    SuffixMismatch {
+        expected_vid: u16,
+        expected_pid: u16,
+        vid: u16,
+        pid: u16,
+    },
+    #[error("Invalid DfuSe options: {0}")]
+    InvalidDfuseOptions(String),
+    #[error("Memory layout doesn't fit the flash geometry: {0}")]
+    FlashGeometry(String),
+    #[error("Invalid firmware file: {0}")]
+    InvalidFirmware(String),
+    #[error("Firmware CRC-32 is {actual:#010x} instead of {expected:#010x}")]
+    DigestMismatch { expected: u32, actual: u32 },
     #[error(transparent)]
     FunctionalDescriptor(#[from] dfu_core::functional_descriptor::Error),
     #[error(transparent)]
     Dfu(#[from] dfu_core::Error),
+    #[error("Device disconnected")]
+    Disconnected,
+    #[error("Device still busy after {0:?}")]
+    BusyTimeout(Duration),
+    #[error("Another operation is running on the device")]
+    Busy,
+    #[error("Hook {point} failed: {source}")]
+    Hook { point: HookPoint, source: HookError },
+    #[error(
+        "Device busy for {elapsed:?} in total, last in state {state:?} with status {status:?}"
+    )]
+    DevicePollTimeout {
+        elapsed: Duration,
+        state: dfu_core::State,
+        status: dfu_core::Status,
+    },
+    #[error(transparent)]
+    Nusb(nusb::Error),
+    #[error(transparent)]
+    Io(std::io::Error),
     #[error(transparent)]
-    Nusb(#[from] nusb::Error),
+    Transfer(TransferError),
+    #[cfg(feature = "reqwest")]
     #[error(transparent)]
-    Transfer(#[from] TransferError),
+    Http(#[from] reqwest::Error),
+}
+
+/// Broad classification of an [`Error`], to decide how to recover from it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// Transient bus error; retrying the operation may succeed
+    Transient,
+    /// The device is gone; it needs to be replugged or re-opened
+    Disconnected,
+    /// The device reported an error through its DFU status or state
+    DeviceStatus,
+    /// The device doesn't behave as the DFU protocol requires
+    Protocol,
+    /// Invalid use of the API, like bad options or an image not fitting the device
+    Usage,
+    /// I/O on the host failed, like reading the firmware or writing uploaded data; the
+    /// device isn't at fault
+    Host,
+}
+
+impl Error {
+    /// Classify the error
+    pub fn kind(&self) -> ErrorKind {
+        use dfu_core::Error as Dfu;
+        use std::io::ErrorKind as Io;
+
+        match self {
+            Error::DeviceNotFound | Error::Disconnected => ErrorKind::Disconnected,
+            Error::FunctionalDescriptorNotFound | Error::FunctionalDescriptor(_) => {
+                ErrorKind::Protocol
+            }
+            Error::AltSettingNotFound
+            | Error::MultipleDevices(_)
+            | Error::NotADfuInterface(_)
+            | Error::ConfigurationNotFound(_)
+            | Error::ConfigurationUnsupported
+            | Error::DangerousTarget(_)
+            | Error::DownloadNotSupported
+            | Error::UploadNotSupported
+            | Error::DfuseRequired
+            | Error::CapacityProbeUnsupported
+            | Error::VerifyNotSupported
+            | Error::FirmwareTooLarge { .. }
+            | Error::SuffixMismatch { .. }
+            | Error::InvalidDfuseOptions(_)
+            | Error::FlashGeometry(_)
+            | Error::InvalidFirmware(_)
+            | Error::DigestMismatch { .. }
+            | Error::Busy
+            | Error::Hook { .. }
+            | Error::UnexpectedDevice { .. } => ErrorKind::Usage,
+            Error::BusyTimeout(_)
+            | Error::DevicePollTimeout { .. }
+            | Error::VerifyMismatch { .. } => ErrorKind::DeviceStatus,
+            Error::Dfu(e) => match e {
+                Dfu::StatusError(_) | Dfu::StateError(_) | Dfu::InvalidState { .. } => {
+                    ErrorKind::DeviceStatus
+                }
+                Dfu::OutOfCapabilities
+                | Dfu::BufferTooBig { .. }
+                | Dfu::MaximumTransferSizeExceeded
+                | Dfu::EraseLimitReached
+                | Dfu::MaximumChunksExceeded
+                | Dfu::NoSpaceLeft => ErrorKind::Usage,
+                _ => ErrorKind::Protocol,
+            },
+            Error::Nusb(e) => match e.kind() {
+                Io::PermissionDenied | Io::NotFound | Io::Unsupported | Io::InvalidInput => {
+                    ErrorKind::Usage
+                }
+                _ => ErrorKind::Transient,
+            },
+            Error::Io(_) => ErrorKind::Host,
+            Error::DeviceLocked(_) => ErrorKind::Transient,
+            Error::Transfer(TransferError::Stall) => ErrorKind::Protocol,
+            Error::Transfer(_) => ErrorKind::Transient,
+            #[cfg(feature = "reqwest")]
+            Error::Http(_) => ErrorKind::Transient,
+        }
+    }
+
+    /// Returns whether retrying the failed operation on the same device handle may succeed
+    pub fn is_retryable(&self) -> bool {
+        self.kind() == ErrorKind::Transient
+    }
+}
+
+/// Returns whether an OS error indicates the device is gone
+fn is_disconnect_error(error: &nusb::Error) -> bool {
+    #[cfg(unix)]
+    const DISCONNECT_ERRORS: &[i32] = &[19 /* ENODEV */];
+    #[cfg(windows)]
+    const DISCONNECT_ERRORS: &[i32] = &[
+        433,  /* ERROR_NO_SUCH_DEVICE */
+        1167, /* ERROR_DEVICE_NOT_CONNECTED */
+    ];
+    #[cfg(not(any(unix, windows)))]
+    const DISCONNECT_ERRORS: &[i32] = &[];
+
+    error.kind() == std::io::ErrorKind::ConnectionAborted
+        || error
+            .raw_os_error()
+            .is_some_and(|code| DISCONNECT_ERRORS.contains(&code))
+}
+
+impl From<nusb::Error> for Error {
+    fn from(error: nusb::Error) -> Self {
+        if is_disconnect_error(&error) {
+            Error::Disconnected
+        } else {
+            Error::Nusb(error)
+        }
+    }
 }
 
+impl From<TransferError> for Error {
+    fn from(error: TransferError) -> Self {
+        match error {
+            TransferError::Disconnected => Error::Disconnected,
+            error => Error::Transfer(error),
+        }
+    }
+}
+
+/// DFU interface of an opened device
+///
+/// Only one operation, like a download or an upload, runs on the device at a time: starting
+/// another one while it is running fails with [`Error::Busy`] rather than interleaving their
+/// requests. Querying the status or state of the device is always possible.
 pub struct DfuNusb {
     device: nusb::Device,
     interface: nusb::Interface,
     descriptor: FunctionalDescriptor,
     protocol: dfu_core::DfuProtocol<dfu_core::memory_layout::MemoryLayout>,
+    reporter: Mutex<progress::Reporter>,
+    override_address: Option<u32>,
+    alt_name: String,
+    allow_dangerous_targets: bool,
+    detached_during_manifest: AtomicBool,
+    /// Set while an operation is running, see [`DfuNusb::begin_operation`]
+    operation: AtomicBool,
+    timeouts: Timeouts,
+    busy: Mutex<Option<(Instant, Duration)>>,
+    /// Time the device was busy during the current operation, excluding the pending request
+    polled: Mutex<Duration>,
+    skip_erase: bool,
+    bad_sectors: BadSectorPolicy,
+    skipped_sectors: Mutex<Vec<u32>>,
+    /// Whether the last download was verified, see [`DfuNusb::verified`]
+    verified: AtomicBool,
+    /// Capacity of the current plain DFU target, see [`DfuNusb::probe_capacity`]
+    probed_capacity: Mutex<Option<u64>>,
+    force: bool,
+    device_ids: Option<(u16, u16)>,
+    identity: Option<DeviceIdentity>,
+    pacing: Pacing,
+    padding: Padding,
+    budget: Option<BandwidthBudget>,
+    verify: bool,
+    manufacturer: Option<String>,
+    product: Option<String>,
+    serial: Option<String>,
+    close_policy: ClosePolicy,
+    alt: u8,
+    crc_command: Option<u8>,
+    lock: Option<DeviceLock>,
+    request_index: RequestIndex,
+    final_status: FinalStatus,
+    /// Requests seen, see [`DfuNusb::debug_snapshot`]
+    trace: Mutex<snapshot::Trace>,
+    warnings: Vec<Warning>,
+    timer: Arc<dyn Timer>,
+    hooks: Vec<(HookPoint, Box<dyn Hook>)>,
+    /// Hooks which failed once the firmware was written, see [`DfuNusb::take_hook_failures`]
+    hook_failures: Mutex<Vec<Error>>,
 }
 
 impl DfuNusb {
     /// Open a device
     pub fn open(device: nusb::Device, interface: nusb::Interface, alt: u8) -> Result<Self, Error> {
-        interface.set_alt_setting(alt)?;
-        let descriptor = interface
+        Self::open_with_timeouts(device, interface, alt, Timeouts::default(), None)
+    }
+
+    /// Open a device, using `timeouts` for the requests done while opening and
+    /// `fallback_transfer_size` for devices advertising a wTransferSize of 0
+    pub(crate) fn open_with_timeouts(
+        device: nusb::Device,
+        interface: nusb::Interface,
+        alt: u8,
+        timeouts: Timeouts,
+        fallback_transfer_size: Option<u16>,
+    ) -> Result<Self, Error> {
+        let mut descriptor = interface
             .descriptors()
             .find_map(|alt| {
                 alt.descriptors()
                     .find_map(|d| FunctionalDescriptor::from_bytes(&d))
             })
             .ok_or(Error::FunctionalDescriptorNotFound)??;
-        let alt = interface
-            .descriptors()
-            .find(|a| a.alternate_setting() == alt)
-            .ok_or(Error::AltSettingNotFound)?;
-
-        let s = if let Some(index) = alt.string_index() {
-            let lang = device
-                .get_string_descriptor_supported_languages(Duration::from_secs(3))?
-                .next()
-                .unwrap_or_default();
-            device
-                .get_string_descriptor(index, lang, Duration::from_secs(3))
-                .unwrap_or_default()
-        } else {
-            String::new()
+        let mut warnings = Vec::new();
+        // Zero-length blocks would never get the firmware across, and end the download
+        if descriptor.transfer_size == 0 {
+            let used = fallback_transfer_size
+                .filter(|&size| size > 0)
+                .unwrap_or(warning::DEFAULT_TRANSFER_SIZE);
+            warning!("Device advertises a wTransferSize of 0, using {used}");
+            descriptor.transfer_size = used;
+            warnings.push(Warning::ZeroTransferSize { used });
+        }
+        let (s, protocol) = select_alt(&device, &interface, &descriptor, alt, timeouts.control)?;
+        let device_descriptor = device
+            .get_descriptor(DESCRIPTOR_TYPE_DEVICE, 0, 0, timeouts.control)
+            .ok()
+            .filter(|d| d.len() >= 18);
+        let device_ids = device_descriptor.as_ref().map(|d| {
+            (
+                u16::from_le_bytes([d[8], d[9]]),
+                u16::from_le_bytes([d[10], d[11]]),
+            )
+        });
+        let string = |offset: usize| match device_descriptor.as_ref().map(|d| d[offset]) {
+            Some(index) if index != 0 => read_string(&device, index, timeouts.control)
+                .ok()
+                .filter(|s| !s.is_empty()),
+            _ => None,
         };
-        let protocol = DfuProtocol::new(&s, descriptor.dfu_version)?;
+        let (manufacturer, product, serial) = (string(14), string(15), string(16));
+        info!(
+            "Opened interface {} alt {} \"{}\" of {:?} ({})",
+            interface.interface_number(),
+            alt,
+            s,
+            product,
+            if matches!(protocol, DfuProtocol::Dfu) {
+                "DFU"
+            } else {
+                "DfuSe"
+            }
+        );
 
         Ok(Self {
             device,
             interface,
             descriptor,
             protocol,
+            reporter: Mutex::default(),
+            override_address: None,
+            alt_name: s,
+            allow_dangerous_targets: false,
+            detached_during_manifest: AtomicBool::new(false),
+            operation: AtomicBool::new(false),
+            timeouts,
+            busy: Mutex::new(None),
+            polled: Mutex::default(),
+            skip_erase: false,
+            bad_sectors: BadSectorPolicy::default(),
+            skipped_sectors: Mutex::new(Vec::new()),
+            verified: AtomicBool::new(false),
+            probed_capacity: Mutex::new(None),
+            force: false,
+            device_ids,
+            identity: None,
+            pacing: Pacing::default(),
+            padding: Padding::default(),
+            budget: None,
+            verify: false,
+            manufacturer,
+            product,
+            serial,
+            close_policy: ClosePolicy::default(),
+            alt,
+            crc_command: None,
+            lock: None,
+            request_index: RequestIndex::default(),
+            final_status: FinalStatus::default(),
+            trace: Mutex::default(),
+            warnings,
+            timer: timer::default_timer(),
+            hooks: Vec::new(),
+            hook_failures: Mutex::new(Vec::new()),
         })
     }
 
+    /// Switch to another alternative setting of the interface, e.g. another memory of a DfuSe
+    /// device
+    ///
+    /// The address override is cleared as it likely doesn't apply to the new target.
+    pub fn set_alt(&mut self, alt: u8) -> Result<(), Error> {
+        let (name, protocol) = select_alt(
+            &self.device,
+            &self.interface,
+            &self.descriptor,
+            alt,
+            self.timeouts.control,
+        )?;
+        self.alt_name = name;
+        self.protocol = protocol;
+        self.override_address = None;
+        *self.probed_capacity.get_mut().unwrap() = None;
+        self.alt = alt;
+        Ok(())
+    }
+
+    /// Returns the selected alternative setting
+    pub fn alt(&self) -> u8 {
+        self.alt
+    }
+
+    /// Returns the name (interface string) of the selected alternative setting
+    pub fn alt_name(&self) -> &str {
+        &self.alt_name
+    }
+
+    /// Returns whether the selected target is a special memory region like option bytes or
+    /// OTP memory, where a bad write can permanently brick or lock the device
+    pub fn is_dangerous_target(&self) -> bool {
+        let name = self
+            .alt_name
+            .trim_start_matches('@')
+            .trim_start()
+            .to_ascii_lowercase();
+        ["option bytes", "otp"]
+            .iter()
+            .any(|prefix| name.starts_with(prefix))
+    }
+
+    /// Allow writing to dangerous targets (see [`DfuNusb::is_dangerous_target`])
+    ///
+    /// Downloads to such targets fail with [`Error::DangerousTarget`] unless this is enabled.
+    pub fn allow_dangerous_targets(&mut self, allow: bool) -> &mut Self {
+        self.allow_dangerous_targets = allow;
+        self
+    }
+
+    /// Deviations from the DFU specification which were worked around while opening the device
+    pub fn warnings(&self) -> &[Warning] {
+        &self.warnings
+    }
+
+    /// Returns whether the device dropped off the bus while manifesting the firmware
+    ///
+    /// Devices which detach by themselves or aren't manifestation tolerant may disappear
+    /// during the manifestation phase; this is treated as a successful download. So is a
+    /// device not answering while manifesting with [`FinalStatus::TolerateTimeout`] set.
+    pub fn detached_during_manifest(&self) -> bool {
+        self.detached_during_manifest.load(Ordering::Relaxed)
+    }
+
+    /// Set the timeouts for the various phases of DFU operations
+    ///
+    /// While the device is busy, [`Progress::Busy`] events are emitted on every status poll.
+    pub fn timeouts(&mut self, timeouts: Timeouts) -> &mut Self {
+        self.timeouts = timeouts;
+        self
+    }
+
+    /// Keep track of how long the device has been busy, and of the blocks it wrote, based on
+    /// a GETSTATUS response
+    fn check_busy(&self, request: u8, response: &[u8]) -> Result<(), Error> {
+        if request != DFU_GETSTATUS || response.len() < 6 {
+            return Ok(());
+        }
+
+        debug!(
+            "Status {:?}, state {:?}",
+            dfu_core::Status::from(response[0]),
+            dfu_core::State::from(response[4])
+        );
+        self.reporter
+            .lock()
+            .unwrap()
+            .status(response[4].into(), response[0].into());
+        let mut busy = self.busy.lock().unwrap();
+        match (dfu_core::State::from(response[4]), *busy) {
+            (
+                dfu_core::State::DfuDnbusy
+                | dfu_core::State::DfuDnloadSync
+                | dfu_core::State::DfuManifest
+                | dfu_core::State::DfuManifestSync,
+                Some((since, limit)),
+            ) => {
+                let elapsed = self.timer.now().saturating_duration_since(since);
+                self.reporter.lock().unwrap().busy(elapsed);
+                if elapsed > limit {
+                    info!("Device still busy after {elapsed:?}, giving up");
+                    return Err(Error::BusyTimeout(limit));
+                }
+                let total = *self.polled.lock().unwrap() + elapsed;
+                if self.timeouts.poll_total.is_some_and(|cap| total > cap) {
+                    info!("Device polled for {total:?} in total, giving up");
+                    return Err(Error::DevicePollTimeout {
+                        elapsed: total,
+                        state: dfu_core::State::from(response[4]),
+                        status: dfu_core::Status::from(response[0]),
+                    });
+                }
+            }
+            _ => {
+                if let Some((since, _)) = busy.take() {
+                    *self.polled.lock().unwrap() +=
+                        self.timer.now().saturating_duration_since(since);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Adjust the value of outgoing requests to the configuration
+    fn request_value(&self, request: u8, value: u16) -> u16 {
+        match self.timeouts.detach {
+            Some(detach) if request == DFU_DETACH => u16::try_from(detach.as_millis())
+                .unwrap_or(u16::MAX)
+                .min(self.descriptor.detach_timeout),
+            _ => value,
+        }
+    }
+
+    /// Mark an operation as running until the returned guard is dropped
+    ///
+    /// Operations are sequences of requests which mustn't be interleaved with those of
+    /// another, e.g. the DNLOAD requests of a download, so this fails with [`Error::Busy`]
+    /// while one is running. The guard is also dropped with the future of a cancelled
+    /// operation.
+    pub(crate) fn begin_operation(&self) -> Result<Operation<'_>, Error> {
+        self.operation
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .map_err(|_| Error::Busy)?;
+        self.trace.lock().unwrap().start_operation();
+        Ok(Operation(&self.operation))
+    }
+
+    /// Start a new operation for the total polling time limit
+    pub(crate) fn reset_polled(&self) {
+        *self.polled.lock().unwrap() = Duration::ZERO;
+    }
+
+    /// Start tracking the busy time of a DNLOAD request
+    fn start_busy(&self, value: u16, buffer: &[u8]) {
+        let limit = match buffer {
+            [] => self.timeouts.manifest,
+            // DfuSe erase commands: a page erase has an address, a mass erase doesn't
+            [DFUSE_ERASE, _, _, _, _] if self.is_dfuse() && value == 0 => self.timeouts.erase_page,
+            [DFUSE_ERASE] if self.is_dfuse() && value == 0 => self.timeouts.mass_erase,
+            _ => self.timeouts.write_block,
+        };
+        *self.busy.lock().unwrap() = Some((self.timer.now(), limit));
+    }
+
+    /// Handle a failed control IN transfer, answering GETSTATUS on behalf of a device that
+    /// disconnected during manifestation as expected
+    fn control_in_failed(
+        &self,
+        request: u8,
+        error: TransferError,
+        buffer: &mut [u8],
+    ) -> Result<usize, Error> {
+        let detached = (self.descriptor.will_detach || !self.descriptor.manifestation_tolerant)
+            && matches!(error, TransferError::Disconnected | TransferError::Fault);
+        // Timeouts are reported as cancelled transfers
+        let unanswered = self.final_status == FinalStatus::TolerateTimeout
+            && matches!(error, TransferError::Cancelled);
+        if request != DFU_GETSTATUS
+            || self.reporter.lock().unwrap().phase() != Some(Phase::Manifest)
+            || !(detached || unanswered)
+            || buffer.len() < 6
+        {
+            return Err(error.into());
+        }
+
+        if detached {
+            info!("Device detached during manifestation");
+        } else {
+            info!(
+                "Device didn't answer the final status request, assuming it started the firmware"
+            );
+        }
+        let outcome = if detached { "detached" } else { "unanswered" };
+        self.log_recovery(
+            "final_status",
+            session::JsonFields::default().string("outcome", Some(outcome)),
+        );
+        self.detached_during_manifest.store(true, Ordering::Relaxed);
+        let state = if self.descriptor.manifestation_tolerant {
+            dfu_core::State::DfuIdle
+        } else {
+            dfu_core::State::DfuManifest
+        };
+        buffer[..6].copy_from_slice(&[0, 0, 0, 0, state.into(), 0]);
+        Ok(6)
+    }
+
+    /// Throttle firmware downloads, see [`Pacing`]
+    pub fn pacing(&mut self, pacing: Pacing) -> &mut Self {
+        self.pacing = pacing;
+        self
+    }
+
+    /// Share the bandwidth of firmware transfers with other devices, see [`BandwidthBudget`]
+    pub fn bandwidth_budget(&mut self, budget: BandwidthBudget) -> &mut Self {
+        self.budget = Some(budget);
+        self
+    }
+
+    /// Use `timer` for sleeping and measuring busy time rather than the timer of the runtime
+    /// selected through the features, see [`Timer`]
+    pub fn timer(&mut self, timer: Arc<dyn Timer>) -> &mut Self {
+        self.timer = timer;
+        self
+    }
+
+    /// Pad the firmware for bootloaders requiring full blocks or aligned starts, see [`Padding`]
+    pub fn padding(&mut self, padding: Padding) -> &mut Self {
+        self.padding = padding;
+        self
+    }
+
+    /// Set how wIndex is populated in class requests, for non-conforming bootloaders
+    pub fn request_index_quirk(&mut self, index: RequestIndex) -> &mut Self {
+        self.request_index = index;
+        self
+    }
+
+    /// Set how the status requests after the end of a download are treated, for bootloaders
+    /// starting the firmware without answering them
+    pub fn final_status_quirk(&mut self, final_status: FinalStatus) -> &mut Self {
+        self.final_status = final_status;
+        self
+    }
+
+    /// wIndex of class requests to the DFU interface
+    pub(crate) fn request_index(&self) -> u16 {
+        self.request_index
+            .value(self.interface.interface_number(), self.alt)
+    }
+
+    /// Pause to insert after a control OUT request; only firmware blocks are paced
+    fn pacing_delay(&self, request: u8, value: u16, buffer: &[u8]) -> Option<Duration> {
+        // DfuSe commands are sent as block 0
+        if request != DFU_DNLOAD || buffer.is_empty() || (self.is_dfuse() && value == 0) {
+            return None;
+        }
+        Some(self.pacing.delay(buffer.len())).filter(|delay| !delay.is_zero())
+    }
+
+    /// Wait before a transfer to stay within the shared bandwidth budget; only firmware
+    /// blocks count
+    fn budget_delay(&self, request: u8, value: u16, length: usize) -> Option<Duration> {
+        let firmware = match request {
+            // DfuSe commands are sent as block 0
+            DFU_DNLOAD => length > 0 && !(self.is_dfuse() && value == 0),
+            DFU_UPLOAD => !(self.is_dfuse() && value == 0),
+            _ => false,
+        };
+        let budget = self.budget.as_ref().filter(|_| firmware)?;
+        Some(budget.reserve(length, self.timer.now())).filter(|delay| !delay.is_zero())
+    }
+
+    fn check_write(&self, request: u8) -> Result<(), Error> {
+        if request == DFU_DNLOAD && !self.allow_dangerous_targets && self.is_dangerous_target() {
+            return Err(Error::DangerousTarget(self.alt_name.clone()));
+        }
+        Ok(())
+    }
+
+    /// Override the address onto which the firmware is downloaded.
+    ///
+    /// This address is only used if the device uses the DfuSe protocol. It also applies to the
+    /// dfu helpers created from this device.
+    pub fn override_address(&mut self, address: u32) -> &mut Self {
+        self.override_address = Some(address);
+        self
+    }
+
+    /// Report progress of operations on the device to `handler`
+    ///
+    /// Can be called repeatedly to attach several consumers, e.g. a progress bar and a logger;
+    /// every event is passed to each of them in the order they were attached.
+    pub fn with_progress(mut self, handler: impl ProgressHandler + 'static) -> Self {
+        self.reporter
+            .get_mut()
+            .unwrap()
+            .add_handler(Box::new(handler));
+        self
+    }
+
+    /// Pass every firmware block written to the device to `handler`
+    ///
+    /// Blocks are passed once the status request following them reports them written, with
+    /// their index within the download and, for DfuSe devices, the address they were written
+    /// to. Blocks the device failed to write and DfuSe commands aren't reported. Can be called
+    /// repeatedly to attach several handlers.
+    pub fn with_block_handler(mut self, handler: impl BlockHandler + 'static) -> Self {
+        self.reporter
+            .get_mut()
+            .unwrap()
+            .add_block_handler(Box::new(handler));
+        self
+    }
+
+    /// Record the operations on the device in an audit log
+    ///
+    /// The identity of the device is logged right away, so attach the log after opening.
+    pub fn with_session_log(mut self, mut log: SessionLog) -> Self {
+        let (vid, pid) = self.device_ids.unzip();
+        let previous_ids = self.identity().and_then(DeviceIdentity::previous_ids);
+        let fields = session::JsonFields::default()
+            .string("vid", vid.map(|vid| format!("{vid:04x}")).as_deref())
+            .string("pid", pid.map(|pid| format!("{pid:04x}")).as_deref())
+            .string("manufacturer", self.manufacturer())
+            .string("product", self.product())
+            .string("serial", self.serial())
+            .string("port", self.identity().map(DeviceIdentity::port))
+            .string(
+                "previous_vid",
+                previous_ids.map(|(vid, _)| format!("{vid:04x}")).as_deref(),
+            )
+            .string(
+                "previous_pid",
+                previous_ids.map(|(_, pid)| format!("{pid:04x}")).as_deref(),
+            )
+            .number("interface", Some(self.interface.interface_number().into()))
+            .number("alt", Some(self.alt.into()))
+            .string("alt_name", Some(&self.alt_name));
+        log.record("session", fields);
+        self.reporter.get_mut().unwrap().set_session(log);
+        self
+    }
+
+    /// Parameters of a download of `length` bytes, for the session log
+    pub(crate) fn session_parameters(&self, length: u32) -> session::JsonFields {
+        session::JsonFields::default()
+            .number("length", (length != u32::MAX).then_some(length.into()))
+            .number("address", self.address().map(Into::into))
+            .bool("dfuse", self.is_dfuse())
+            .bool("skip_erase", self.skip_erase)
+            .bool("verify", self.verify)
+            .bool("force", self.force)
+            .bool(
+                "tolerant_completion",
+                self.final_status == FinalStatus::TolerateTimeout,
+            )
+            .number("block_delay_ms", Some(self.pacing.block_delay.as_millis()))
+            .number("max_bandwidth", self.pacing.max_bandwidth.map(Into::into))
+    }
+
+    fn report_control_out(&self, request: u8, value: u16, buffer: &[u8]) {
+        debug!(
+            "Control OUT request {request} value {value} length {}",
+            buffer.len()
+        );
+        self.trace_completed(&[]);
+        if request == DFU_DNLOAD {
+            self.start_busy(value, buffer);
+        }
+        self.reporter
+            .lock()
+            .unwrap()
+            .control_out(request, value, buffer, self.is_dfuse());
+    }
+
+    /// Record a request about to be sent, see [`DfuNusb::debug_snapshot`]
+    fn trace_sent(&self, request: u8, value: u16, length: usize) {
+        let now = self.timer.now();
+        self.trace.lock().unwrap().sent(request, value, length, now);
+    }
+
+    /// Record the completion of the request last sent, with the data received
+    fn trace_completed(&self, data: &[u8]) {
+        self.trace.lock().unwrap().completed(data, self.is_dfuse());
+    }
+
+    fn report_reset(&self) {
+        info!("Resetting device");
+        self.reporter.lock().unwrap().reset();
+    }
+
+    /// Don't erase the target region of DfuSe devices before writing
+    ///
+    /// Only use this when the flash is known to be erased already, e.g. after a mass erase.
+    /// This applies to [`DfuNusb::download`] and [`DfuNusb::download_stream`].
+    pub fn skip_erase(&mut self, skip: bool) -> &mut Self {
+        self.skip_erase = skip;
+        self
+    }
+
+    /// Read back the firmware during [`DfuNusb::download`] and [`DfuNusb::download_stream`]
+    ///
+    /// For DfuSe devices the memory written is read back once the whole firmware was written,
+    /// before leaving DFU mode, in a single upload per contiguous region, or checked with
+    /// [`DfuNusb::device_crc_command`]; the data written is kept in memory until then. Plain
+    /// DFU devices have no way to read back part of the memory, so they are verified in a
+    /// second pass after the download, which requires them to be manifestation tolerant.
+    pub fn verify(&mut self, verify: bool) -> &mut Self {
+        self.verify = verify;
+        self
+    }
+
+    /// Returns whether the firmware of the last download was read back, or checked with
+    /// [`DfuNusb::device_crc_command`], and matched
+    ///
+    /// This is `false` if [`DfuNusb::verify`] wasn't set, the download failed or the firmware
+    /// was empty.
+    pub fn verified(&self) -> bool {
+        self.verified.load(Ordering::Relaxed)
+    }
+
+    /// Verify DfuSe blocks with a vendor-specific checksum command instead of reading them back
+    ///
+    /// This is only used if the device lists `command` in its response to the DfuSe Get
+    /// command (see [`DfuNusb::dfuse_commands`]), otherwise verification falls back to reading
+    /// back the data. The command is sent as a DNLOAD of block 0 with the command byte followed
+    /// by the address and length of the region (both little endian `u32`); the device answers
+    /// with the IEEE CRC-32 of the region in a 4 byte UPLOAD of block 1.
+    pub fn device_crc_command(&mut self, command: Option<u8>) -> &mut Self {
+        self.crc_command = command;
+        self
+    }
+
+    /// Bypass safety checks
+    ///
+    /// This skips the DFU suffix check, the download capability check and the firmware size
+    /// check, for intentionally flashing unusual images.
+    pub fn force(&mut self, force: bool) -> &mut Self {
+        self.force = force;
+        self
+    }
+
+    /// Returns the vendor and product id of the device, if they could be read
+    pub fn device_ids(&self) -> Option<(u16, u16)> {
+        self.device_ids
+    }
+
+    /// Returns the manufacturer string of the device, if any
+    pub fn manufacturer(&self) -> Option<&str> {
+        self.manufacturer.as_deref()
+    }
+
+    /// Returns the product string of the device, if any
+    pub fn product(&self) -> Option<&str> {
+        self.product.as_deref()
+    }
+
+    /// Returns the serial number of the device, if any
+    pub fn serial(&self) -> Option<&str> {
+        self.serial.as_deref()
+    }
+
+    /// Returns the physical identity of the device, if it was opened from a [`DfuDeviceInfo`]
+    ///
+    /// Use [`DeviceIdentity::find`] to get hold of the same device after it re-enumerated.
+    pub fn identity(&self) -> Option<&DeviceIdentity> {
+        self.identity.as_ref()
+    }
+
+    /// Check that a firmware with the given DFU suffix is meant for this device
+    pub fn check_suffix(&self, suffix: &DfuSuffix) -> Result<(), Error> {
+        match self.device_ids {
+            Some((vid, pid)) if !self.force && !suffix.matches(vid, pid) => {
+                Err(Error::SuffixMismatch {
+                    expected_vid: suffix.vendor_id,
+                    expected_pid: suffix.product_id,
+                    vid,
+                    pid,
+                })
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Check that the device can take a firmware of `length` bytes, if known
+    fn check_download(&self, length: Option<u32>) -> Result<(), Error> {
+        if self.force {
+            return Ok(());
+        }
+        if !self.descriptor.can_download {
+            return Err(Error::DownloadNotSupported);
+        }
+        if let (Some(size), Some(capacity)) = (length, self.capacity()) {
+            if u64::from(size) > capacity {
+                return Err(Error::FirmwareTooLarge { size, capacity });
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns whether the device uses the DfuSe protocol extensions
+    pub fn is_dfuse(&self) -> bool {
+        matches!(self.protocol, DfuProtocol::Dfuse { .. })
+    }
+
+    /// Returns the start address advertised by the DfuSe target
+    ///
+    /// This is `None` for devices using plain DFU.
+    pub fn default_address(&self) -> Option<u32> {
+        match self.protocol {
+            DfuProtocol::Dfuse { address, .. } => Some(address),
+            DfuProtocol::Dfu => None,
+        }
+    }
+
+    /// Returns the address the firmware will be downloaded to, taking overrides into account
+    ///
+    /// This is `None` for devices using plain DFU.
+    pub fn address(&self) -> Option<u32> {
+        self.default_address()
+            .map(|address| self.override_address.unwrap_or(address))
+    }
+
+    /// Returns the memory layout of the DfuSe target
+    pub fn memory_layout(&self) -> Option<&dfu_core::memory_layout::MemoryLayout> {
+        match &self.protocol {
+            DfuProtocol::Dfuse { memory_layout, .. } => Some(memory_layout),
+            DfuProtocol::Dfu => None,
+        }
+    }
+
+    /// Split into the underlying device, claimed interface, functional descriptor and the
+    /// [`DeviceLock`] held, if any
+    ///
+    /// This allows vendor-specific control transfers on the same interface, e.g. to unlock a
+    /// bootloader. The [`ClosePolicy`] isn't applied. Keep the lock as long as the interface
+    /// is used, as other processes may take the device once it is dropped.
+    pub fn into_parts(
+        mut self,
+    ) -> (
+        nusb::Device,
+        nusb::Interface,
+        FunctionalDescriptor,
+        Option<DeviceLock>,
+    ) {
+        self.close_policy = ClosePolicy::default();
+        (
+            self.device.clone(),
+            self.interface.clone(),
+            self.descriptor,
+            self.lock.take(),
+        )
+    }
+
     /// Wrap device in an *async* dfu helper
     pub fn into_async_dfu(self) -> DfuASync {
-        DfuASync::new(self)
+        let address = self.override_address;
+        let mut dfu = DfuASync::new(self);
+        if let Some(address) = address {
+            dfu.override_address(address);
+        }
+        dfu
     }
 
     /// Wrap device in an *sync* dfu helper
     pub fn into_sync_dfu(self) -> DfuSync {
-        DfuSync::new(self)
+        let address = self.override_address;
+        let mut dfu = DfuSync::new(self);
+        if let Some(address) = address {
+            dfu.override_address(address);
+        }
+        dfu
+    }
+}
+
+/// Switch the interface to alternative setting `alt`, returning its name and protocol
+fn select_alt(
+    device: &nusb::Device,
+    interface: &nusb::Interface,
+    descriptor: &FunctionalDescriptor,
+    alt: u8,
+    timeout: Duration,
+) -> Result<(String, DfuProtocol<dfu_core::memory_layout::MemoryLayout>), Error> {
+    interface.set_alt_setting(alt)?;
+    let alt = interface
+        .descriptors()
+        .find(|a| a.alternate_setting() == alt)
+        .ok_or(Error::AltSettingNotFound)?;
+
+    let name = if let Some(index) = alt.string_index() {
+        read_string(device, index, timeout)?
+    } else {
+        String::new()
+    };
+    let protocol = DfuProtocol::new(&name, descriptor.dfu_version)?;
+    Ok((name, protocol))
+}
+
+/// Operation running on a device, see [`DfuNusb::begin_operation`]
+pub(crate) struct Operation<'a>(&'a AtomicBool);
+
+impl Drop for Operation<'_> {
+    fn drop(&mut self) {
+        self.0.store(false, Ordering::Release);
+    }
+}
+
+/// Read a string descriptor in the first language supported by the device
+fn read_string(device: &nusb::Device, index: u8, timeout: Duration) -> Result<String, Error> {
+    let lang = device
+        .get_string_descriptor_supported_languages(timeout)?
+        .next()
+        .unwrap_or_default();
+    Ok(device
+        .get_string_descriptor(index, lang, timeout)
+        .unwrap_or_default())
+}
+
+/// Wait for a control transfer, cancelling it after `timeout` like the blocking calls do
+async fn with_timeout<T>(
+    dfu: &DfuNusb,
+    transfer: impl Future<Output = Completion<T>>,
+) -> Result<T, TransferError> {
+    let transfer = std::pin::pin!(transfer);
+    let sleep = std::pin::pin!(DfuAsyncIo::sleep(dfu, dfu.timeouts.control));
+    match futures::future::select(transfer, sleep).await {
+        futures::future::Either::Left((completion, _)) => completion.into_result(),
+        // Dropping the transfer cancels it
+        futures::future::Either::Right(_) => Err(TransferError::Cancelled),
     }
 }
 
@@ -120,12 +1166,23 @@ impl DfuIo for DfuNusb {
             recipient,
             request,
             value,
-            index: self.interface.interface_number() as u16,
+            index: self.request_index(),
         };
-        let r = self
+        if let Some(delay) = self.budget_delay(request, value, buffer.len()) {
+            self.timer.sleep_blocking(delay);
+        }
+        self.trace_sent(request, value, buffer.len());
+        match self
             .interface
-            .control_in_blocking(req, buffer, Duration::from_secs(3))?;
-        Ok(r)
+            .control_in_blocking(req, buffer, self.timeouts.control)
+        {
+            Ok(r) => {
+                self.trace_completed(&buffer[..r]);
+                self.check_busy(request, &buffer[..r])?;
+                Ok(r)
+            }
+            Err(e) => self.control_in_failed(request, e, buffer),
+        }
     }
 
     fn write_control(
@@ -135,21 +1192,35 @@ impl DfuIo for DfuNusb {
         value: u16,
         buffer: &[u8],
     ) -> Result<Self::Write, Self::Error> {
+        self.check_write(request)?;
+        let value = self.request_value(request, value);
         let (control_type, recipient) = split_request_type(request_type);
         let req = Control {
             control_type,
             recipient,
             request,
             value,
-            index: self.interface.interface_number() as u16,
+            index: self.request_index(),
         };
+        if let Some(delay) = self.budget_delay(request, value, buffer.len()) {
+            self.timer.sleep_blocking(delay);
+        }
+        self.trace_sent(request, value, buffer.len());
         let r = self
             .interface
-            .control_out_blocking(req, buffer, Duration::from_secs(3))?;
+            .control_out_blocking(req, buffer, self.timeouts.control)?;
+        self.report_control_out(request, value, buffer);
+        if let Some(delay) = self.pacing_delay(request, value, buffer) {
+            self.timer.sleep_blocking(delay);
+        }
         Ok(r)
     }
 
     fn usb_reset(&self) -> Result<Self::Reset, Self::Error> {
+        if self.detached_during_manifest() {
+            return Ok(());
+        }
+        self.report_reset();
         self.device.reset()?;
         Ok(())
     }
@@ -183,12 +1254,21 @@ impl DfuAsyncIo for DfuNusb {
             recipient,
             request,
             value,
-            index: self.interface.interface_number() as u16,
+            index: self.request_index(),
             length: buffer.len() as u16,
         };
-        let r = self.interface.control_in(req).await.into_result()?;
+        if let Some(delay) = self.budget_delay(request, value, buffer.len()) {
+            DfuAsyncIo::sleep(self, delay).await;
+        }
+        self.trace_sent(request, value, buffer.len());
+        let r = match with_timeout(self, self.interface.control_in(req)).await {
+            Ok(r) => r,
+            Err(e) => return self.control_in_failed(request, e, buffer),
+        };
         let len = buffer.len().min(r.len());
         buffer[0..len].copy_from_slice(&r[0..len]);
+        self.trace_completed(&buffer[..len]);
+        self.check_busy(request, &buffer[..len])?;
         Ok(len)
     }
 
@@ -199,39 +1279,40 @@ impl DfuAsyncIo for DfuNusb {
         value: u16,
         buffer: &[u8],
     ) -> Result<Self::Write, Self::Error> {
+        self.check_write(request)?;
+        let value = self.request_value(request, value);
         let (control_type, recipient) = split_request_type(request_type);
         let req = ControlOut {
             control_type,
             recipient,
             request,
             value,
-            index: self.interface.interface_number() as u16,
+            index: self.request_index(),
             data: buffer,
         };
-        let r = self.interface.control_out(req).await.into_result()?;
+        if let Some(delay) = self.budget_delay(request, value, buffer.len()) {
+            DfuAsyncIo::sleep(self, delay).await;
+        }
+        self.trace_sent(request, value, buffer.len());
+        let r = with_timeout(self, self.interface.control_out(req)).await?;
+        self.report_control_out(request, value, buffer);
+        if let Some(delay) = self.pacing_delay(request, value, buffer) {
+            DfuAsyncIo::sleep(self, delay).await;
+        }
         Ok(r.actual_length())
     }
 
     async fn usb_reset(&self) -> Result<Self::Reset, Self::Error> {
+        if self.detached_during_manifest() {
+            return Ok(());
+        }
+        self.report_reset();
         self.device.reset()?;
         Ok(())
     }
 
-    #[cfg(feature = "tokio")]
-    async fn sleep(&self, duration: Duration) {
-        tokio::time::sleep(duration).await
-    }
-
-    #[cfg(feature = "async-std")]
     async fn sleep(&self, duration: Duration) {
-        async_std::task::sleep(duration).await
-    }
-
-    #[cfg(not(any(feature = "tokio", feature = "async-std")))]
-    async fn sleep(&self, duration: Duration) {
-        compile_error!(
-            "You must select an async runtime through the features: tokio, asyncstd, ...",
-        )
+        self.timer.sleep(duration).await
     }
 
     fn protocol(&self) -> &dfu_core::DfuProtocol<Self::MemoryLayout> {
@@ -0,0 +1,72 @@
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use fs4::{FileExt, TryLockError};
+
+use crate::{DeviceIdentity, Error};
+
+/// Advisory lock on a physical device, held on a lock file keyed on its port
+///
+/// This keeps cooperating processes from talking to the same bootloader at the same time,
+/// even on platforms where claiming an interface isn't exclusive. The lock is an OS file lock
+/// (`flock` or `LockFileEx`), which the kernel releases when the process exits, so a crashed
+/// process doesn't leave the device locked. The file itself stays behind; it holds the id of
+/// the process owning the lock, for error messages.
+#[derive(Debug)]
+pub struct DeviceLock {
+    path: PathBuf,
+    file: File,
+}
+
+impl DeviceLock {
+    /// Acquire the lock of the device in `dir`, failing with [`Error::DeviceLocked`] if another
+    /// process holds it
+    pub fn acquire(dir: &Path, identity: &DeviceIdentity) -> Result<Self, Error> {
+        let name: String = identity
+            .port()
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+            .collect();
+        let path = dir.join(format!("dfu-nusb-{name}.lock"));
+
+        // Not truncated before locking, so the id of the current owner survives
+        let mut file = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)?;
+        // Not the inherent method of newer Rust versions
+        match FileExt::try_lock(&file) {
+            Ok(()) => (),
+            Err(TryLockError::WouldBlock) => {
+                let owner = fs::read_to_string(&path).unwrap_or_default();
+                return Err(Error::DeviceLocked(format!(
+                    "lock file {} held by process {}",
+                    path.display(),
+                    owner.trim()
+                )));
+            }
+            Err(TryLockError::Error(e)) => return Err(e.into()),
+        }
+        file.set_len(0)?;
+        let _ = writeln!(file, "{}", std::process::id());
+
+        Ok(Self { path, file })
+    }
+
+    /// Path of the lock file
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for DeviceLock {
+    fn drop(&mut self) {
+        // The file isn't removed: another process may have opened it already and be about to
+        // lock it, which it couldn't tell from a new file created by a third one
+        let _ = self.file.set_len(0);
+        let _ = FileExt::unlock(&self.file);
+    }
+}
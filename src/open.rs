@@ -0,0 +1,232 @@
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use nusb::transfer::{Control, ControlType, Recipient, TransferError};
+
+use crate::{
+    list_devices, timer::default_timer, DeviceFilter, DeviceLock, DfuDeviceInfo, DfuNusb, Error,
+    Timeouts, Timer, DFU_GETSTATE, DFU_GETSTATUS,
+};
+
+/// Request sent right after opening to check the interface speaks DFU
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Probe {
+    /// DFU_GETSTATUS, supported by every DFU device
+    GetStatus,
+    /// DFU_GETSTATE, which doesn't trigger any state transition
+    GetState,
+}
+
+/// Options used to open a DFU interface of a device
+#[derive(Debug, Clone, Default)]
+pub struct OpenOptions {
+    interface: u8,
+    alt: u8,
+    configuration: Option<u8>,
+    lock_dir: Option<PathBuf>,
+    pub(crate) timeouts: Timeouts,
+    probe: Option<Probe>,
+    fallback_transfer_size: Option<u16>,
+    timer: Option<Arc<dyn Timer>>,
+}
+
+impl OpenOptions {
+    /// Create options opening interface 0, alternative setting 0
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the number of the DFU interface
+    pub fn interface(mut self, interface: u8) -> Self {
+        self.interface = interface;
+        self
+    }
+
+    /// Set the alternative setting of the DFU interface
+    pub fn alt(mut self, alt: u8) -> Self {
+        self.alt = alt;
+        self
+    }
+
+    /// Select the USB configuration with the given `bConfigurationValue` before claiming the
+    /// interface
+    ///
+    /// This is needed for devices which enumerate unconfigured or expose the DFU interface in a
+    /// non-default configuration. Selecting a configuration isn't supported on Windows.
+    pub fn configuration(mut self, configuration: u8) -> Self {
+        self.configuration = Some(configuration);
+        self
+    }
+
+    /// Set the timeouts of the device, including those of the requests done while opening it
+    pub fn timeouts(mut self, timeouts: Timeouts) -> Self {
+        self.timeouts = timeouts;
+        self
+    }
+
+    /// Send `probe` right after opening, failing with [`Error::NotADfuInterface`] if the
+    /// interface doesn't answer it
+    ///
+    /// This catches a wrong interface number before committing to a download, rather than
+    /// failing later with a stall.
+    pub fn probe(mut self, probe: Probe) -> Self {
+        self.probe = Some(probe);
+        self
+    }
+
+    /// Set the transfer size used for devices advertising a wTransferSize of 0
+    ///
+    /// Such devices are opened with a transfer size of 1024 bytes by default; the substitution
+    /// is reported by [`DfuNusb::warnings`].
+    pub fn fallback_transfer_size(mut self, size: u16) -> Self {
+        self.fallback_transfer_size = Some(size);
+        self
+    }
+
+    /// Use `timer` for the sleeps of the opened device, see [`DfuNusb::timer`]
+    pub fn timer(mut self, timer: Arc<dyn Timer>) -> Self {
+        self.timer = Some(timer);
+        self
+    }
+
+    /// The timer set with [`OpenOptions::timer`]
+    pub(crate) fn timer_or_default(&self) -> Arc<dyn Timer> {
+        self.timer.clone().unwrap_or_else(default_timer)
+    }
+
+    /// Hold a [`DeviceLock`] in `dir` while the device is open
+    ///
+    /// This only applies to [`OpenOptions::open`] and [`OpenOptions::open_by_path`], which know
+    /// the port of the device.
+    pub fn lock_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.lock_dir = Some(dir.into());
+        self
+    }
+
+    /// Open the device
+    pub fn open(&self, info: &DfuDeviceInfo) -> Result<DfuNusb, Error> {
+        let identity = info.identity();
+        let lock = match &self.lock_dir {
+            Some(dir) => Some(DeviceLock::acquire(dir, &identity)?),
+            None => None,
+        };
+        let device = info.info().open()?;
+        let mut dfu = self.open_device(device)?;
+        dfu.identity = Some(identity);
+        dfu.lock = lock;
+        Ok(dfu)
+    }
+
+    /// Open the device with the given platform specific path
+    ///
+    /// This is the sysfs path on Linux (e.g. `/sys/bus/usb/devices/1-1.2`, the udev `DEVPATH`
+    /// or just `1-1.2`), the device instance path (e.g. `USB\VID_0483&PID_DF11\123456`) or
+    /// its `GUID_DEVINTERFACE_USB_DEVICE` interface path (e.g.
+    /// `\\?\USB#VID_0483&PID_DF11#123456#{a5dcbf10-6530-11d2-901f-00c04fb951ed}`) on Windows and
+    /// the location ID on macOS. Interface paths of single functions of composite devices
+    /// (`&MI_xx`) aren't supported, as only whole devices are enumerated.
+    pub fn open_by_path(&self, path: &str) -> Result<DfuNusb, Error> {
+        let info = list_devices(&DeviceFilter::new().path(path))?
+            .pop()
+            .ok_or(Error::DeviceNotFound)?;
+        self.open(&info)
+    }
+
+    /// Open an already opened device
+    pub fn open_device(&self, device: nusb::Device) -> Result<DfuNusb, Error> {
+        if let Some(configuration) = self.configuration {
+            select_configuration(&device, configuration)?;
+        }
+        let interface = device.claim_interface(self.interface).map_err(|e| {
+            if is_busy_error(&e) {
+                Error::DeviceLocked(format!("interface {} already claimed", self.interface))
+            } else {
+                e.into()
+            }
+        })?;
+        let mut dfu = DfuNusb::open_with_timeouts(
+            device,
+            interface,
+            self.alt,
+            self.timeouts,
+            self.fallback_transfer_size,
+        )?;
+        if let Some(timer) = &self.timer {
+            dfu.timer(timer.clone());
+        }
+        if let Some(probe) = self.probe {
+            dfu.probe(probe)?;
+        }
+        Ok(dfu)
+    }
+}
+
+impl DfuNusb {
+    fn probe(&self, probe: Probe) -> Result<(), Error> {
+        // Request, response length and offset of the state in the response
+        let (request, expected, state) = match probe {
+            Probe::GetStatus => (DFU_GETSTATUS, 6, 4),
+            Probe::GetState => (DFU_GETSTATE, 1, 0),
+        };
+        let control = Control {
+            control_type: ControlType::Class,
+            recipient: Recipient::Interface,
+            request,
+            value: 0,
+            index: self.request_index(),
+        };
+        let mut buffer = [0; 6];
+        let not_dfu = || Error::NotADfuInterface(self.interface.interface_number());
+        let n = match self.interface.control_in_blocking(
+            control,
+            &mut buffer[..expected],
+            self.timeouts.control,
+        ) {
+            Ok(n) => n,
+            Err(TransferError::Stall) => return Err(not_dfu()),
+            Err(e) => return Err(e.into()),
+        };
+        // dfuERROR (10) is the highest state
+        if n < expected || buffer[state] > 10 {
+            return Err(not_dfu());
+        }
+        Ok(())
+    }
+}
+
+/// Returns whether an OS error indicates the interface is claimed by someone else
+fn is_busy_error(error: &nusb::Error) -> bool {
+    #[cfg(unix)]
+    const BUSY_ERRORS: &[i32] = &[16 /* EBUSY */];
+    #[cfg(windows)]
+    const BUSY_ERRORS: &[i32] = &[170 /* ERROR_BUSY */];
+    #[cfg(not(any(unix, windows)))]
+    const BUSY_ERRORS: &[i32] = &[];
+
+    error
+        .raw_os_error()
+        .is_some_and(|code| BUSY_ERRORS.contains(&code))
+}
+
+fn select_configuration(device: &nusb::Device, configuration: u8) -> Result<(), Error> {
+    if !device
+        .configurations()
+        .any(|c| c.configuration_value() == configuration)
+    {
+        return Err(Error::ConfigurationNotFound(configuration));
+    }
+    if device
+        .active_configuration()
+        .is_ok_and(|c| c.configuration_value() == configuration)
+    {
+        return Ok(());
+    }
+
+    device.set_configuration(configuration).map_err(|e| {
+        if e.kind() == std::io::ErrorKind::Unsupported {
+            Error::ConfigurationUnsupported
+        } else {
+            e.into()
+        }
+    })
+}
@@ -0,0 +1,25 @@
+use std::time::Duration;
+
+/// Throttling of firmware blocks sent to the device
+///
+/// Some bootloaders, or devices behind flaky hubs, corrupt data under sustained full-speed
+/// traffic. Pacing inserts a pause after every downloaded block; by default there is none.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Pacing {
+    /// Fixed pause after each block
+    pub block_delay: Duration,
+    /// Maximum average download rate in bytes per second
+    pub max_bandwidth: Option<u32>,
+}
+
+impl Pacing {
+    /// Pause to insert after a block of `length` bytes
+    pub(crate) fn delay(&self, length: usize) -> Duration {
+        let bandwidth = self
+            .max_bandwidth
+            .filter(|&bandwidth| bandwidth > 0)
+            .map(|bandwidth| Duration::from_secs_f64(length as f64 / f64::from(bandwidth)))
+            .unwrap_or_default();
+        self.block_delay + bandwidth
+    }
+}
@@ -0,0 +1,43 @@
+/// Padding of the firmware sent to the device
+///
+/// A few non-conforming bootloaders only accept full-size blocks or downloads starting at a
+/// page boundary. By default the firmware is sent as is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Padding {
+    /// Pad the last block to wTransferSize rather than sending a short block
+    pub final_block: bool,
+    /// Start DfuSe downloads at the start of the page containing the address, padding the
+    /// firmware at the front; the page is erased anyway
+    pub align_start: bool,
+    /// Byte used for padding, 0xff (erased flash) by default
+    pub fill: u8,
+}
+
+impl Default for Padding {
+    fn default() -> Self {
+        Self {
+            final_block: false,
+            align_start: false,
+            fill: 0xff,
+        }
+    }
+}
+
+/// Start of the page containing `address` in a memory starting at `base` with the given pages
+pub(crate) fn page_start(base: u32, pages: &[u32], address: u32) -> Option<u32> {
+    page_bounds(base, pages, address).map(|(start, _)| start)
+}
+
+/// Start and end of the page containing `address` in a memory starting at `base` with the
+/// given pages
+pub(crate) fn page_bounds(base: u32, pages: &[u32], address: u32) -> Option<(u32, u32)> {
+    let mut start = u64::from(base);
+    for &page in pages {
+        let end = start + u64::from(page);
+        if (start..end).contains(&u64::from(address)) {
+            return Some((u32::try_from(start).ok()?, u32::try_from(end).ok()?));
+        }
+        start = end;
+    }
+    None
+}
@@ -0,0 +1,369 @@
+use std::time::Duration;
+
+use dfu_core::{State, Status};
+
+use crate::session::SessionLog;
+use crate::{info, BlockHandler, WrittenBlock, DFUSE_ERASE, DFUSE_SET_ADDRESS, DFU_DNLOAD};
+
+/// Phase of a DFU operation
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+    /// Erasing flash pages before writing (DfuSe only)
+    Erase,
+    /// Writing firmware blocks to the device
+    Download,
+    /// Device is manifesting the downloaded firmware
+    Manifest,
+    /// Reading data from the device
+    Upload,
+    /// Reading back the firmware to verify it, after the download for plain DFU targets and
+    /// per contiguous region after the last block for DfuSe targets
+    Verify,
+    /// Device is being reset
+    Reset,
+}
+
+/// Progress event emitted while talking to the device
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Progress {
+    /// A new phase of the operation started
+    Phase(Phase),
+    /// A page is being erased
+    Erase {
+        /// Start address of the page
+        address: u32,
+        /// Index of the page within the pages erased by this download
+        index: usize,
+        /// Number of pages to erase, if known
+        total: Option<usize>,
+    },
+    /// A block with the given number of bytes was written to the device
+    Written(usize),
+    /// A block with the given number of bytes was read from the device
+    Read(usize),
+    /// The device has been busy processing the last request for the given time
+    Busy(Duration),
+}
+
+/// Receiver of [`Progress`] events
+///
+/// Besides closures, the senders of `futures` and `tokio` mpsc channels implement this, so
+/// async applications can consume the events from a task.
+pub trait ProgressHandler: Send {
+    /// Handle a single progress event
+    fn progress(&mut self, progress: Progress);
+}
+
+impl<F> ProgressHandler for F
+where
+    F: FnMut(Progress) + Send,
+{
+    fn progress(&mut self, progress: Progress) {
+        self(progress)
+    }
+}
+
+/// Forwards events to an async task; events are dropped once the receiver is gone
+impl ProgressHandler for futures::channel::mpsc::UnboundedSender<Progress> {
+    fn progress(&mut self, progress: Progress) {
+        let _ = self.unbounded_send(progress);
+    }
+}
+
+/// Forwards events to an async task without blocking; events are dropped while the channel is
+/// full
+impl ProgressHandler for futures::channel::mpsc::Sender<Progress> {
+    fn progress(&mut self, progress: Progress) {
+        let _ = self.try_send(progress);
+    }
+}
+
+/// Forwards events to an async task; events are dropped once the receiver is gone
+#[cfg(feature = "tokio")]
+impl ProgressHandler for tokio::sync::mpsc::UnboundedSender<Progress> {
+    fn progress(&mut self, progress: Progress) {
+        let _ = self.send(progress);
+    }
+}
+
+/// Forwards events to an async task without blocking; events are dropped while the channel is
+/// full
+#[cfg(feature = "tokio")]
+impl ProgressHandler for tokio::sync::mpsc::Sender<Progress> {
+    fn progress(&mut self, progress: Progress) {
+        let _ = self.try_send(progress);
+    }
+}
+
+/// [`ProgressHandler`] driving an [`indicatif::ProgressBar`]
+///
+/// Shows the current phase, the number of bytes written, the throughput and an ETA.
+#[cfg(feature = "indicatif")]
+#[derive(Clone)]
+pub struct ProgressBarAdapter {
+    bar: indicatif::ProgressBar,
+}
+
+#[cfg(feature = "indicatif")]
+impl ProgressBarAdapter {
+    /// Create a styled progress bar for a firmware of `total` bytes
+    pub fn new(total: u64) -> Self {
+        let bar = indicatif::ProgressBar::new(total);
+        bar.set_style(
+            indicatif::ProgressStyle::default_bar()
+                .template(
+                    "{spinner:.green} [{elapsed_precise}] [{bar:27.cyan/blue}] \
+                        {bytes}/{total_bytes} ({bytes_per_sec}) ({eta}) {msg:10}",
+                )
+                .expect("valid progress template")
+                .progress_chars("#>-"),
+        );
+        Self { bar }
+    }
+
+    /// Use an existing progress bar, keeping its style
+    pub fn with_bar(bar: indicatif::ProgressBar) -> Self {
+        Self { bar }
+    }
+
+    /// The underlying progress bar
+    pub fn bar(&self) -> &indicatif::ProgressBar {
+        &self.bar
+    }
+}
+
+#[cfg(feature = "indicatif")]
+impl ProgressHandler for ProgressBarAdapter {
+    fn progress(&mut self, progress: Progress) {
+        match progress {
+            Progress::Phase(Phase::Erase) => self.bar.set_message("Erasing"),
+            Progress::Phase(Phase::Download) => self.bar.set_message("Writing"),
+            Progress::Phase(Phase::Manifest) => self.bar.set_message("Manifesting"),
+            Progress::Phase(Phase::Upload) => self.bar.set_message("Reading"),
+            Progress::Phase(Phase::Verify) => {
+                self.bar.reset();
+                self.bar.set_message("Verifying");
+            }
+            Progress::Phase(Phase::Reset) => self.bar.finish_with_message("Resetting"),
+            Progress::Erase {
+                address,
+                index,
+                total,
+            } => match total {
+                Some(total) => self
+                    .bar
+                    .set_message(format!("Erasing {address:#010x} ({}/{total})", index + 1)),
+                None => self.bar.set_message(format!("Erasing {address:#010x}")),
+            },
+            Progress::Written(n) | Progress::Read(n) => self.bar.inc(n as u64),
+            Progress::Busy(_) => self.bar.tick(),
+        }
+    }
+}
+
+/// Derives [`Progress`] events from the control requests sent to the device
+#[derive(Default)]
+pub(crate) struct Reporter {
+    handlers: Vec<Box<dyn ProgressHandler>>,
+    block_handlers: Vec<Box<dyn BlockHandler>>,
+    phase: Option<Phase>,
+    block_index: usize,
+    /// Address set by the DfuSe set address command, which block numbers are based on
+    base_address: Option<u32>,
+    transfer_size: u32,
+    /// Block sent to the device whose status wasn't confirmed yet, for the block handlers
+    pending: Option<(Option<u32>, Vec<u8>)>,
+    erase_index: usize,
+    erase_total: Option<usize>,
+    session: Option<SessionLog>,
+}
+
+impl Reporter {
+    pub(crate) fn set_session(&mut self, session: SessionLog) {
+        self.session = Some(session);
+    }
+
+    pub(crate) fn session(&mut self) -> Option<&mut SessionLog> {
+        self.session.as_mut()
+    }
+
+    pub(crate) fn add_handler(&mut self, handler: Box<dyn ProgressHandler>) {
+        self.handlers.push(handler);
+    }
+
+    pub(crate) fn add_block_handler(&mut self, handler: Box<dyn BlockHandler>) {
+        self.block_handlers.push(handler);
+    }
+
+    pub(crate) fn phase(&self) -> Option<Phase> {
+        self.phase
+    }
+
+    fn emit(&mut self, progress: Progress) {
+        for handler in &mut self.handlers {
+            handler.progress(progress);
+        }
+    }
+
+    pub(crate) fn enter(&mut self, phase: Phase) {
+        if self.phase != Some(phase) {
+            info!("Entering {phase:?} phase");
+            self.phase = Some(phase);
+            self.erase_index = 0;
+            if let Some(session) = self.session.as_mut() {
+                session.enter(phase);
+            }
+            self.emit(Progress::Phase(phase));
+        }
+    }
+
+    /// Start a new download in blocks of `transfer_size` bytes which will erase `erase_total`
+    /// pages, if known
+    pub(crate) fn start_download(&mut self, erase_total: Option<usize>, transfer_size: u16) {
+        self.phase = None;
+        self.erase_total = erase_total;
+        self.block_index = 0;
+        self.base_address = None;
+        self.transfer_size = u32::from(transfer_size);
+        self.pending = None;
+    }
+
+    /// Report a control OUT request which completed successfully
+    pub(crate) fn control_out(&mut self, request: u8, value: u16, buffer: &[u8], dfuse: bool) {
+        if request != DFU_DNLOAD {
+            return;
+        }
+
+        match buffer {
+            [] => self.enter(Phase::Manifest),
+            // DfuSe commands are sent as block 0
+            &[DFUSE_ERASE, a, b, c, d] if dfuse && value == 0 => {
+                self.enter(Phase::Erase);
+                self.emit(Progress::Erase {
+                    address: u32::from_le_bytes([a, b, c, d]),
+                    index: self.erase_index,
+                    total: self.erase_total,
+                });
+                self.erase_index += 1;
+            }
+            &[DFUSE_SET_ADDRESS, a, b, c, d] if dfuse && value == 0 => {
+                // Also sent again when verifying through a CRC command or skipping bad sectors
+                self.base_address = Some(u32::from_le_bytes([a, b, c, d]));
+            }
+            _ if dfuse && value == 0 => (),
+            data => {
+                self.enter(Phase::Download);
+                if let Some(session) = self.session.as_mut() {
+                    session.written(data);
+                }
+                if !self.block_handlers.is_empty() {
+                    // DfuSe data blocks are numbered from 2
+                    let offset = u32::from(value.wrapping_sub(2)).wrapping_mul(self.transfer_size);
+                    let address = self.base_address.map(|base| base.wrapping_add(offset));
+                    self.pending = Some((address, data.to_vec()));
+                }
+                self.emit(Progress::Written(data.len()));
+            }
+        }
+    }
+
+    /// Report the state and status of a DFU_GETSTATUS answer
+    ///
+    /// The last block sent is handed to the block handlers once the device reports it was
+    /// written, and dropped if the device failed to write it.
+    pub(crate) fn status(&mut self, state: State, status: Status) {
+        match (state, status) {
+            (State::DfuDnbusy | State::DfuDnloadSync, Status::Ok) => (),
+            (_, Status::Ok) if state != State::DfuError => {
+                if let Some((address, data)) = self.pending.take() {
+                    let block = WrittenBlock::new(self.block_index, address, &data);
+                    for handler in &mut self.block_handlers {
+                        handler.block(&block);
+                    }
+                    self.block_index += 1;
+                }
+            }
+            _ => self.pending = None,
+        }
+    }
+
+    /// Report a block of `n` bytes read from the device
+    pub(crate) fn read(&mut self, n: usize) {
+        self.emit(Progress::Read(n));
+    }
+
+    /// Report the device being busy for `elapsed`
+    pub(crate) fn busy(&mut self, elapsed: Duration) {
+        self.emit(Progress::Busy(elapsed));
+    }
+
+    /// Report a USB reset of the device
+    pub(crate) fn reset(&mut self) {
+        self.enter(Phase::Reset);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::file::crc32;
+
+    /// Index, address and CRC of the blocks handed out
+    type Blocks = Arc<Mutex<Vec<(usize, Option<u32>, u32)>>>;
+
+    fn reporter() -> (Reporter, Blocks) {
+        let blocks = Arc::new(Mutex::new(Vec::new()));
+        let mut reporter = Reporter::default();
+        let sink = blocks.clone();
+        reporter.add_block_handler(Box::new(move |block: &WrittenBlock<'_>| {
+            sink.lock()
+                .unwrap()
+                .push((block.index, block.address, block.crc32))
+        }));
+        reporter.start_download(None, 4);
+        (reporter, blocks)
+    }
+
+    #[test]
+    fn block_reported_once_written() {
+        let (mut reporter, blocks) = reporter();
+        reporter.control_out(DFU_DNLOAD, 0, b"abcd", false);
+        assert!(blocks.lock().unwrap().is_empty());
+        reporter.status(State::DfuDnbusy, Status::Ok);
+        assert!(blocks.lock().unwrap().is_empty());
+        reporter.status(State::DfuDnloadIdle, Status::Ok);
+        reporter.status(State::DfuDnloadIdle, Status::Ok);
+        assert_eq!(*blocks.lock().unwrap(), [(0, None, !crc32(b"abcd"))]);
+    }
+
+    #[test]
+    fn failed_block_not_reported() {
+        let (mut reporter, blocks) = reporter();
+        reporter.control_out(DFU_DNLOAD, 0, b"abcd", false);
+        reporter.status(State::DfuError, Status::ErrWrite);
+        reporter.control_out(DFU_DNLOAD, 1, b"efgh", false);
+        reporter.status(State::DfuDnloadIdle, Status::Ok);
+        assert_eq!(*blocks.lock().unwrap(), [(0, None, !crc32(b"efgh"))]);
+    }
+
+    #[test]
+    fn dfuse_block_address() {
+        let (mut reporter, blocks) = reporter();
+        reporter.control_out(DFU_DNLOAD, 0, &[DFUSE_SET_ADDRESS, 0, 0, 0, 8], true);
+        reporter.status(State::DfuDnloadIdle, Status::Ok);
+        reporter.control_out(DFU_DNLOAD, 3, b"abcd", true);
+        reporter.status(State::DfuDnloadIdle, Status::Ok);
+        assert_eq!(
+            *blocks.lock().unwrap(),
+            [(0, Some(0x0800_0004), !crc32(b"abcd"))]
+        );
+    }
+
+    #[test]
+    fn zlib_crc() {
+        // Check value of the CRC-32 used by zlib
+        assert_eq!(WrittenBlock::new(0, None, b"123456789").crc32, 0xcbf4_3926);
+    }
+}
@@ -0,0 +1,47 @@
+use dfu_core::{asynchronous::DfuAsyncIo, Status};
+
+use crate::{DfuNusb, Error, ErrorKind, DFUSE_READ_UNPROTECT, DFU_DNLOAD, REQUEST_TYPE_OUT};
+
+impl Error {
+    /// Returns whether the device failed to write with errWRITE, as DfuSe devices do for
+    /// protected sectors
+    ///
+    /// Bad sectors and other write failures report errWRITE too, so this can't tell them from
+    /// a protection.
+    pub fn is_write_protected(&self) -> bool {
+        matches!(
+            self,
+            Error::Dfu(dfu_core::Error::StatusError(Status::ErrWrite))
+        )
+    }
+}
+
+impl DfuNusb {
+    /// Remove the flash protection of a DfuSe device, like the `unprotect` modifier of dfu-util
+    ///
+    /// The device mass erases its flash and resets itself, so this handle is unusable
+    /// afterwards; find the device again once it re-enumerated, e.g. with
+    /// [`DeviceIdentity::find`](crate::DeviceIdentity::find).
+    pub async fn unprotect(&self) -> Result<(), Error> {
+        if !self.is_dfuse() {
+            return Err(Error::DfuseRequired);
+        }
+        let _operation = self.begin_operation()?;
+        self.ensure_idle().await?;
+        DfuAsyncIo::write_control(
+            self,
+            REQUEST_TYPE_OUT,
+            DFU_DNLOAD,
+            0,
+            &[DFUSE_READ_UNPROTECT],
+        )
+        .await?;
+        // The command is executed on the next GETSTATUS, after which the device resets; losing
+        // it on the way is expected
+        match self.get_status().await {
+            Ok(_) => Ok(()),
+            Err(e) if matches!(e.kind(), ErrorKind::Disconnected | ErrorKind::Transient) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+}
@@ -0,0 +1,42 @@
+/// How wIndex is populated in DFU class requests
+///
+/// The DFU specification addresses class requests to the DFU interface, but a few
+/// non-conforming bootloaders only answer requests with a different wIndex.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum RequestIndex {
+    /// The number of the DFU interface, as required by the specification
+    #[default]
+    Interface,
+    /// Always 0
+    Zero,
+    /// The currently selected alternative setting
+    AltSetting,
+    /// A fixed value
+    Fixed(u16),
+}
+
+impl RequestIndex {
+    /// wIndex to use for a request to `interface` with `alt` selected
+    pub(crate) fn value(self, interface: u8, alt: u8) -> u16 {
+        match self {
+            RequestIndex::Interface => u16::from(interface),
+            RequestIndex::Zero => 0,
+            RequestIndex::AltSetting => u16::from(alt),
+            RequestIndex::Fixed(index) => index,
+        }
+    }
+}
+
+/// How the DFU_GETSTATUS requests sent after the end of a download are treated
+///
+/// Some bootloaders start the new firmware right after the last block, without answering
+/// the status requests which should confirm the download was complete.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum FinalStatus {
+    /// The device must answer, a timeout fails the download
+    #[default]
+    Required,
+    /// A timeout while manifesting the firmware counts as a successful download, as if the
+    /// device detached, see [`DfuNusb::detached_during_manifest`](crate::DfuNusb::detached_during_manifest)
+    TolerateTimeout,
+}
@@ -0,0 +1,183 @@
+use std::fmt;
+
+use dfu_core::functional_descriptor::FunctionalDescriptor;
+use dfu_core::DfuProtocol;
+
+use crate::{
+    read_string, Bootloader, DfuNusb, Error, RawDescriptors, DFUSE_ERASE, DFUSE_READ_UNPROTECT,
+    DFUSE_SET_ADDRESS,
+};
+
+/// Alternative setting of the DFU interface, as listed in a [`CapabilityReport`]
+#[derive(Debug, Clone)]
+pub struct ReportAltSetting {
+    /// Number of the alternative setting
+    pub alt: u8,
+    /// Name of the alternative setting; describes the memory layout for DfuSe targets
+    pub name: String,
+    /// Start address and page sizes parsed from the name, for DfuSe targets
+    pub memory: Option<(u32, Vec<u32>)>,
+}
+
+/// Everything the device tells about its DFU capabilities
+///
+/// Meant for diagnostics and for adding quirks for new devices; the [`Display`](fmt::Display)
+/// implementation gives a human readable summary.
+#[derive(Debug, Clone)]
+pub struct CapabilityReport {
+    /// Vendor and product IDs of the device
+    pub device_ids: Option<(u16, u16)>,
+    /// Manufacturer string of the device
+    pub manufacturer: Option<String>,
+    /// Product string of the device
+    pub product: Option<String>,
+    /// Serial number string of the device
+    pub serial: Option<String>,
+    /// Number of the DFU interface
+    pub interface: u8,
+    /// DFU functional descriptor: version, attributes, wTransferSize and wDetachTimeOut
+    pub descriptor: FunctionalDescriptor,
+    /// Whether the device speaks DfuSe
+    pub dfuse: bool,
+    /// Bootloader the device was recognised to run
+    pub bootloader: Option<Bootloader>,
+    /// All alternative settings of the DFU interface
+    pub alt_settings: Vec<ReportAltSetting>,
+    /// Number of bytes the selected target holds, see [`DfuNusb::capacity`]
+    pub capacity: Option<u64>,
+    /// Commands listed by the DfuSe Get command; `None` for plain DFU devices or if the
+    /// device refused the command
+    pub dfuse_commands: Option<Vec<u8>>,
+    /// Descriptors of the DFU interface exactly as advertised by the device
+    pub raw: RawDescriptors,
+}
+
+impl DfuNusb {
+    /// Collect the DFU capabilities of the device
+    ///
+    /// Besides reading descriptors, this sends the DfuSe Get command to DfuSe devices, which
+    /// requires the device to be idle.
+    pub async fn capability_report(&self) -> Result<CapabilityReport, Error> {
+        let _operation = self.begin_operation()?;
+        let mut alt_settings = Vec::new();
+        for alt in self.interface.descriptors() {
+            let name = match alt.string_index() {
+                Some(index) => read_string(&self.device, index, self.timeouts.control)?,
+                None => String::new(),
+            };
+            let memory = match DfuProtocol::new(&name, self.descriptor.dfu_version) {
+                Ok(DfuProtocol::Dfuse {
+                    address,
+                    memory_layout,
+                }) => Some((address, memory_layout.to_vec())),
+                _ => None,
+            };
+            alt_settings.push(ReportAltSetting {
+                alt: alt.alternate_setting(),
+                name,
+                memory,
+            });
+        }
+        let dfuse_commands = if self.is_dfuse() {
+            self.read_dfuse_commands().await.ok()
+        } else {
+            None
+        };
+
+        Ok(CapabilityReport {
+            device_ids: self.device_ids,
+            manufacturer: self.manufacturer.clone(),
+            product: self.product.clone(),
+            serial: self.serial.clone(),
+            interface: self.interface.interface_number(),
+            descriptor: self.descriptor,
+            dfuse: self.is_dfuse(),
+            bootloader: self.bootloader(),
+            alt_settings,
+            capacity: self.capacity(),
+            dfuse_commands,
+            raw: self.raw_descriptors(),
+        })
+    }
+}
+
+/// Name of a DfuSe command, as listed by the Get command
+fn command_name(command: u8) -> Option<&'static str> {
+    match command {
+        0x00 => Some("get"),
+        DFUSE_SET_ADDRESS => Some("set address"),
+        DFUSE_ERASE => Some("erase"),
+        DFUSE_READ_UNPROTECT => Some("read unprotect"),
+        _ => None,
+    }
+}
+
+impl fmt::Display for CapabilityReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.device_ids {
+            Some((vid, pid)) => write!(f, "Device: [{vid:04x}:{pid:04x}]")?,
+            None => write!(f, "Device: [unknown]")?,
+        }
+        writeln!(
+            f,
+            " manufacturer=\"{}\" product=\"{}\" serial=\"{}\"",
+            self.manufacturer.as_deref().unwrap_or(""),
+            self.product.as_deref().unwrap_or(""),
+            self.serial.as_deref().unwrap_or(""),
+        )?;
+        let descriptor = &self.descriptor;
+        let (major, minor) = descriptor.dfu_version;
+        writeln!(f, "Interface: {}", self.interface)?;
+        writeln!(
+            f,
+            "DFU version: {:#06x} ({})",
+            u16::from_be_bytes([major, minor]),
+            if self.dfuse { "DfuSe" } else { "DFU" }
+        )?;
+        let attributes: Vec<_> = [
+            (descriptor.can_download, "download"),
+            (descriptor.can_upload, "upload"),
+            (descriptor.manifestation_tolerant, "manifestation tolerant"),
+            (descriptor.will_detach, "will detach"),
+        ]
+        .into_iter()
+        .filter_map(|(set, name)| set.then_some(name))
+        .collect();
+        writeln!(f, "Attributes: {}", attributes.join(", "))?;
+        if let Some(bootloader) = self.bootloader {
+            writeln!(f, "Bootloader: {bootloader}")?;
+        }
+        writeln!(f, "wTransferSize: {}", descriptor.transfer_size)?;
+        writeln!(f, "wDetachTimeOut: {} ms", descriptor.detach_timeout)?;
+        for alt in &self.alt_settings {
+            writeln!(f, "Alt {}: \"{}\"", alt.alt, alt.name)?;
+            if let Some((address, pages)) = &alt.memory {
+                let size: u64 = pages.iter().map(|&page| u64::from(page)).sum();
+                writeln!(
+                    f,
+                    "  {address:#010x}-{:#010x}, {} pages, {size} bytes",
+                    (u64::from(*address) + size).saturating_sub(1),
+                    pages.len(),
+                )?;
+            }
+        }
+        if let Some(capacity) = self.capacity {
+            writeln!(f, "Capacity: {capacity} bytes")?;
+        }
+        if let Some(commands) = &self.dfuse_commands {
+            let commands: Vec<_> = commands
+                .iter()
+                .map(|&command| match command_name(command) {
+                    Some(name) => format!("{command:#04x} ({name})"),
+                    None => format!("{command:#04x}"),
+                })
+                .collect();
+            writeln!(f, "DfuSe commands: {}", commands.join(", "))?;
+        }
+        if let Some(functional) = &self.raw.functional {
+            let hex: Vec<_> = functional.iter().map(|b| format!("{b:02x}")).collect();
+            write!(f, "Functional descriptor: {}", hex.join(" "))?;
+        }
+        Ok(())
+    }
+}
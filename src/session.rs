@@ -0,0 +1,165 @@
+use std::fmt::{self, Write as _};
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use crate::Phase;
+
+/// Audit trail of the operations on a device, written as JSON lines
+///
+/// Every line is a JSON object with an `event` field:
+/// - `session`: identity of the device, when the log is attached, including the ids it had
+///   before re-enumerating with other ones
+/// - `download`: parameters of a download, when it starts, including the `digest` the
+///   firmware source declares (see [`FirmwareSource::digest`](crate::FirmwareSource::digest))
+/// - `phase`: a phase (see [`Phase`]) ended, with its duration
+/// - `recovery`: the download went on or was retried after a failure, with the `action`
+///   taken: `bad_sector` (with its `address`, see
+///   [`BadSectorPolicy`](crate::BadSectorPolicy)), `final_status` (the device `detached` or
+///   left the final status request `unanswered`), and from
+///   [`flash_and_verify`](crate::flash_and_verify) `unprotect` and `replug` (with the
+///   `attempt`)
+/// - `result`: outcome of a download, with the number of bytes written and their CRC-32, and
+///   on success the CRC-32 of the firmware as read from its source, without padding
+///
+/// All events carry a `time` field with the Unix time in milliseconds. Failing to write the
+/// log doesn't fail the operation. Clones write to the same writer, e.g. to follow a device
+/// through a replug, see [`FlashOptions::session_log`](crate::FlashOptions::session_log).
+#[derive(Clone)]
+pub struct SessionLog {
+    writer: Arc<Mutex<Box<dyn Write + Send>>>,
+    phase: Option<(Phase, Instant)>,
+    started: Option<Instant>,
+    bytes: usize,
+    crc: u32,
+}
+
+/// Fields of a JSON object, built up in order
+#[derive(Default)]
+pub(crate) struct JsonFields(String);
+
+impl JsonFields {
+    pub(crate) fn string(mut self, name: &str, value: Option<&str>) -> Self {
+        let _ = write!(self.0, ",\"{name}\":");
+        match value {
+            Some(value) => {
+                self.0.push('"');
+                for c in value.chars() {
+                    match c {
+                        '"' => self.0.push_str("\\\""),
+                        '\\' => self.0.push_str("\\\\"),
+                        c if c.is_control() => {
+                            let _ = write!(self.0, "\\u{:04x}", c as u32);
+                        }
+                        c => self.0.push(c),
+                    }
+                }
+                self.0.push('"');
+            }
+            None => self.0.push_str("null"),
+        }
+        self
+    }
+
+    pub(crate) fn number(mut self, name: &str, value: Option<u128>) -> Self {
+        match value {
+            Some(value) => write!(self.0, ",\"{name}\":{value}"),
+            None => write!(self.0, ",\"{name}\":null"),
+        }
+        .unwrap();
+        self
+    }
+
+    pub(crate) fn bool(mut self, name: &str, value: bool) -> Self {
+        write!(self.0, ",\"{name}\":{value}").unwrap();
+        self
+    }
+}
+
+impl SessionLog {
+    /// Write the log to `writer`, e.g. a file opened for appending
+    pub fn new(writer: impl Write + Send + 'static) -> Self {
+        Self {
+            writer: Arc::new(Mutex::new(Box::new(writer))),
+            phase: None,
+            started: None,
+            bytes: 0,
+            crc: 0xffff_ffff,
+        }
+    }
+
+    pub(crate) fn record(&mut self, event: &str, fields: JsonFields) {
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        let mut writer = self.writer.lock().unwrap();
+        let _ = writeln!(
+            writer,
+            "{{\"event\":\"{event}\",\"time\":{time}{}}}",
+            fields.0
+        );
+        let _ = writer.flush();
+    }
+
+    /// Log a retry or a recovery from a failure, see [`SessionLog`]
+    pub(crate) fn recovery(&mut self, action: &str, fields: JsonFields) {
+        let mut all = JsonFields::default().string("action", Some(action));
+        all.0.push_str(&fields.0);
+        self.record("recovery", all);
+    }
+
+    /// Start logging a download with the given parameters
+    pub(crate) fn start(&mut self, fields: JsonFields) {
+        self.started = Some(Instant::now());
+        self.bytes = 0;
+        self.crc = 0xffff_ffff;
+        self.record("download", fields);
+    }
+
+    pub(crate) fn written(&mut self, data: &[u8]) {
+        self.bytes += data.len();
+        self.crc = crate::file::crc32_update(self.crc, data);
+    }
+
+    pub(crate) fn enter(&mut self, phase: Phase) {
+        self.end_phase();
+        self.phase = Some((phase, Instant::now()));
+    }
+
+    fn end_phase(&mut self) {
+        if let Some((phase, since)) = self.phase.take() {
+            let fields = JsonFields::default()
+                .string("phase", Some(&format!("{phase:?}").to_lowercase()))
+                .number("duration_ms", Some(since.elapsed().as_millis()));
+            self.record("phase", fields);
+        }
+    }
+
+    /// Log the outcome of the download, with the CRC-32 of the firmware if it succeeded
+    pub(crate) fn finish(&mut self, result: Result<u32, String>) {
+        self.end_phase();
+        let (firmware_crc, error) =
+            result.map_or_else(|e| (None, Some(e)), |crc| (Some(crc), None));
+        let fields = JsonFields::default()
+            .bool("success", error.is_none())
+            .string("error", error.as_deref())
+            .number("bytes", Some(self.bytes as u128))
+            .string("crc32", Some(&format!("{:08x}", !self.crc)))
+            .string(
+                "firmware_crc32",
+                firmware_crc.map(|crc| format!("{crc:08x}")).as_deref(),
+            )
+            .number(
+                "duration_ms",
+                self.started.take().map(|s| s.elapsed().as_millis()),
+            );
+        self.record("result", fields);
+    }
+}
+
+impl fmt::Debug for SessionLog {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SessionLog").finish_non_exhaustive()
+    }
+}
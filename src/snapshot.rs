@@ -0,0 +1,272 @@
+use std::fmt;
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
+
+use dfu_core::{State, Status};
+
+use crate::{
+    BadSectorPolicy, DfuNusb, FinalStatus, Pacing, Padding, Phase, RequestIndex, Timeouts,
+    DFU_ABORT, DFU_CLRSTATUS, DFU_DETACH, DFU_DNLOAD, DFU_GETSTATE, DFU_GETSTATUS, DFU_UPLOAD,
+};
+
+/// Control request last sent to the device, as listed in a [`DebugSnapshot`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SentRequest {
+    /// bRequest, e.g. 1 for DFU_DNLOAD
+    pub request: u8,
+    /// wValue, the block number of DFU_DNLOAD and DFU_UPLOAD
+    pub value: u16,
+    /// wLength
+    pub length: usize,
+    /// Whether the device completed the request
+    pub completed: bool,
+    /// Time since the request was sent
+    pub elapsed: Duration,
+}
+
+impl SentRequest {
+    /// Name of the request, for the DFU class requests
+    pub fn name(&self) -> Option<&'static str> {
+        request_name(self.request)
+    }
+}
+
+/// The crate's view of the session with a device, see [`DfuNusb::debug_snapshot`]
+///
+/// The [`Display`](fmt::Display) implementation gives a summary meant to be pasted into bug
+/// reports, e.g. when a download hangs.
+#[derive(Debug, Clone)]
+pub struct DebugSnapshot {
+    /// Vendor and product IDs of the device
+    pub device_ids: Option<(u16, u16)>,
+    /// Number of the DFU interface
+    pub interface: u8,
+    /// Selected alternative setting
+    pub alt: u8,
+    /// Name of the selected alternative setting
+    pub alt_name: String,
+    /// Whether the device speaks DfuSe
+    pub dfuse: bool,
+    /// wTransferSize in effect
+    pub transfer_size: u16,
+    /// Whether an operation, like a download, is running
+    pub operation: bool,
+    /// Phase of the current or last operation
+    pub phase: Option<Phase>,
+    /// Control request last sent to the device
+    pub last_request: Option<SentRequest>,
+    /// State reported by the last DFU_GETSTATUS answered by the device
+    pub state: Option<State>,
+    /// Status reported by the last DFU_GETSTATUS answered by the device
+    pub status: Option<Status>,
+    /// Block number of the last DFU_DNLOAD or DFU_UPLOAD
+    pub block: Option<u16>,
+    /// Firmware bytes sent by the current or last operation
+    pub bytes_written: u64,
+    /// Bytes read by the current or last operation
+    pub bytes_read: u64,
+    /// Time left before the device is given up on, while it is busy
+    pub busy_timeout: Option<Duration>,
+    /// Timeouts in effect
+    pub timeouts: Timeouts,
+    /// Address downloads go to, for DfuSe targets
+    pub address: Option<u32>,
+    /// Whether safety checks are bypassed
+    pub force: bool,
+    /// Whether erasing is skipped
+    pub skip_erase: bool,
+    /// Whether downloads are read back
+    pub verify: bool,
+    /// Throttling of downloads
+    pub pacing: Pacing,
+    /// Padding of downloads
+    pub padding: Padding,
+    /// Handling of DfuSe sectors failing with errWRITE
+    pub bad_sectors: BadSectorPolicy,
+    /// wIndex of class requests
+    pub request_index: RequestIndex,
+    /// Handling of the status requests at the end of a download
+    pub final_status: FinalStatus,
+}
+
+/// Requests and transfers seen since the device was opened, for [`DebugSnapshot`]
+#[derive(Default)]
+pub(crate) struct Trace {
+    last_request: Option<(u8, u16, usize, bool, Instant)>,
+    state: Option<State>,
+    status: Option<Status>,
+    block: Option<u16>,
+    bytes_written: u64,
+    bytes_read: u64,
+}
+
+impl Trace {
+    /// Start counting the bytes of a new operation
+    pub(crate) fn start_operation(&mut self) {
+        self.bytes_written = 0;
+        self.bytes_read = 0;
+    }
+
+    /// Record a request of `length` bytes being sent at `now`
+    pub(crate) fn sent(&mut self, request: u8, value: u16, length: usize, now: Instant) {
+        self.last_request = Some((request, value, length, false, now));
+        if matches!(request, DFU_DNLOAD | DFU_UPLOAD) {
+            self.block = Some(value);
+        }
+    }
+
+    /// Record the completion of the last request, with the data the device answered with
+    pub(crate) fn completed(&mut self, data: &[u8], dfuse: bool) {
+        let Some((request, value, length, completed, _)) = &mut self.last_request else {
+            return;
+        };
+        *completed = true;
+        match *request {
+            // DfuSe commands are sent as block 0
+            DFU_DNLOAD if !(dfuse && *value == 0) => self.bytes_written += *length as u64,
+            DFU_UPLOAD if !(dfuse && *value == 0) => self.bytes_read += data.len() as u64,
+            DFU_GETSTATUS if data.len() >= 6 => {
+                self.status = Some(data[0].into());
+                self.state = Some(data[4].into());
+            }
+            _ => (),
+        }
+    }
+}
+
+impl DfuNusb {
+    /// Collect the crate's view of the session with the device, for bug reports
+    ///
+    /// This doesn't talk to the device and can be called while an operation is running,
+    /// e.g. from another task once a download seems stuck.
+    pub fn debug_snapshot(&self) -> DebugSnapshot {
+        let now = self.timer.now();
+        let trace = self.trace.lock().unwrap();
+        let busy_timeout = self
+            .busy
+            .lock()
+            .unwrap()
+            .map(|(since, limit)| limit.saturating_sub(now.saturating_duration_since(since)));
+        DebugSnapshot {
+            device_ids: self.device_ids,
+            interface: self.interface.interface_number(),
+            alt: self.alt,
+            alt_name: self.alt_name.clone(),
+            dfuse: self.is_dfuse(),
+            transfer_size: self.descriptor.transfer_size,
+            operation: self.operation.load(Ordering::Relaxed),
+            phase: self.reporter.lock().unwrap().phase(),
+            last_request: trace
+                .last_request
+                .map(|(request, value, length, completed, sent)| SentRequest {
+                    request,
+                    value,
+                    length,
+                    completed,
+                    elapsed: now.saturating_duration_since(sent),
+                }),
+            state: trace.state,
+            status: trace.status,
+            block: trace.block,
+            bytes_written: trace.bytes_written,
+            bytes_read: trace.bytes_read,
+            busy_timeout,
+            timeouts: self.timeouts,
+            address: self.address(),
+            force: self.force,
+            skip_erase: self.skip_erase,
+            verify: self.verify,
+            pacing: self.pacing,
+            padding: self.padding,
+            bad_sectors: self.bad_sectors,
+            request_index: self.request_index,
+            final_status: self.final_status,
+        }
+    }
+}
+
+/// Name of a DFU class request
+fn request_name(request: u8) -> Option<&'static str> {
+    match request {
+        DFU_DETACH => Some("DFU_DETACH"),
+        DFU_DNLOAD => Some("DFU_DNLOAD"),
+        DFU_UPLOAD => Some("DFU_UPLOAD"),
+        DFU_GETSTATUS => Some("DFU_GETSTATUS"),
+        DFU_CLRSTATUS => Some("DFU_CLRSTATUS"),
+        DFU_GETSTATE => Some("DFU_GETSTATE"),
+        DFU_ABORT => Some("DFU_ABORT"),
+        _ => None,
+    }
+}
+
+impl fmt::Display for DebugSnapshot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.device_ids {
+            Some((vid, pid)) => write!(f, "Device: [{vid:04x}:{pid:04x}]")?,
+            None => write!(f, "Device: [unknown]")?,
+        }
+        writeln!(
+            f,
+            " interface {} alt {} \"{}\" ({}, wTransferSize {})",
+            self.interface,
+            self.alt,
+            self.alt_name,
+            if self.dfuse { "DfuSe" } else { "DFU" },
+            self.transfer_size,
+        )?;
+        writeln!(
+            f,
+            "Operation: {}, phase {:?}",
+            if self.operation { "running" } else { "idle" },
+            self.phase,
+        )?;
+        match &self.last_request {
+            Some(request) => writeln!(
+                f,
+                "Last request: {} value {} length {}, {} {:?} ago",
+                request
+                    .name()
+                    .map_or_else(|| request.request.to_string(), str::to_string),
+                request.value,
+                request.length,
+                if request.completed {
+                    "completed, sent"
+                } else {
+                    "pending, sent"
+                },
+                request.elapsed,
+            )?,
+            None => writeln!(f, "Last request: none")?,
+        }
+        writeln!(
+            f,
+            "Last status: state {:?}, status {:?}",
+            self.state, self.status
+        )?;
+        writeln!(
+            f,
+            "Block: {:?}, {} bytes written, {} bytes read",
+            self.block, self.bytes_written, self.bytes_read
+        )?;
+        if let Some(timeout) = self.busy_timeout {
+            writeln!(f, "Busy timeout in: {timeout:?}")?;
+        }
+        writeln!(f, "Timeouts: {:?}", self.timeouts)?;
+        if let Some(address) = self.address {
+            writeln!(f, "Address: {address:#010x}")?;
+        }
+        writeln!(
+            f,
+            "Options: force={} skip_erase={} verify={}",
+            self.force, self.skip_erase, self.verify
+        )?;
+        writeln!(f, "Pacing: {:?}", self.pacing)?;
+        writeln!(f, "Padding: {:?}", self.padding)?;
+        writeln!(f, "Bad sectors: {:?}", self.bad_sectors)?;
+        write!(
+            f,
+            "Quirks: request index {:?}, final status {:?}",
+            self.request_index, self.final_status
+        )
+    }
+}
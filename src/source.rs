@@ -0,0 +1,328 @@
+use std::fs::File;
+use std::future::Future;
+use std::path::Path;
+
+use blocking::Unblock;
+use dfu_core::DfuProtocol;
+use futures::{AsyncRead, AsyncReadExt};
+
+use crate::file::crc32_update;
+use crate::{DfuNusb, Error};
+
+/// Source of a firmware image to download into a device
+///
+/// Implemented for byte slices, [`FileSource`], [`ReaderSource`] and, with the `reqwest`
+/// feature, [`HttpSource`], so updaters can stream an image from wherever it is stored
+/// straight into the device with [`DfuNusb::download_source`].
+pub trait FirmwareSource {
+    /// Size of the firmware in bytes, if known before reading it
+    fn length_hint(&self) -> Option<u32>;
+
+    /// Read the next chunk of the firmware into `buf`, returning its size; 0 marks the end
+    fn read_chunk(&mut self, buf: &mut [u8]) -> impl Future<Output = Result<usize, Error>>;
+
+    /// Expected CRC-32 (as computed by zlib or `crc32`) of the whole firmware
+    ///
+    /// It is checked once the end of the firmware was read, before the download is completed,
+    /// so a corrupted image fails with [`Error::DigestMismatch`] rather than being started.
+    fn digest(&self) -> Option<u32> {
+        None
+    }
+}
+
+impl<S: FirmwareSource> FirmwareSource for &mut S {
+    fn length_hint(&self) -> Option<u32> {
+        (**self).length_hint()
+    }
+
+    async fn read_chunk(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        (**self).read_chunk(buf).await
+    }
+
+    fn digest(&self) -> Option<u32> {
+        (**self).digest()
+    }
+}
+
+impl FirmwareSource for &[u8] {
+    fn length_hint(&self) -> Option<u32> {
+        u32::try_from(self.len()).ok()
+    }
+
+    async fn read_chunk(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        let n = buf.len().min(self.len());
+        buf[..n].copy_from_slice(&self[..n]);
+        *self = &self[n..];
+        Ok(n)
+    }
+}
+
+/// Firmware read from a local file
+///
+/// Reads run on a thread pool, so a slow disk or network file system doesn't stall the
+/// executor driving the download.
+#[derive(Debug)]
+pub struct FileSource {
+    file: Unblock<File>,
+    length: Option<u32>,
+    digest: Option<u32>,
+}
+
+impl FileSource {
+    /// Open the firmware file at `path`
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, Error> {
+        let file = File::open(path).map_err(Error::Io)?;
+        let length = u32::try_from(file.metadata().map_err(Error::Io)?.len()).ok();
+        Ok(Self {
+            file: Unblock::new(file),
+            length,
+            digest: None,
+        })
+    }
+
+    /// Check the firmware against its expected CRC-32
+    pub fn crc32(mut self, crc: u32) -> Self {
+        self.digest = Some(crc);
+        self
+    }
+}
+
+impl FirmwareSource for FileSource {
+    fn length_hint(&self) -> Option<u32> {
+        self.length
+    }
+
+    async fn read_chunk(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        self.file.read(buf).await.map_err(Error::Io)
+    }
+
+    fn digest(&self) -> Option<u32> {
+        self.digest
+    }
+}
+
+/// Firmware read from an [`AsyncRead`], like a pipe or socket
+#[derive(Debug)]
+pub struct ReaderSource<R> {
+    reader: R,
+    length: Option<u32>,
+    digest: Option<u32>,
+}
+
+impl<R: AsyncRead + Unpin> ReaderSource<R> {
+    /// Read the firmware from `reader`, until its end
+    pub fn new(reader: R) -> Self {
+        Self {
+            reader,
+            length: None,
+            digest: None,
+        }
+    }
+
+    /// Set the size of the firmware, if known up-front
+    pub fn length(mut self, length: u32) -> Self {
+        self.length = Some(length);
+        self
+    }
+
+    /// Check the firmware against its expected CRC-32
+    pub fn crc32(mut self, crc: u32) -> Self {
+        self.digest = Some(crc);
+        self
+    }
+}
+
+impl<R: AsyncRead + Unpin> FirmwareSource for ReaderSource<R> {
+    fn length_hint(&self) -> Option<u32> {
+        self.length
+    }
+
+    async fn read_chunk(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        self.reader.read(buf).await.map_err(Error::Io)
+    }
+
+    fn digest(&self) -> Option<u32> {
+        self.digest
+    }
+}
+
+/// Firmware downloaded over HTTP(S)
+///
+/// The body is streamed into the device as it arrives; its length is taken from the
+/// `Content-Length` header when the server sends one.
+#[cfg(feature = "reqwest")]
+#[derive(Debug)]
+pub struct HttpSource {
+    response: reqwest::Response,
+    pending: Vec<u8>,
+    position: usize,
+    digest: Option<u32>,
+}
+
+#[cfg(feature = "reqwest")]
+impl HttpSource {
+    /// Request the firmware at `url`, failing on an error status
+    pub async fn open(url: impl reqwest::IntoUrl) -> Result<Self, Error> {
+        Self::from_response(reqwest::get(url).await?)
+    }
+
+    /// Stream the body of a response, e.g. of a request needing authentication
+    pub fn from_response(response: reqwest::Response) -> Result<Self, Error> {
+        Ok(Self {
+            response: response.error_for_status()?,
+            pending: Vec::new(),
+            position: 0,
+            digest: None,
+        })
+    }
+
+    /// Check the firmware against its expected CRC-32
+    pub fn crc32(mut self, crc: u32) -> Self {
+        self.digest = Some(crc);
+        self
+    }
+}
+
+#[cfg(feature = "reqwest")]
+impl FirmwareSource for HttpSource {
+    fn length_hint(&self) -> Option<u32> {
+        self.response
+            .content_length()
+            .and_then(|length| u32::try_from(length).ok())
+    }
+
+    async fn read_chunk(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        while self.position == self.pending.len() {
+            match self.response.chunk().await? {
+                Some(chunk) => {
+                    self.pending = chunk.to_vec();
+                    self.position = 0;
+                }
+                None => return Ok(0),
+            }
+        }
+        let n = buf.len().min(self.pending.len() - self.position);
+        buf[..n].copy_from_slice(&self.pending[self.position..self.position + n]);
+        self.position += n;
+        Ok(n)
+    }
+
+    fn digest(&self) -> Option<u32> {
+        self.digest
+    }
+}
+
+/// Checks the digest of a source once its end is reached
+pub(crate) struct Checked<S> {
+    source: S,
+    crc: u32,
+}
+
+impl<S: FirmwareSource> Checked<S> {
+    pub(crate) fn new(source: S) -> Self {
+        Self {
+            source,
+            crc: 0xffff_ffff,
+        }
+    }
+}
+
+impl<S: FirmwareSource> FirmwareSource for Checked<S> {
+    fn length_hint(&self) -> Option<u32> {
+        self.source.length_hint()
+    }
+
+    fn digest(&self) -> Option<u32> {
+        self.source.digest()
+    }
+
+    async fn read_chunk(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        let n = self.source.read_chunk(buf).await?;
+        self.crc = crc32_update(self.crc, &buf[..n]);
+        if n == 0 {
+            if let Some(expected) = self.source.digest() {
+                let actual = !self.crc;
+                if actual != expected {
+                    return Err(Error::DigestMismatch { expected, actual });
+                }
+            }
+        }
+        Ok(n)
+    }
+}
+
+impl DfuNusb {
+    /// Download a firmware from any [`FirmwareSource`]
+    ///
+    /// Sources of unknown length are handled like [`DfuNusb::download_stream`] does: they are
+    /// read into memory first for DfuSe devices, which also checks their digest before
+    /// anything is written.
+    pub async fn download_source<S: FirmwareSource>(&self, source: S) -> Result<(), Error> {
+        let mut source = Checked::new(source);
+        match (source.length_hint(), &self.protocol) {
+            (Some(length), _) => {
+                self.check_download(Some(length))?;
+                self.download_reader(source, length, true).await
+            }
+            (None, DfuProtocol::Dfuse { .. }) => {
+                let mut firmware = Vec::new();
+                let mut buf = vec![0; usize::from(self.descriptor.transfer_size)];
+                loop {
+                    let n = source.read_chunk(&mut buf).await?;
+                    if n == 0 {
+                        break;
+                    }
+                    firmware.extend_from_slice(&buf[..n]);
+                }
+                let length = u32::try_from(firmware.len())
+                    .map_err(|_| dfu_core::Error::OutOfCapabilities)?;
+                self.check_download(Some(length))?;
+                self.download_reader(firmware.as_slice(), length, true)
+                    .await
+            }
+            // Plain DFU only stops at the end of the stream, the length is never used
+            (None, DfuProtocol::Dfu) => {
+                self.check_download(None)?;
+                self.download_reader(source, u32::MAX, true).await
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+
+    use futures::executor::block_on;
+
+    use super::*;
+    use crate::ErrorKind;
+
+    /// Reader failing like a broken pipe
+    struct Broken;
+
+    impl AsyncRead for Broken {
+        fn poll_read(
+            self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            _buf: &mut [u8],
+        ) -> Poll<std::io::Result<usize>> {
+            Poll::Ready(Err(std::io::ErrorKind::BrokenPipe.into()))
+        }
+    }
+
+    #[test]
+    fn missing_file() {
+        let error = FileSource::open("/nonexistent/firmware.bin").unwrap_err();
+        assert!(matches!(error, Error::Io(_)), "{error:?}");
+        assert_eq!(error.kind(), ErrorKind::Host);
+    }
+
+    #[test]
+    fn reader_error() {
+        let error = block_on(ReaderSource::new(Broken).read_chunk(&mut [0; 64])).unwrap_err();
+        assert!(matches!(error, Error::Io(_)), "{error:?}");
+        assert!(!error.is_retryable());
+    }
+}
@@ -0,0 +1,322 @@
+use std::fmt;
+use std::ops::{Deref, DerefMut};
+use std::time::{Duration, Instant};
+
+use crate::{DfuNusb, Error, Phase};
+
+/// Options of [`DfuNusb::stress_test`]
+#[derive(Debug, Clone)]
+pub struct StressOptions {
+    address: u32,
+    length: usize,
+    iterations: u32,
+    seed: u32,
+    stop_on_error: bool,
+}
+
+impl StressOptions {
+    /// Write and read back `length` bytes at `address`, 100 times
+    ///
+    /// The address is only used by DfuSe devices; plain DFU devices are written from the start
+    /// of the alternative setting, so select one which is safe to overwrite.
+    pub fn new(address: u32, length: usize) -> Self {
+        Self {
+            address,
+            length,
+            iterations: 100,
+            seed: 0x2545_f491,
+            stop_on_error: false,
+        }
+    }
+
+    /// Set the number of write and read back cycles
+    pub fn iterations(mut self, iterations: u32) -> Self {
+        self.iterations = iterations;
+        self
+    }
+
+    /// Set the seed of the pseudorandom patterns, to reproduce a run
+    pub fn seed(mut self, seed: u32) -> Self {
+        self.seed = seed;
+        self
+    }
+
+    /// Stop at the first mismatch or failed transfer rather than running all iterations
+    pub fn stop_on_error(mut self, stop: bool) -> Self {
+        self.stop_on_error = stop;
+        self
+    }
+}
+
+/// Outcome of one iteration of a stress test
+#[derive(Debug)]
+pub enum StressOutcome {
+    /// The data read back matched the pattern written
+    Passed,
+    /// The data read back differed from the pattern written
+    Mismatch {
+        /// Offset of the first differing byte
+        offset: usize,
+        /// Number of differing bytes, including those missing from a short read
+        count: usize,
+    },
+    /// Writing or reading failed
+    Failed(Error),
+}
+
+/// One write and read back cycle of a stress test
+#[derive(Debug)]
+pub struct StressIteration {
+    /// Seed of the pattern written
+    pub seed: u32,
+    /// Time taken to write the pattern, if it was written
+    pub write_time: Option<Duration>,
+    /// Time taken to read the pattern back, if it was read
+    pub read_time: Option<Duration>,
+    /// Result of the comparison
+    pub outcome: StressOutcome,
+}
+
+/// Results of a [`DfuNusb::stress_test`] run
+#[derive(Debug)]
+pub struct StressReport {
+    /// Number of bytes written per iteration
+    pub length: usize,
+    /// Iterations in the order they ran
+    pub iterations: Vec<StressIteration>,
+}
+
+impl StressReport {
+    /// Number of iterations which read back the pattern written
+    pub fn passed(&self) -> usize {
+        self.count(|outcome| matches!(outcome, StressOutcome::Passed))
+    }
+
+    /// Number of iterations which read back different data
+    pub fn mismatched(&self) -> usize {
+        self.count(|outcome| matches!(outcome, StressOutcome::Mismatch { .. }))
+    }
+
+    /// Number of iterations which failed with an error
+    pub fn failed(&self) -> usize {
+        self.count(|outcome| matches!(outcome, StressOutcome::Failed(_)))
+    }
+
+    fn count(&self, predicate: impl Fn(&StressOutcome) -> bool) -> usize {
+        self.iterations
+            .iter()
+            .filter(|iteration| predicate(&iteration.outcome))
+            .count()
+    }
+
+    /// Minimum, average and maximum throughput in bytes per second over the given durations
+    fn throughput(&self, times: impl Iterator<Item = Duration>) -> Option<(f64, f64, f64)> {
+        let rates: Vec<_> = times
+            .map(|time| self.length as f64 / time.as_secs_f64().max(f64::EPSILON))
+            .collect();
+        let min = rates.iter().copied().reduce(f64::min)?;
+        let max = rates.iter().copied().reduce(f64::max)?;
+        Some((min, rates.iter().sum::<f64>() / rates.len() as f64, max))
+    }
+
+    /// Minimum, average and maximum write throughput in bytes per second
+    pub fn write_throughput(&self) -> Option<(f64, f64, f64)> {
+        self.throughput(self.iterations.iter().filter_map(|i| i.write_time))
+    }
+
+    /// Minimum, average and maximum read throughput in bytes per second
+    pub fn read_throughput(&self) -> Option<(f64, f64, f64)> {
+        self.throughput(self.iterations.iter().filter_map(|i| i.read_time))
+    }
+}
+
+impl fmt::Display for StressReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, iteration) in self.iterations.iter().enumerate() {
+            match &iteration.outcome {
+                StressOutcome::Passed => continue,
+                StressOutcome::Mismatch { offset, count } => writeln!(
+                    f,
+                    "iteration {i} (seed {:#010x}): {count} bytes differ, first at offset {offset:#x}",
+                    iteration.seed
+                )?,
+                StressOutcome::Failed(error) => writeln!(
+                    f,
+                    "iteration {i} (seed {:#010x}): FAILED: {error}",
+                    iteration.seed
+                )?,
+            }
+        }
+        write!(
+            f,
+            "{} iterations of {} bytes: {} passed, {} mismatched, {} failed",
+            self.iterations.len(),
+            self.length,
+            self.passed(),
+            self.mismatched(),
+            self.failed()
+        )?;
+        for (name, throughput) in [
+            ("write", self.write_throughput()),
+            ("read", self.read_throughput()),
+        ] {
+            if let Some((min, average, max)) = throughput {
+                write!(
+                    f,
+                    "\n{name}: {average:.0} B/s average ({min:.0} min, {max:.0} max)"
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Fill `buffer` with a xorshift32 sequence seeded with `seed`
+fn fill_pattern(buffer: &mut [u8], seed: u32) {
+    // xorshift is stuck at 0
+    let mut state = if seed == 0 { 0x9e37_79b9 } else { seed };
+    for chunk in buffer.chunks_mut(4) {
+        state ^= state << 13;
+        state ^= state >> 17;
+        state ^= state << 5;
+        chunk.copy_from_slice(&state.to_le_bytes()[..chunk.len()]);
+    }
+}
+
+/// Settings of a device changed for a stress test, restored when dropped, also if the test
+/// is cancelled
+struct Settings<'a> {
+    device: &'a mut DfuNusb,
+    override_address: Option<u32>,
+    verify: bool,
+}
+
+impl<'a> Settings<'a> {
+    fn new(device: &'a mut DfuNusb) -> Self {
+        Self {
+            override_address: device.override_address,
+            verify: device.verify,
+            device,
+        }
+    }
+}
+
+impl Deref for Settings<'_> {
+    type Target = DfuNusb;
+
+    fn deref(&self) -> &DfuNusb {
+        self.device
+    }
+}
+
+impl DerefMut for Settings<'_> {
+    fn deref_mut(&mut self) -> &mut DfuNusb {
+        self.device
+    }
+}
+
+impl Drop for Settings<'_> {
+    fn drop(&mut self) {
+        self.device.override_address = self.override_address;
+        self.device.verify = self.verify;
+    }
+}
+
+impl DfuNusb {
+    /// Repeatedly write a pseudorandom pattern to a region and read it back, for validating
+    /// new bootloaders against this host stack
+    ///
+    /// Every iteration writes a different pattern (erasing the region first on DfuSe devices),
+    /// reads it back and compares it. Failed transfers are recorded and the device is brought
+    /// back to dfuIDLE before the next iteration; the test only ends early if that fails or
+    /// [`StressOptions::stop_on_error`] is set. DfuSe devices stay in DFU mode throughout,
+    /// plain DFU devices must be manifestation tolerant. Only point this at a region which is
+    /// safe to overwrite, and mind the wear of flash memories. A region running past the end of
+    /// the memory (see [`DfuNusb::capacity`]) fails with [`Error::FirmwareTooLarge`] unless
+    /// [`DfuNusb::force`] is set.
+    pub async fn stress_test(&mut self, options: &StressOptions) -> Result<StressReport, Error> {
+        if !self.descriptor.can_upload
+            || !(self.is_dfuse() || self.descriptor.manifestation_tolerant)
+        {
+            return Err(Error::VerifyNotSupported);
+        }
+        let length =
+            u32::try_from(options.length).map_err(|_| dfu_core::Error::OutOfCapabilities)?;
+        let mut device = Settings::new(self);
+        device.override_address = Some(options.address);
+        device.verify = false;
+        device.check_download(Some(length))?;
+
+        let mut report = StressReport {
+            length: options.length,
+            iterations: Vec::new(),
+        };
+        let mut pattern = vec![0; options.length];
+        for i in 0..options.iterations {
+            let seed = options.seed.wrapping_add(i);
+            fill_pattern(&mut pattern, seed);
+            let iteration = device.stress_iteration(&pattern, length, seed).await;
+            let failed = !matches!(iteration.outcome, StressOutcome::Passed);
+            let recover = matches!(iteration.outcome, StressOutcome::Failed(_));
+            report.iterations.push(iteration);
+            if failed && options.stop_on_error {
+                break;
+            }
+            // The failed iteration is in the report, there's no point going on without the
+            // device
+            if recover && device.ensure_idle().await.is_err() {
+                break;
+            }
+        }
+        Ok(report)
+    }
+
+    async fn stress_iteration(&self, pattern: &[u8], length: u32, seed: u32) -> StressIteration {
+        let mut iteration = StressIteration {
+            seed,
+            write_time: None,
+            read_time: None,
+            outcome: StressOutcome::Passed,
+        };
+
+        let start = Instant::now();
+        // DfuSe devices would start the firmware when leaving DFU mode
+        if let Err(e) = self
+            .download_reader(pattern, length, !self.is_dfuse())
+            .await
+        {
+            iteration.outcome = StressOutcome::Failed(e);
+            return iteration;
+        }
+        iteration.write_time = Some(start.elapsed());
+
+        let start = Instant::now();
+        let address = if self.is_dfuse() {
+            self.override_address.unwrap_or_default()
+        } else {
+            0
+        };
+        let mut read = Vec::with_capacity(pattern.len());
+        self.reset_polled();
+        if let Err(e) = self
+            .upload_into(address, pattern.len(), &mut read, Phase::Upload)
+            .await
+        {
+            iteration.outcome = StressOutcome::Failed(e);
+            return iteration;
+        }
+        iteration.read_time = Some(start.elapsed());
+
+        let differing = pattern.iter().zip(&read).filter(|(a, b)| a != b).count();
+        let count = differing + (pattern.len() - read.len());
+        if count > 0 {
+            let offset = pattern
+                .iter()
+                .zip(&read)
+                .position(|(a, b)| a != b)
+                .unwrap_or(read.len());
+            iteration.outcome = StressOutcome::Mismatch { offset, count };
+        }
+        iteration
+    }
+}
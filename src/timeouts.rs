@@ -0,0 +1,45 @@
+use std::time::Duration;
+
+/// Timeouts for the various phases of DFU operations
+///
+/// The busy timeouts bound how long the device may report itself busy (through GETSTATUS)
+/// after a single request before the operation fails with
+/// [`Error::BusyTimeout`](crate::Error::BusyTimeout).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timeouts {
+    /// Timeout of a single control transfer, in both the blocking and async API
+    pub control: Duration,
+    /// Time the device waits for a USB reset after a DETACH request, sent as its wValue
+    ///
+    /// If `None`, the wDetachTimeOut of the functional descriptor is sent; a longer value is
+    /// capped to it, as the device doesn't wait longer.
+    pub detach: Option<Duration>,
+    /// Maximum busy time for erasing a single page
+    pub erase_page: Duration,
+    /// Maximum busy time for a mass erase
+    pub mass_erase: Duration,
+    /// Maximum busy time for writing a single block
+    pub write_block: Duration,
+    /// Maximum busy time for manifesting the firmware
+    pub manifest: Duration,
+    /// Time to wait for the device to re-enumerate after a detach or reset
+    pub reset: Duration,
+    /// Maximum total busy time over a whole operation, failing with
+    /// [`Error::DevicePollTimeout`](crate::Error::DevicePollTimeout); unlimited if `None`
+    pub poll_total: Option<Duration>,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Self {
+            control: Duration::from_secs(3),
+            detach: None,
+            erase_page: Duration::from_secs(10),
+            mass_erase: Duration::from_secs(120),
+            write_block: Duration::from_secs(5),
+            manifest: Duration::from_secs(30),
+            reset: Duration::from_secs(5),
+            poll_total: None,
+        }
+    }
+}
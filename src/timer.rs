@@ -0,0 +1,173 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Condvar, Mutex, OnceLock, Weak};
+use std::task::{Context, Poll, Waker};
+use std::time::{Duration, Instant};
+
+/// Future returned by [`Timer::sleep`]
+pub type Sleep = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+/// Source of the sleeps and the clock used for status polling, retry backoff and waiting
+/// for devices
+///
+/// The default is [`TokioTimer`] or [`AsyncStdTimer`], depending on the runtime selected
+/// through the features, and [`StdTimer`] if none is. Set another one with
+/// [`DfuNusb::timer`](crate::DfuNusb::timer) or [`OpenOptions::timer`](crate::OpenOptions::timer)
+/// to run on a custom executor, or to run tests with virtual time rather than waiting for
+/// the timeouts of real devices.
+pub trait Timer: fmt::Debug + Send + Sync {
+    /// Wait for `duration` without blocking the executor
+    fn sleep(&self, duration: Duration) -> Sleep;
+
+    /// Block the calling thread for `duration`, used by the blocking [`DfuIo`](dfu_core::DfuIo)
+    /// implementation
+    fn sleep_blocking(&self, duration: Duration) {
+        std::thread::sleep(duration)
+    }
+
+    /// Current time, against which busy and replug timeouts are measured
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// [`Timer`] of the tokio runtime
+#[cfg(feature = "tokio")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TokioTimer;
+
+#[cfg(feature = "tokio")]
+impl Timer for TokioTimer {
+    fn sleep(&self, duration: Duration) -> Sleep {
+        Box::pin(tokio::time::sleep(duration))
+    }
+}
+
+/// [`Timer`] of the async-std runtime
+#[cfg(feature = "async-std")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct AsyncStdTimer;
+
+#[cfg(feature = "async-std")]
+impl Timer for AsyncStdTimer {
+    fn sleep(&self, duration: Duration) -> Sleep {
+        Box::pin(async_std::task::sleep(duration))
+    }
+}
+
+/// Runtime independent [`Timer`], waking sleeping futures from a background thread
+///
+/// Works with any executor; the thread is started on the first sleep and shared by all
+/// devices.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct StdTimer;
+
+impl Timer for StdTimer {
+    fn sleep(&self, duration: Duration) -> Sleep {
+        Box::pin(StdSleep {
+            deadline: Instant::now() + duration,
+            waker: None,
+        })
+    }
+}
+
+/// Waker of a sleeping future, shared with the timer thread
+type SharedWaker = Arc<Mutex<Option<Waker>>>;
+
+/// Deadlines of the sleeping futures, woken by the timer thread
+#[derive(Default)]
+struct Wheel {
+    sleepers: Mutex<Sleepers>,
+    changed: Condvar,
+}
+
+#[derive(Default)]
+struct Sleepers {
+    /// Futures by deadline; the sequence number keeps futures with the same deadline apart
+    by_deadline: BTreeMap<(Instant, u64), Weak<Mutex<Option<Waker>>>>,
+    next: u64,
+}
+
+impl Wheel {
+    /// The timer wheel, starting its thread on first use
+    fn get() -> &'static Wheel {
+        static WHEEL: OnceLock<Wheel> = OnceLock::new();
+        WHEEL.get_or_init(|| {
+            std::thread::Builder::new()
+                .name("dfu-nusb-timer".into())
+                .spawn(|| Wheel::get().run())
+                .expect("failed to spawn the timer thread");
+            Wheel::default()
+        })
+    }
+
+    fn insert(&self, deadline: Instant, waker: &SharedWaker) {
+        let mut sleepers = self.sleepers.lock().unwrap();
+        sleepers.next += 1;
+        let key = (deadline, sleepers.next);
+        sleepers.by_deadline.insert(key, Arc::downgrade(waker));
+        self.changed.notify_one();
+    }
+
+    fn run(&self) {
+        let mut sleepers = self.sleepers.lock().unwrap();
+        loop {
+            let now = Instant::now();
+            while let Some(entry) = sleepers.by_deadline.first_entry() {
+                if entry.key().0 > now {
+                    break;
+                }
+                // Dropped futures are gone already
+                if let Some(waker) = entry.remove().upgrade() {
+                    if let Some(waker) = waker.lock().unwrap().take() {
+                        waker.wake();
+                    }
+                }
+            }
+            sleepers = match sleepers.by_deadline.keys().next() {
+                Some(&(deadline, _)) => {
+                    let timeout = deadline.saturating_duration_since(now);
+                    self.changed.wait_timeout(sleepers, timeout).unwrap().0
+                }
+                None => self.changed.wait(sleepers).unwrap(),
+            };
+        }
+    }
+}
+
+/// Future returned by [`StdTimer::sleep`]
+struct StdSleep {
+    deadline: Instant,
+    waker: Option<SharedWaker>,
+}
+
+impl Future for StdSleep {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if Instant::now() >= self.deadline {
+            return Poll::Ready(());
+        }
+        match &self.waker {
+            Some(waker) => *waker.lock().unwrap() = Some(cx.waker().clone()),
+            None => {
+                let waker = Arc::new(Mutex::new(Some(cx.waker().clone())));
+                Wheel::get().insert(self.deadline, &waker);
+                self.waker = Some(waker);
+            }
+        }
+        Poll::Pending
+    }
+}
+
+/// The timer of the runtime selected through the features
+pub(crate) fn default_timer() -> Arc<dyn Timer> {
+    #[cfg(feature = "tokio")]
+    return Arc::new(TokioTimer);
+    #[cfg(all(feature = "async-std", not(feature = "tokio")))]
+    return Arc::new(AsyncStdTimer);
+    #[cfg(not(any(feature = "tokio", feature = "async-std")))]
+    Arc::new(StdTimer)
+}
@@ -0,0 +1,355 @@
+use std::time::Duration;
+
+use dfu_core::{asynchronous::DfuAsyncIo, State, Status};
+use futures::{AsyncWrite, AsyncWriteExt};
+
+use crate::file::{crc32, DfuSuffix, DfuseImage};
+use crate::{
+    DfuNusb, Error, Phase, DFUSE_SET_ADDRESS, DFU_ABORT, DFU_CLRSTATUS, DFU_DNLOAD, DFU_GETSTATUS,
+    DFU_UPLOAD, REQUEST_TYPE_IN, REQUEST_TYPE_OUT,
+};
+
+/// Decoded DFU_GETSTATUS response
+pub(crate) struct DeviceStatus {
+    pub(crate) status: Status,
+    pub(crate) poll_timeout: Duration,
+    pub(crate) state: State,
+}
+
+/// Device the request sequences of the crate are sent to: a [`DfuNusb`], or a simulated one
+/// in tests
+pub(crate) trait DfuDevice: DfuAsyncIo<Read = usize, Write = usize, Error = Error> {}
+
+impl<D: DfuAsyncIo<Read = usize, Write = usize, Error = Error>> DfuDevice for D {}
+
+pub(crate) async fn get_status<D: DfuDevice>(device: &D) -> Result<DeviceStatus, Error> {
+    let mut buffer = [0; 6];
+    let n = device
+        .read_control(REQUEST_TYPE_IN, DFU_GETSTATUS, 0, &mut buffer)
+        .await?;
+    if n < buffer.len() {
+        return Err(dfu_core::Error::ResponseTooShort {
+            got: n,
+            expected: buffer.len(),
+        }
+        .into());
+    }
+    Ok(DeviceStatus {
+        status: buffer[0].into(),
+        poll_timeout: Duration::from_millis(u64::from_le_bytes([
+            buffer[1], buffer[2], buffer[3], 0, 0, 0, 0, 0,
+        ])),
+        state: buffer[4].into(),
+    })
+}
+
+/// Poll the status until the device leaves the busy state
+pub(crate) async fn wait_idle<D: DfuDevice>(device: &D) -> Result<DeviceStatus, Error> {
+    loop {
+        let status = get_status(device).await?;
+        match status.state {
+            State::DfuDnbusy | State::DfuDnloadSync => device.sleep(status.poll_timeout).await,
+            State::DfuError => return Err(dfu_core::Error::StatusError(status.status).into()),
+            _ => return Ok(status),
+        }
+    }
+}
+
+/// Bring the device back into the dfuIDLE state
+pub(crate) async fn ensure_idle<D: DfuDevice>(device: &D) -> Result<(), Error> {
+    match get_status(device).await?.state {
+        State::DfuIdle => return Ok(()),
+        State::DfuError => {
+            device
+                .write_control(REQUEST_TYPE_OUT, DFU_CLRSTATUS, 0, &[])
+                .await?;
+        }
+        _ => {
+            device
+                .write_control(REQUEST_TYPE_OUT, DFU_ABORT, 0, &[])
+                .await?;
+        }
+    }
+
+    match get_status(device).await?.state {
+        State::DfuIdle => Ok(()),
+        state => Err(dfu_core::Error::InvalidState {
+            got: state,
+            expected: State::DfuIdle,
+        }
+        .into()),
+    }
+}
+
+/// Send a DfuSe command and wait for the device to process it
+pub(crate) async fn dfuse_command<D: DfuDevice>(
+    device: &D,
+    command: u8,
+    address: u32,
+) -> Result<(), Error> {
+    let mut request = [command, 0, 0, 0, 0];
+    request[1..].copy_from_slice(&address.to_le_bytes());
+    device
+        .write_control(REQUEST_TYPE_OUT, DFU_DNLOAD, 0, &request)
+        .await?;
+    wait_idle(device).await?;
+    Ok(())
+}
+
+/// Have a DfuSe device compute the CRC-32 of `length` bytes at `address` with the vendor
+/// specific `command`, see [`DfuNusb::device_crc_command`]
+///
+/// The command may move the address pointer the block numbers are based on. Returns `None` if
+/// the device answered with less than 4 bytes.
+pub(crate) async fn device_crc<D: DfuDevice>(
+    device: &D,
+    command: u8,
+    address: u32,
+    length: u32,
+) -> Result<Option<u32>, Error> {
+    ensure_idle(device).await?;
+    let mut request = [command, 0, 0, 0, 0, 0, 0, 0, 0];
+    request[1..5].copy_from_slice(&address.to_le_bytes());
+    request[5..].copy_from_slice(&length.to_le_bytes());
+    device
+        .write_control(REQUEST_TYPE_OUT, DFU_DNLOAD, 0, &request)
+        .await?;
+    wait_idle(device).await?;
+    ensure_idle(device).await?;
+
+    let mut crc = [0; 4];
+    let n = device
+        .read_control(REQUEST_TYPE_IN, DFU_UPLOAD, 1, &mut crc)
+        .await?;
+    ensure_idle(device).await?;
+    Ok((n == crc.len()).then(|| u32::from_le_bytes(crc)))
+}
+
+/// Read back a DfuSe block written during the current download and compare it to `data`
+///
+/// Uploads use the same address pointer as downloads, so the block number addresses the
+/// same memory. With `device_crc`, the CRC command, the start address of the download and the
+/// offset of the block, the device computes the CRC of the block instead (see
+/// [`DfuNusb::device_crc_command`]). The device is left idle, from where the download can
+/// continue.
+pub(crate) async fn verify_block<D: DfuDevice>(
+    device: &D,
+    block: u16,
+    data: &[u8],
+    device_crc: Option<(u8, u32, u32)>,
+) -> Result<bool, Error> {
+    ensure_idle(device).await?;
+    if let Some((command, start, offset)) = device_crc {
+        let address = start
+            .checked_add(offset)
+            .ok_or(dfu_core::Error::OutOfCapabilities)?;
+        let crc = self::device_crc(device, command, address, data.len() as u32).await?;
+        dfuse_command(device, DFUSE_SET_ADDRESS, start).await?;
+        ensure_idle(device).await?;
+        return Ok(crc == Some(!crc32(data)));
+    }
+
+    let mut buffer = vec![0; data.len()];
+    let n = device
+        .read_control(REQUEST_TYPE_IN, DFU_UPLOAD, block, &mut buffer)
+        .await?;
+    ensure_idle(device).await?;
+    Ok(buffer[..n] == *data)
+}
+
+impl DfuNusb {
+    pub(crate) async fn get_status(&self) -> Result<DeviceStatus, Error> {
+        get_status(self).await
+    }
+
+    /// Poll the status until the device leaves the busy state
+    pub(crate) async fn wait_idle(&self) -> Result<DeviceStatus, Error> {
+        wait_idle(self).await
+    }
+
+    /// Bring the device back into the dfuIDLE state
+    pub(crate) async fn ensure_idle(&self) -> Result<(), Error> {
+        ensure_idle(self).await
+    }
+
+    /// Cancel the operation in progress, returning the state the device ends up in
+    ///
+    /// Meant to be called after dropping the future of an interrupted download or upload, or
+    /// from another task while one hangs: once the device finished processing the last block,
+    /// an error state is cleared with DFU_CLRSTATUS and the transfer is aborted with DFU_ABORT,
+    /// which brings the device back to dfuIDLE rather than leaving it in dfuERROR. Unlike
+    /// other operations this doesn't fail with [`Error::Busy`] while one is running; the
+    /// interrupted operation fails instead. A device staying busy for longer than the busy
+    /// timeout of the last request (see [`Timeouts`](crate::Timeouts)) fails with
+    /// [`Error::BusyTimeout`].
+    pub async fn abort(&self) -> Result<State, Error> {
+        let limit = self
+            .busy
+            .lock()
+            .unwrap()
+            .map_or(self.timeouts.write_block, |(_, limit)| limit);
+        let start = self.timer.now();
+        let mut status = self.get_status().await?;
+        while let State::DfuDnbusy = status.state {
+            if self.timer.now().saturating_duration_since(start) > limit {
+                return Err(Error::BusyTimeout(limit));
+            }
+            DfuAsyncIo::sleep(self, status.poll_timeout).await;
+            status = self.get_status().await?;
+        }
+        match status.state {
+            State::DfuIdle => return Ok(status.state),
+            State::DfuError => {
+                DfuAsyncIo::write_control(self, REQUEST_TYPE_OUT, DFU_CLRSTATUS, 0, &[]).await?;
+            }
+            _ => {
+                DfuAsyncIo::write_control(self, REQUEST_TYPE_OUT, DFU_ABORT, 0, &[]).await?;
+            }
+        }
+        Ok(self.get_status().await?.state)
+    }
+
+    /// Read `length` bytes of memory starting at `address` from the device
+    ///
+    /// For DfuSe devices `address` is an absolute memory address; for plain DFU devices it is
+    /// an offset into the data returned by an upload, as there is no way to start elsewhere.
+    pub async fn read_memory(&self, address: u32, length: usize) -> Result<Vec<u8>, Error> {
+        if !self.descriptor.can_upload && !self.force {
+            return Err(Error::UploadNotSupported);
+        }
+        let _operation = self.begin_operation()?;
+        self.reset_polled();
+        let mut data = Vec::with_capacity(length);
+        self.upload_into(address, length, &mut data, Phase::Upload)
+            .await?;
+        Ok(data)
+    }
+
+    /// Read `length` bytes of memory starting at `address` from a DfuSe device into a DfuSe
+    /// image
+    ///
+    /// The image holds a single element in a target for the current alternative setting,
+    /// named like it, and ends with a DFU suffix carrying the ids of the device. It can be
+    /// written back with [`DfuNusb::download_image`] or any DfuSe tool.
+    pub async fn read_image(&self, address: u32, length: usize) -> Result<Vec<u8>, Error> {
+        if !self.is_dfuse() {
+            return Err(Error::DfuseRequired);
+        }
+        let data = self.read_memory(address, length).await?;
+        let (vendor_id, product_id) = self.device_ids.unwrap_or((0xffff, 0xffff));
+        Ok(DfuseImage::new()
+            .element(self.alt, Some(&self.alt_name), address, data)
+            .build(DfuSuffix::new(vendor_id, product_id)))
+    }
+
+    /// Upload `length` bytes of memory starting at `address` from the device into `writer`
+    ///
+    /// Like [`DfuNusb::read_memory`], but each block is written out as soon as it was read, so
+    /// large memories can be streamed to a file or socket without buffering them. Returns the
+    /// number of bytes written, which is less than `length` if the device ended the upload
+    /// early.
+    pub async fn upload<W: AsyncWrite + Unpin>(
+        &self,
+        address: u32,
+        length: usize,
+        mut writer: W,
+    ) -> Result<usize, Error> {
+        if !self.descriptor.can_upload && !self.force {
+            return Err(Error::UploadNotSupported);
+        }
+        let _operation = self.begin_operation()?;
+        self.reset_polled();
+        let n = self
+            .upload_into(address, length, &mut writer, Phase::Upload)
+            .await?;
+        writer.flush().await.map_err(Error::Io)?;
+        Ok(n)
+    }
+
+    pub(crate) async fn upload_into<W: AsyncWrite + Unpin>(
+        &self,
+        address: u32,
+        length: usize,
+        writer: &mut W,
+        phase: Phase,
+    ) -> Result<usize, Error> {
+        let transfer_size = usize::from(self.descriptor.transfer_size);
+
+        self.ensure_idle().await?;
+        let (mut skip, mut block) = if self.is_dfuse() {
+            let mut command = [DFUSE_SET_ADDRESS, 0, 0, 0, 0];
+            command[1..].copy_from_slice(&address.to_le_bytes());
+            DfuAsyncIo::write_control(self, REQUEST_TYPE_OUT, DFU_DNLOAD, 0, &command).await?;
+            self.wait_idle().await?;
+            DfuAsyncIo::write_control(self, REQUEST_TYPE_OUT, DFU_ABORT, 0, &[]).await?;
+            // Block numbers 0 and 1 are reserved for DfuSe commands
+            (0, 2)
+        } else {
+            (address as usize, 0)
+        };
+
+        self.reporter.lock().unwrap().enter(phase);
+        let mut written = 0;
+        let mut buffer = vec![0; transfer_size];
+        while written < length {
+            let n = DfuAsyncIo::read_control(self, REQUEST_TYPE_IN, DFU_UPLOAD, block, &mut buffer)
+                .await?;
+            self.reporter.lock().unwrap().read(n);
+            block = block.wrapping_add(1);
+
+            let chunk = &buffer[skip.min(n)..n];
+            skip = skip.saturating_sub(n);
+            let chunk = &chunk[..chunk.len().min(length - written)];
+            writer.write_all(chunk).await.map_err(Error::Io)?;
+            written += chunk.len();
+            if n < transfer_size {
+                break;
+            }
+        }
+
+        self.ensure_idle().await?;
+        Ok(written)
+    }
+
+    /// Returns the commands supported by a DfuSe device, as listed by the Get command
+    pub async fn dfuse_commands(&self) -> Result<Vec<u8>, Error> {
+        let _operation = self.begin_operation()?;
+        self.read_dfuse_commands().await
+    }
+
+    /// [`DfuNusb::dfuse_commands`], as part of an operation which is already running
+    pub(crate) async fn read_dfuse_commands(&self) -> Result<Vec<u8>, Error> {
+        if !self.is_dfuse() {
+            return Err(Error::DfuseRequired);
+        }
+        self.ensure_idle().await?;
+        let mut buffer = vec![0; usize::from(self.descriptor.transfer_size)];
+        let n = DfuAsyncIo::read_control(self, REQUEST_TYPE_IN, DFU_UPLOAD, 0, &mut buffer).await?;
+        self.ensure_idle().await?;
+        buffer.truncate(n);
+        Ok(buffer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::executor::block_on;
+
+    use super::*;
+    use crate::fake::FakeDevice;
+
+    #[test]
+    fn crc_address_overflow() {
+        let device = FakeDevice::dfuse(0x0800_0000, vec![1024; 4], 256);
+        let result = block_on(verify_block(
+            &device,
+            2,
+            &[0; 256],
+            Some((0xb0, u32::MAX, 256)),
+        ));
+        assert!(matches!(
+            result,
+            Err(Error::Dfu(dfu_core::Error::OutOfCapabilities))
+        ));
+    }
+}
@@ -0,0 +1,134 @@
+use std::fmt;
+use std::str::FromStr;
+
+/// Extract a version string from raw memory
+///
+/// The string ends at the first NUL byte (or the end of the data) and surrounding whitespace
+/// is removed. Returns `None` if it isn't valid UTF-8 or is empty.
+pub fn version_string(data: &[u8]) -> Option<&str> {
+    let end = data.iter().position(|&b| b == 0).unwrap_or(data.len());
+    let s = std::str::from_utf8(&data[..end]).ok()?.trim();
+    (!s.is_empty()).then_some(s)
+}
+
+/// A `major.minor.patch` firmware version
+///
+/// Versions compare numerically, so they can be used to decide whether an update is needed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct FirmwareVersion {
+    /// Major version
+    pub major: u32,
+    /// Minor version
+    pub minor: u32,
+    /// Patch version
+    pub patch: u32,
+}
+
+impl FirmwareVersion {
+    /// Parse a version from raw memory holding a version string like `v1.2.3`
+    pub fn from_bytes(data: &[u8]) -> Option<Self> {
+        version_string(data)?.parse().ok()
+    }
+}
+
+/// Error parsing a [`FirmwareVersion`]
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+#[error("Invalid firmware version: {0}")]
+pub struct ParseVersionError(String);
+
+impl FromStr for FirmwareVersion {
+    type Err = ParseVersionError;
+
+    /// Parse versions like `1.2.3`, `v1.2` or `1.2.3-rc1+build5`; missing parts are zero and
+    /// pre-release or build metadata is ignored
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || ParseVersionError(s.to_string());
+        let version = s.strip_prefix(['v', 'V']).unwrap_or(s);
+        let version = version
+            .split(['-', '+'])
+            .next()
+            .filter(|v| !v.is_empty())
+            .ok_or_else(invalid)?;
+
+        let mut parts = version.split('.').map(|p| p.parse::<u32>());
+        let mut next = || parts.next().transpose().map_err(|_| invalid());
+        let major = next()?.ok_or_else(invalid)?;
+        let minor = next()?.unwrap_or(0);
+        let patch = next()?.unwrap_or(0);
+        if next()?.is_some() {
+            return Err(invalid());
+        }
+
+        Ok(Self {
+            major,
+            minor,
+            patch,
+        })
+    }
+}
+
+impl fmt::Display for FirmwareVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn version(major: u32, minor: u32, patch: u32) -> FirmwareVersion {
+        FirmwareVersion {
+            major,
+            minor,
+            patch,
+        }
+    }
+
+    #[test]
+    fn parse() {
+        for (s, expected) in [
+            ("1.2.3", version(1, 2, 3)),
+            ("v1.2.3", version(1, 2, 3)),
+            ("V10.0.1", version(10, 0, 1)),
+            ("1.2", version(1, 2, 0)),
+            ("7", version(7, 0, 0)),
+            ("1.2.3-rc1", version(1, 2, 3)),
+            ("1.2.3+build5", version(1, 2, 3)),
+            ("1.2-rc1+build5", version(1, 2, 0)),
+        ] {
+            assert_eq!(s.parse::<FirmwareVersion>(), Ok(expected), "{s:?}");
+        }
+    }
+
+    #[test]
+    fn parse_invalid() {
+        for s in [
+            "", "v", "-rc1", "1.2.3.4", "1..3", "1.x", "a.b.c", " 1.2.3", "1.2.-3",
+        ] {
+            assert_eq!(
+                s.parse::<FirmwareVersion>(),
+                Err(ParseVersionError(s.to_string())),
+                "{s:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn from_bytes() {
+        for (data, expected) in [
+            (&b"v1.2.3\0\xff\xff"[..], Some(version(1, 2, 3))),
+            (b"  2.0 \n", Some(version(2, 0, 0))),
+            (b"\0v1.2.3", None),
+            (b"\xff\xff\xff\xff", None),
+        ] {
+            assert_eq!(FirmwareVersion::from_bytes(data), expected, "{data:?}");
+        }
+    }
+
+    #[test]
+    fn ordering() {
+        assert!(version(1, 10, 0) > version(1, 9, 9));
+        assert!(version(2, 0, 0) > version(1, 99, 99));
+    }
+}
@@ -0,0 +1,30 @@
+use std::fmt;
+
+/// wTransferSize used for devices advertising a wTransferSize of 0
+pub(crate) const DEFAULT_TRANSFER_SIZE: u16 = 1024;
+
+/// Deviation from the DFU specification which was worked around while opening a device
+///
+/// Available through [`DfuNusb::warnings`](crate::DfuNusb::warnings), so tools can point out
+/// misbehaving bootloaders rather than silently papering over them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Warning {
+    /// The functional descriptor advertises a wTransferSize of 0, the given size is used
+    /// instead
+    ZeroTransferSize {
+        /// Transfer size used instead, see
+        /// [`OpenOptions::fallback_transfer_size`](crate::OpenOptions::fallback_transfer_size)
+        used: u16,
+    },
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Warning::ZeroTransferSize { used } => write!(
+                f,
+                "device advertises a wTransferSize of 0, using {used} bytes instead"
+            ),
+        }
+    }
+}
@@ -0,0 +1,123 @@
+//! The async API doesn't depend on tokio: it runs on smol, async-std or any other executor
+
+use std::future::Future;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use dfu_nusb::{
+    DeviceFilter, DfuNusb, FirmwareSource, FlashOptions, OpenOptions, ReaderSource, StdTimer, Timer,
+};
+
+fn assert_send<T: Send>(_: &T) {}
+
+fn assert_send_sync<T: Send + Sync>() {}
+
+/// Never called; only compiles if the futures of the device API can be spawned on
+/// multi-threaded executors
+#[allow(dead_code)]
+fn futures_are_send(device: &mut DfuNusb, firmware: &'static [u8]) {
+    let reader = futures::io::Cursor::new(firmware);
+    assert_send(&device.download(reader.clone(), firmware.len() as u32));
+    assert_send(&device.download_stream(reader.clone()));
+    assert_send(&device.download_source(firmware));
+    assert_send(&device.read_memory(0, 0x1000));
+    assert_send(&device.read_image(0x0800_0000, 0x1000));
+    assert_send(&device.upload(0, 0x1000, futures::io::sink()));
+    assert_send(&device.probe_capacity());
+    assert_send(&device.capability_report());
+    assert_send(&device.dfuse_commands());
+    assert_send(&device.abort());
+    assert_send(&device.unprotect());
+    assert_send(&device.download_image(firmware));
+    let options = FlashOptions::new();
+    assert_send(&dfu_nusb::flash_and_verify(
+        &DeviceFilter::new(),
+        firmware,
+        &options,
+    ));
+    assert_send(&dfu_nusb::wait_for_device(
+        &DeviceFilter::new(),
+        Duration::from_millis(250),
+        Duration::MAX,
+        &StdTimer,
+    ));
+}
+
+#[test]
+fn types_are_send_and_sync() {
+    assert_send_sync::<DfuNusb>();
+    assert_send_sync::<OpenOptions>();
+    assert_send_sync::<dfu_nusb::Error>();
+    assert_send_sync::<StdTimer>();
+}
+
+/// Sleep on `timer` twice concurrently, returning the time taken
+async fn sleep_twice(timer: &dyn Timer) -> Duration {
+    let start = Instant::now();
+    futures::join!(
+        timer.sleep(Duration::from_millis(20)),
+        timer.sleep(Duration::from_millis(30))
+    );
+    start.elapsed()
+}
+
+/// Read a whole firmware through a [`FirmwareSource`], as a download does
+async fn read_source(mut source: impl FirmwareSource) -> Vec<u8> {
+    let mut firmware = Vec::new();
+    let mut buffer = [0; 64];
+    loop {
+        let n = source.read_chunk(&mut buffer).await.unwrap();
+        if n == 0 {
+            return firmware;
+        }
+        firmware.extend_from_slice(&buffer[..n]);
+    }
+}
+
+/// Check the timer and firmware sources work when driven by `block_on`
+fn run_on(block_on: impl Fn(std::pin::Pin<Box<dyn Future<Output = ()>>>)) {
+    block_on(Box::pin(async {
+        let elapsed = sleep_twice(&StdTimer).await;
+        assert!(elapsed >= Duration::from_millis(30), "{elapsed:?}");
+        assert!(elapsed < Duration::from_secs(1), "{elapsed:?}");
+    }));
+
+    block_on(Box::pin(async {
+        let firmware: Vec<u8> = (0..=255).cycle().take(1000).collect();
+        let reader = futures::io::Cursor::new(firmware.clone());
+        let source = ReaderSource::new(reader).length(1000);
+        assert_eq!(source.length_hint(), Some(1000));
+        assert_eq!(read_source(source).await, firmware);
+    }));
+}
+
+#[test]
+fn runs_on_smol() {
+    run_on(smol::block_on);
+}
+
+#[test]
+fn runs_on_futures_executor() {
+    run_on(futures::executor::block_on);
+}
+
+#[cfg(feature = "async-std")]
+#[test]
+fn runs_on_async_std() {
+    run_on(async_std::task::block_on);
+
+    async_std::task::block_on(async {
+        let elapsed = sleep_twice(&dfu_nusb::AsyncStdTimer).await;
+        assert!(elapsed >= Duration::from_millis(30), "{elapsed:?}");
+    });
+}
+
+#[test]
+fn std_timer_runs_on_any_thread() {
+    let timer: Arc<dyn Timer> = Arc::new(StdTimer);
+    // Any executor can poll the sleeps of the std timer, including a thread of its own
+    let elapsed = std::thread::spawn(move || smol::block_on(sleep_twice(&*timer)))
+        .join()
+        .unwrap();
+    assert!(elapsed >= Duration::from_millis(30), "{elapsed:?}");
+}